@@ -0,0 +1,2 @@
+DROP TABLE reaction_role_choice_temp;
+DROP TABLE reaction_role_temp;
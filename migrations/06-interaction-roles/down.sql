@@ -0,0 +1,2 @@
+DROP TABLE interaction_role_choice;
+DROP TABLE interaction_role;
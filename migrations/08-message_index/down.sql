@@ -0,0 +1,4 @@
+-- The bot messages and duplicate entries the up deleted from message_log aren't brought back
+DROP TABLE message_chunk_temp;
+DROP TABLE message_index;
+DROP TABLE message_chunk;
@@ -0,0 +1,23 @@
+-- The table is recreated as 07 made it rather than renamed into place so the schema
+-- matches exactly
+ALTER TABLE message_log RENAME TO message_log_with_id;
+
+CREATE TABLE message_log (
+    guild_id INTEGER NOT NULL, -- Snowflake/u64 --
+    user_id INTEGER, -- Snowflake/u64 --
+    channel_id INTEGER NOT NULL, -- Snowflake/u64 --
+    message_id INTEGER NOT NULL, -- Snowflake/u64 --
+
+    timestamp TEXT NOT NULL,
+
+    type TEXT NOT NULL
+        CHECK(type IN ('CREATE', 'EDIT', 'DELETE', 'PURGE')),
+
+    message_json TEXT
+) STRICT;
+
+INSERT INTO message_log (guild_id, user_id, channel_id, message_id, timestamp, type, message_json)
+SELECT guild_id, user_id, channel_id, message_id, timestamp, type, message_json FROM message_log_with_id
+ORDER BY message_index_id;
+
+DROP TABLE message_log_with_id;
@@ -1,3 +1,7 @@
+-- There's no down.sql: message_log is dropped once its entries are split into
+-- message_index and message_chunk_temp, and the guild, channel and user ids it had
+-- aren't kept anywhere SQL can get them back from. Reverting this needs a backup
+
 -- This is needed because the message_chunk_temp might have stuff deleted from it as
 -- part of the compression process so we can't rely on it's max value and we can't 
 -- reorder them to select the max for the index first because of foreign key constraints
@@ -0,0 +1,3 @@
+ALTER TABLE interaction_role DROP COLUMN max_values;
+ALTER TABLE interaction_role DROP COLUMN min_values;
+ALTER TABLE interaction_role DROP COLUMN style;
@@ -0,0 +1,7 @@
+-- How the role menu is rendered: a grid of buttons or a dropdown (select menu)
+ALTER TABLE interaction_role ADD COLUMN style TEXT NOT NULL DEFAULT('BUTTON')
+    CHECK(style IN ('BUTTON', 'SELECT'));
+
+-- Only used by SELECT style menus. NULL means use the default
+ALTER TABLE interaction_role ADD COLUMN min_values INTEGER NULL;
+ALTER TABLE interaction_role ADD COLUMN max_values INTEGER NULL;
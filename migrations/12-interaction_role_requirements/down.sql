@@ -0,0 +1,3 @@
+ALTER TABLE interaction_role_choice DROP COLUMN min_message_count;
+ALTER TABLE interaction_role_choice DROP COLUMN forbidden_role_id;
+ALTER TABLE interaction_role_choice DROP COLUMN required_role_id;
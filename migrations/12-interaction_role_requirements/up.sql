@@ -0,0 +1,4 @@
+-- Optional requirements a member must meet before they can pick a choice. NULL means no requirement
+ALTER TABLE interaction_role_choice ADD COLUMN required_role_id INTEGER NULL; -- Snowflake/u64 --
+ALTER TABLE interaction_role_choice ADD COLUMN forbidden_role_id INTEGER NULL; -- Snowflake/u64 --
+ALTER TABLE interaction_role_choice ADD COLUMN min_message_count INTEGER NULL;
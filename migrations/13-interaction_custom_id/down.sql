@@ -0,0 +1,2 @@
+-- Role menus posted since this migration use these ids and will need re-posting
+DROP TABLE interaction_custom_id;
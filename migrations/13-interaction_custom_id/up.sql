@@ -0,0 +1,16 @@
+-- Maps the short opaque custom_ids attached to message components to what they do.
+-- This keeps set names and ids out of the custom_id and its 100 character limit
+CREATE TABLE interaction_custom_id (
+    id TEXT NOT NULL PRIMARY KEY,
+    guild_id INTEGER NOT NULL, -- Snowflake/u64 --
+
+    kind TEXT NOT NULL,
+    -- Empty string rather than NULL so the unique constraint applies --
+    set_name TEXT NOT NULL DEFAULT(''),
+    choice TEXT NOT NULL DEFAULT(''),
+    extra TEXT NULL, -- JSON --
+
+    created TEXT NOT NULL,
+
+    UNIQUE (guild_id, kind, set_name, choice)
+) STRICT;
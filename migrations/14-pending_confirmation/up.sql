@@ -0,0 +1,13 @@
+-- Destructive commands ask for confirmation with buttons. Storing what was asked
+-- means the buttons still work if the bot restarts before they are clicked
+CREATE TABLE pending_confirmation (
+    id TEXT NOT NULL PRIMARY KEY,
+    guild_id INTEGER NOT NULL, -- Snowflake/u64 --
+    requester_id INTEGER NOT NULL, -- Snowflake/u64 --
+
+    action TEXT NOT NULL,
+    payload TEXT NOT NULL, -- JSON --
+
+    created TEXT NOT NULL,
+    expires INTEGER NOT NULL -- Unix timestamp --
+) STRICT;
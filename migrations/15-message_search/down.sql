@@ -0,0 +1,2 @@
+DROP TABLE message_search_backfill;
+DROP TABLE message_search;
@@ -0,0 +1,33 @@
+-- Full text search over the message log. It keeps its own copy of the text so it
+-- doesn't matter that compress moves the bodies out of message_chunk_temp.
+-- The rowid is the message_index_id of the log entry the text came from
+CREATE VIRTUAL TABLE message_search USING fts5(
+    content,
+    guild_id UNINDEXED, -- Snowflake/u64 --
+    channel_id UNINDEXED, -- Snowflake/u64 --
+    user_id UNINDEXED, -- Snowflake/u64 --
+    message_id UNINDEXED -- Snowflake/u64 --
+);
+
+-- Index everything that hasn't been compressed yet
+INSERT INTO message_search (rowid, content, guild_id, channel_id, user_id, message_id)
+SELECT
+    message_chunk_temp.message_index_id,
+    json_extract(message_chunk_temp.message_json, '$.content'),
+    CAST(json_extract(message_chunk_temp.message_json, '$.guild_id') AS INTEGER),
+    CAST(json_extract(message_chunk_temp.message_json, '$.channel_id') AS INTEGER),
+    CAST(json_extract(message_chunk_temp.message_json, '$.author.id') AS INTEGER),
+    message_index.message_id
+FROM message_chunk_temp
+INNER JOIN message_index ON message_index.message_index_id = message_chunk_temp.message_index_id
+WHERE json_extract(message_chunk_temp.message_json, '$.guild_id') IS NOT NULL;
+
+-- Chunks that were compressed before the search index existed. They can't be
+-- decompressed in SQL so a background job works through them
+CREATE TABLE message_search_backfill (
+    chunk_id INTEGER NOT NULL PRIMARY KEY,
+    FOREIGN KEY(chunk_id) REFERENCES message_chunk(chunk_id)
+) STRICT;
+
+INSERT INTO message_search_backfill (chunk_id)
+SELECT chunk_id FROM message_chunk;
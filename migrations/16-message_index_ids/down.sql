@@ -0,0 +1,7 @@
+DROP INDEX message_index_user;
+DROP INDEX message_index_channel;
+DROP INDEX message_index_message_id;
+
+ALTER TABLE message_index DROP COLUMN user_id;
+ALTER TABLE message_index DROP COLUMN channel_id;
+ALTER TABLE message_index DROP COLUMN guild_id;
@@ -0,0 +1,46 @@
+-- Where the message was sent and who by. NULL if it isn't known yet (chunks compressed
+-- before these were added get filled in by a background job)
+ALTER TABLE message_index ADD COLUMN guild_id INTEGER NULL; -- Snowflake/u64 --
+ALTER TABLE message_index ADD COLUMN channel_id INTEGER NULL; -- Snowflake/u64 --
+ALTER TABLE message_index ADD COLUMN user_id INTEGER NULL; -- Snowflake/u64 --
+
+-- Everything that hasn't been compressed yet
+UPDATE message_index
+SET
+    guild_id = CAST(json_extract(message_chunk_temp.message_json, '$.guild_id') AS INTEGER),
+    channel_id = CAST(json_extract(message_chunk_temp.message_json, '$.channel_id') AS INTEGER),
+    user_id = CAST(json_extract(message_chunk_temp.message_json, '$.author.id') AS INTEGER)
+FROM message_chunk_temp
+WHERE message_chunk_temp.message_index_id = message_index.message_index_id;
+
+-- Compressed chunks that have already been added to the search index
+UPDATE message_index
+SET
+    guild_id = message_search.guild_id,
+    channel_id = message_search.channel_id,
+    user_id = message_search.user_id
+FROM message_search
+WHERE 
+    message_search.rowid = message_index.message_index_id
+    AND message_index.guild_id IS NULL;
+
+CREATE INDEX message_index_message_id ON message_index (message_id);
+
+-- Deletes and purges don't have a body so copy from the create/edit of the same message
+UPDATE message_index
+SET
+    guild_id = known.guild_id,
+    channel_id = known.channel_id,
+    user_id = known.user_id
+FROM (
+    SELECT message_id, guild_id, channel_id, user_id
+    FROM message_index
+    WHERE guild_id IS NOT NULL
+    GROUP BY message_id
+) AS known
+WHERE 
+    known.message_id = message_index.message_id
+    AND message_index.guild_id IS NULL;
+
+CREATE INDEX message_index_channel ON message_index (guild_id, channel_id, message_id);
+CREATE INDEX message_index_user ON message_index (guild_id, user_id, message_id);
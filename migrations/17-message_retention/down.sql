@@ -0,0 +1 @@
+DROP INDEX message_index_guild;
@@ -0,0 +1,2 @@
+-- Lets the retention job find the expired messages of a guild without a full scan
+CREATE INDEX message_index_guild ON message_index (guild_id, message_id);
@@ -0,0 +1,17 @@
+-- There's no down.sql: chunks compressed with a dictionary can't be read without it,
+-- so reverting this needs a backup from before it
+
+-- Trained zstd dictionaries for compressing message chunks. They are never changed
+-- once inserted, a retrain adds a new version and the highest id is the current one
+CREATE TABLE message_dictionary (
+    dictionary_id INTEGER PRIMARY KEY,
+    created TEXT NOT NULL,
+    sample_count INTEGER NOT NULL,
+    data BLOB NOT NULL
+) STRICT;
+
+-- NULL means the chunk was compressed without a dictionary
+ALTER TABLE message_chunk ADD COLUMN dictionary_id INTEGER
+    REFERENCES message_dictionary(dictionary_id);
+
+CREATE INDEX message_chunk_dictionary ON message_chunk (dictionary_id);
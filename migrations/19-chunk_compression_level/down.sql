@@ -0,0 +1,2 @@
+-- The chunks can still be read, the level only matters to the cold recompression job
+ALTER TABLE message_chunk DROP COLUMN compression_level;
@@ -0,0 +1,4 @@
+-- The zstd level the chunk was compressed at. NULL is the default level chunks are
+-- first compressed at. Chunks recompressed at the cold level are skipped when the
+-- cold recompression job resumes
+ALTER TABLE message_chunk ADD COLUMN compression_level INTEGER;
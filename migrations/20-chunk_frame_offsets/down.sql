@@ -0,0 +1,2 @@
+-- Reads fall back to scanning for the frames
+ALTER TABLE message_chunk DROP COLUMN frame_offsets;
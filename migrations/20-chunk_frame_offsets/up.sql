@@ -0,0 +1,3 @@
+-- Where each entry starts in the decompressed chunk, packed as little endian u32s.
+-- NULL for chunks compressed before this existed, their offsets are found by scanning
+ALTER TABLE message_chunk ADD COLUMN frame_offsets BLOB;
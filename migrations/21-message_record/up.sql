@@ -0,0 +1,29 @@
+-- Message bodies are stored as compact versioned records (see db::message_record)
+-- rather than serenity's JSON. Most bodies are in zstd chunks that can't be converted
+-- in SQL so both formats are read and nothing is converted here, the temp table just
+-- needs a column that can hold the binary records. Rows still holding JSON are
+-- converted as they're compressed and chunks by the message_record_backfill job.
+--
+-- There's no down.sql: once records are written they can't be turned back into
+-- serenity's JSON in SQL, so reverting this needs a backup from before it
+CREATE TABLE message_chunk_temp_new (
+    message_index_id INTEGER NOT NULL UNIQUE,
+    message_body BLOB NOT NULL,
+    FOREIGN KEY(message_index_id) REFERENCES message_index(message_index_id)
+) STRICT;
+
+INSERT INTO message_chunk_temp_new (message_index_id, message_body)
+SELECT message_index_id, CAST(message_json AS BLOB) FROM message_chunk_temp;
+
+DROP TABLE message_chunk_temp;
+ALTER TABLE message_chunk_temp_new RENAME TO message_chunk_temp;
+
+-- Chunks compressed with JSON bodies. They can't be decompressed in SQL so a
+-- background job works through them
+CREATE TABLE message_record_backfill (
+    chunk_id INTEGER NOT NULL PRIMARY KEY,
+    FOREIGN KEY(chunk_id) REFERENCES message_chunk(chunk_id)
+) STRICT;
+
+INSERT INTO message_record_backfill (chunk_id)
+SELECT chunk_id FROM message_chunk;
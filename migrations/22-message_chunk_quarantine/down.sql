@@ -0,0 +1,2 @@
+-- Anything still in quarantine is lost, export it first if it's needed
+DROP TABLE message_chunk_quarantine;
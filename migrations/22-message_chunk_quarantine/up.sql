@@ -0,0 +1,14 @@
+-- Chunks that failed verification are moved here by the repair so they stop
+-- breaking lookups but can still be inspected or recovered by hand
+CREATE TABLE message_chunk_quarantine (
+    chunk_id INTEGER PRIMARY KEY,
+    start_message_index_id INTEGER NOT NULL,
+    end_message_index_id INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    dictionary_id INTEGER,
+    compression_level INTEGER,
+    frame_offsets BLOB,
+    -- Why verification failed
+    reason TEXT NOT NULL,
+    quarantined TEXT NOT NULL
+) STRICT;
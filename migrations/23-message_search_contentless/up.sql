@@ -0,0 +1,19 @@
+-- The guild, channel, user and message ids are all on message_index (with indexes)
+-- so searches filter there and join to the search table on its rowid. The search
+-- table only needs to index the text and, being contentless, doesn't keep a second
+-- uncompressed copy of it. Removing a row from it needs the original text, see
+-- `delete_entries`.
+-- This can't be reverted as the text can't be read back out of a contentless table
+ALTER TABLE message_search RENAME TO message_search_old;
+
+CREATE VIRTUAL TABLE message_search USING fts5(
+    content,
+    content=''
+);
+
+INSERT INTO message_search (rowid, content)
+SELECT rowid, content
+FROM message_search_old
+WHERE rowid IN (SELECT message_index_id FROM message_index);
+
+DROP TABLE message_search_old;
@@ -0,0 +1,674 @@
+use std::{collections::BTreeMap, fmt::Display, fs, future::Future, path::PathBuf, time::Duration};
+
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use poise::{serenity_prelude::{Http, Timestamp}, ChoiceParameter};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::time::Instant;
+use tracing::*;
+
+use gagbot_rs::{
+    commands::{
+        log::message_to_string,
+        interaction_roles::RoleAssigner,
+        reaction_roles::{convert_reaction_roles, ConvertMode},
+        search::{date_end_to_message_id, date_to_message_id},
+    },
+    configure_tracing,
+    db::{
+        backfill_compressed_chunks, backup_before_migration, close_database,
+        current_migration_version, get_migration_files, get_migrations, open_database,
+        plan_migration, restore_database,
+        queries::{
+            config::{self, ConfigKey},
+            get_table_size_in_bytes,
+            message_log::{self, MessageLog},
+        },
+        recompress_cold_chunks, repair_database, spawn_db_task, vacuum_database, verify_database, ChunkCache,
+        CompressionState, DbCommand,
+    },
+    load_dotenv, BotData, ChannelId, Error, GuildId, MessageId, UserId,
+};
+
+// Plenty for the lookups done here, nothing is long running enough to need more
+const CHUNK_CACHE_CAPACITY: usize = 8;
+// Only this tool is sending commands so there's no need for a deep queue
+const DATABASE_COMMAND_CHANNEL_BOUND: usize = 8;
+// How many message log entries are loaded at a time by `messages user`
+const MESSAGE_PAGE_SIZE: u64 = 500;
+
+/// Maintenance for the bot's database. The bot should be stopped first as these work on
+/// the DB file directly
+#[derive(Debug, Parser)]
+#[clap(name = "gagbot_admin")]
+struct Cli {
+    #[clap(long, env, default_value = "gagbot.sqlite")]
+    sqlite_connection_string: String,
+    /// Print the results as JSON instead of text
+    #[clap(long)]
+    json: bool,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compress the logged messages into chunks
+    Compress {
+        /// Train a new compression dictionary from the stored messages before compressing
+        #[clap(long)]
+        train_dictionary: bool,
+        /// Recompress chunks that aren't using the latest dictionary after compressing
+        #[clap(long)]
+        recompress: bool,
+        /// Recompress chunks older than this many days at the cold level after compressing
+        #[clap(long)]
+        cold_days: Option<i64>,
+    },
+    /// Run sqlite's integrity check and verify the compressed chunks
+    Verify {
+        /// Quarantine any chunks that fail verification and fix up the message index
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Vacuum the database to reclaim free space
+    Vacuum,
+    /// Show the schema version, table sizes and compression state
+    Stats,
+    /// Run any pending migrations. The database is copied next to itself first
+    Migrate {
+        /// Migrate to this version instead of the latest. Going to a lower version runs
+        /// the down.sql of each migration above it. 10 (message_index data transform),
+        /// 18 (message dictionary), 21 (message record) and 23 (contentless search) can't
+        /// be reverted, restore a backup from before them instead
+        #[clap(long)]
+        to_version: Option<usize>,
+        /// Print the SQL that would be run without changing anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Don't copy the database before migrating
+        #[clap(long)]
+        no_backup: bool,
+    },
+    /// Replace the database with a backup, such as the copy made before migrating
+    Restore {
+        backup: PathBuf,
+    },
+    /// Export the config of every guild (or just one) as JSON. That's the greet, promote,
+    /// logging and role menu settings shown by `config`. Permissions and the role menus
+    /// themselves aren't included
+    ExportConfig {
+        #[clap(long)]
+        guild_id: Option<u64>,
+        /// Where to write the export. Defaults to stdout
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Import config exported with `export-config`. Existing values for the same keys are
+    /// replaced
+    ImportConfig {
+        input: PathBuf,
+    },
+    /// Look up entries in the message log
+    #[clap(subcommand)]
+    Messages(MessagesCommand),
+    /// Show the config of a guild
+    Config {
+        #[clap(long)]
+        guild_id: u64,
+    },
+    /// Legacy reaction role sets imported by migrate_mongo
+    #[clap(subcommand)]
+    ReactionRoles(ReactionRolesCommand),
+    /// Delete everything logged about a user's messages
+    Forget {
+        #[clap(long)]
+        user_id: u64,
+        /// Only delete the user's data from this guild. Defaults to all guilds
+        #[clap(long)]
+        guild_id: Option<u64>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ReactionRolesCommand {
+    /// List the sets that haven't been converted yet
+    List,
+    /// Convert the sets into role menus
+    Convert {
+        #[clap(long, env)]
+        discord_token: String,
+        /// What to do with the original reaction role messages
+        #[clap(long, value_enum, default_value = "db-only")]
+        mode: ConvertMode,
+        /// Only convert sets from this guild
+        #[clap(long)]
+        guild_id: Option<u64>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum MessagesCommand {
+    /// Every logged version of a message
+    Get {
+        #[clap(long)]
+        message_id: u64,
+    },
+    /// Everything logged for a user in a guild
+    User {
+        #[clap(long)]
+        guild_id: u64,
+        #[clap(long)]
+        user_id: u64,
+    },
+    /// Messages logged in a channel between two dates
+    Channel {
+        #[clap(long)]
+        guild_id: u64,
+        #[clap(long)]
+        channel_id: u64,
+        /// The first day to include (YYYY-MM-DD)
+        #[clap(long)]
+        from: String,
+        /// The last day to include (YYYY-MM-DD)
+        #[clap(long)]
+        to: String,
+        /// How many messages to show, each with every version that was logged
+        #[clap(long, default_value = "100")]
+        limit: u64,
+    },
+}
+
+/// Config values keyed by guild_id and then config key name
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConfigExport {
+    guilds: BTreeMap<u64, BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Serialize)]
+struct TableSize {
+    name: String,
+    bytes: u64,
+    rows: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    schema_version: String,
+    file_size: Option<u64>,
+    tables: Vec<TableSize>,
+    compression: CompressionState,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Schema version: {}\n", self.schema_version)?;
+        if let Some(file_size) = self.file_size {
+            write!(f, "File size: {} bytes\n", file_size)?;
+        }
+        write!(f, "Tables:\n")?;
+        for t in self.tables.iter() {
+            write!(f, "- {}: {} rows, {} bytes\n", t.name, t.rows, t.bytes)?;
+        }
+        write!(f, "Compression:\n{}", CompressionStateDisplay(&self.compression))
+    }
+}
+
+struct CompressionStateDisplay<'a>(&'a CompressionState);
+
+impl Display for CompressionStateDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cs = self.0;
+        write!(f, "- Uncompressed messages: {} ({} bytes)\n", cs.uncompressed_messages, cs.uncompressed_bytes)?;
+        write!(f, "- Compressed messages: {} ({} bytes)\n", cs.compressed_messages, cs.compressed_bytes)?;
+        write!(f, "- Chunks: {}", cs.chunks)
+    }
+}
+
+/// Prints the value as JSON or with Display depending on `--json`
+fn print_output<T: Serialize + Display>(json: bool, value: &T) -> Result<(), Error> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        println!("{}", value);
+    }
+    Ok(())
+}
+
+fn print_message_log(json: bool, log: Vec<MessageLog>) -> Result<(), Error> {
+    if json {
+        let entries = log
+            .iter()
+            .map(|m| json!({
+                "message_index_id": m.message_index_id,
+                "message_id": m.message_id.0,
+                "guild_id": m.guild_id.map(|v| v.0),
+                "channel_id": m.channel_id.map(|v| v.0),
+                "timestamp": m.timestamp.to_rfc3339(),
+                "type": format!("{:?}", m.type_),
+                "message": m.message,
+            }))
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        for m in log.iter() {
+            println!(
+                "{} {:?} message {} ({})",
+                m.timestamp.to_rfc3339(),
+                m.type_,
+                m.message_id.0,
+                m.message_index_id
+            );
+            if let Some(message) = &m.message {
+                if let Some(content) = message_to_string(message)? {
+                    println!("{}", content);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn compress(
+    con: &mut Connection,
+    train_dictionary: bool,
+    recompress: bool,
+    cold_days: Option<i64>,
+) -> Result<CompressionState, Error> {
+    if train_dictionary {
+        match message_log::train_dictionary(con)? {
+            Some(id) => info!("Trained dictionary {}", id),
+            None => warn!("Not enough messages to train a dictionary"),
+        }
+    }
+
+    let start = Instant::now();
+    while message_log::compress(con)? {}
+    info!("Compressed in {} s", start.elapsed().as_secs_f32());
+
+    let start = Instant::now();
+    while backfill_compressed_chunks(con)?.1 {}
+    info!("Backfilled in {} s", start.elapsed().as_secs_f32());
+
+    if recompress {
+        let start = Instant::now();
+        while message_log::recompress_chunk(con)? {}
+        info!("Recompressed in {} s", start.elapsed().as_secs_f32());
+    }
+
+    if let Some(days) = cold_days {
+        let cutoff = MessageId::from_unix_millis(
+            (Utc::now() - chrono::Duration::days(days)).timestamp_millis());
+        let start = Instant::now();
+        while recompress_cold_chunks(con, cutoff, true)?.1 {}
+        info!("Recompressed cold chunks in {} s", start.elapsed().as_secs_f32());
+    }
+
+    message_log::get_compression_state(con)
+}
+
+fn stats(con: &Connection) -> Result<Stats, Error> {
+    let schema_version = get_migrations()?.current_version(con)?.to_string();
+    let file_size = con
+        .path()
+        .map(|p| fs::metadata(p))
+        .transpose()?
+        .map(|m| m.len());
+    let tables = get_table_size_in_bytes(con)?
+        .into_iter()
+        .map(|(name, bytes, rows)| TableSize { name, bytes, rows })
+        .collect();
+    let compression = message_log::get_compression_state(con)?;
+
+    Ok(Stats { schema_version, file_size, tables, compression })
+}
+
+fn export_config(con: &Connection, guild_id: Option<u64>) -> Result<ConfigExport, Error> {
+    let mut export = ConfigExport::default();
+    let mut i = 0;
+    while let Some(key) = ConfigKey::from_index(i) {
+        for (g, value) in config::get_all_guilds(con, key)? {
+            if guild_id.map_or(true, |v| v == g.0) {
+                export
+                    .guilds
+                    .entry(g.0)
+                    .or_default()
+                    .insert(key.name().to_string(), value);
+            }
+        }
+        i += 1;
+    }
+    Ok(export)
+}
+
+fn import_config(con: &Connection, export: ConfigExport) -> Result<usize, Error> {
+    let timestamp = Timestamp::now();
+    let mut count = 0;
+    for (guild_id, values) in export.guilds.into_iter() {
+        for (key, value) in values.into_iter() {
+            let key = ConfigKey::from_name(&key)
+                .ok_or_else(|| anyhow::anyhow!("Unknown config key ({key}) for guild {guild_id}"))?;
+            config::update(con, GuildId::from(guild_id), key, &value, timestamp)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn guild_config(con: &Connection, guild_id: u64) -> Result<BTreeMap<String, Option<String>>, Error> {
+    let mut values = BTreeMap::new();
+    let mut i = 0;
+    while let Some(key) = ConfigKey::from_index(i) {
+        values.insert(
+            key.name().to_string(),
+            config::get::<String>(con, GuildId::from(guild_id), key)?,
+        );
+        i += 1;
+    }
+    Ok(values)
+}
+
+/// Runs `f` against a DB task on the connection, for the commands that share code with
+/// the bot and so go through `BotData`. The connection is closed once `f` is done
+fn run_with_bot_data<F, Fut, T>(con: Connection, f: F) -> Result<T, Error>
+where
+    F: FnOnce(BotData) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    tokio::runtime::Runtime::new()?.block_on(async move {
+        let db_file_path = con.path().map(|p| p.to_owned());
+        let (sender, receiver) = flume::bounded::<DbCommand>(DATABASE_COMMAND_CHANNEL_BOUND);
+        let db_task_handle = spawn_db_task(con, receiver);
+
+        // The background task frequency isn't used outside the bot
+        let data = BotData::new(sender, db_file_path, Duration::from_secs(3600), None);
+        let r = f(data.clone()).await;
+
+        data.db_close().await?;
+        db_task_handle.await??;
+        r
+    })
+}
+
+async fn reaction_roles(data: BotData, command: ReactionRolesCommand, json: bool) -> Result<(), Error> {
+    match command {
+        ReactionRolesCommand::List => {
+            let sets = data.get_reaction_role_temps(None).await?;
+            if json {
+                let sets = sets
+                    .iter()
+                    .map(|set| json!({
+                        "guild_id": set.guild_id.0,
+                        "name": set.name,
+                        "channel_id": set.channel_id.0,
+                        "message_id": set.message_id.0,
+                        "exclusive": set.exclusive,
+                        "choices": set.choices
+                            .iter()
+                            .map(|c| json!({ "choice": c.choice, "role_id": c.role_id.0 }))
+                            .collect::<Vec<_>>(),
+                    }))
+                    .collect::<Vec<_>>();
+                println!("{}", serde_json::to_string_pretty(&sets)?);
+            } else {
+                for set in sets {
+                    println!(
+                        "{} \"{}\": channel: {}, message: {}, exclusive: {}, choices: {}",
+                        set.guild_id.0,
+                        set.name,
+                        set.channel_id.0,
+                        set.message_id.0,
+                        set.exclusive,
+                        set.choices
+                            .iter()
+                            .map(|c| format!("{} => {}", c.choice, c.role_id.0))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+            }
+        },
+        ReactionRolesCommand::Convert { discord_token, mode, guild_id } => {
+            let http = Http::new(&discord_token);
+
+            let mut guild_ids = match guild_id {
+                Some(guild_id) => vec![GuildId::from(guild_id)],
+                None => data
+                    .get_reaction_role_temps(None)
+                    .await?
+                    .iter()
+                    .map(|v| v.guild_id)
+                    .collect(),
+            };
+            // Sets are ordered by guild so this removes all the duplicates
+            guild_ids.dedup();
+
+            let mut reports = Vec::new();
+            for guild_id in guild_ids {
+                info!("Converting reaction roles for {:?}", guild_id);
+                for report in convert_reaction_roles(&http, &data, guild_id, mode, RoleAssigner::Owner).await? {
+                    if json {
+                        reports.push(json!({
+                            "guild_id": report.guild_id.0,
+                            "name": report.name,
+                            "result": report.to_string(),
+                        }));
+                    } else {
+                        println!("{}", report);
+                    }
+                }
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&reports)?);
+            }
+        },
+    }
+    Ok(())
+}
+
+async fn forget(data: BotData, guild_id: Option<u64>, user_id: u64, json: bool) -> Result<(), Error> {
+    let receipt = data
+        .forget_user(guild_id.map(GuildId::from), UserId::from(user_id))
+        .await?;
+    if json {
+        let log = &receipt.message_log;
+        println!("{}", serde_json::to_string_pretty(&json!({
+            "user_id": receipt.user_id.0,
+            "guild_id": receipt.guild_id.map(|v| v.0),
+            "message_log": {
+                "index_entries": log.index_entries,
+                "uncompressed_bodies": log.uncompressed_bodies,
+                "search_entries": log.search_entries,
+                "chunks_rewritten": log.chunks_rewritten,
+                "chunks_dropped": log.chunks_dropped,
+            },
+            "message_counts": receipt.message_counts,
+        }))?);
+    } else {
+        println!("{}", receipt);
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Error> {
+    load_dotenv()?;
+    configure_tracing();
+
+    let args = Cli::parse();
+    debug!("Parsed args: {:#?}", args);
+
+    // Migrations are only run by the migrate command. It and restore can start from an
+    // empty database
+    let create = matches!(args.command, Command::Migrate { .. } | Command::Restore { .. });
+    let mut con = open_database(&args.sqlite_connection_string, create, false)?;
+    let json = args.json;
+
+    match args.command {
+        Command::ReactionRoles(command) => {
+            run_with_bot_data(con, |data| reaction_roles(data, command, json))?;
+            info!("Done");
+            return Ok(());
+        },
+        Command::Forget { user_id, guild_id } => {
+            run_with_bot_data(con, |data| forget(data, guild_id, user_id, json))?;
+            info!("Done");
+            return Ok(());
+        },
+        Command::Compress { train_dictionary, recompress, cold_days } => {
+            let cs = compress(&mut con, train_dictionary, recompress, cold_days)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&cs)?);
+            } else {
+                println!("{}", CompressionStateDisplay(&cs));
+            }
+        },
+        Command::Verify { repair } => {
+            let (duration, mut report) = verify_database(&con)?;
+            info!("Verified in {} s", duration.as_secs_f32());
+            if repair && !report.is_ok() {
+                let chunk_ids = report.bad_chunks.iter().map(|v| v.chunk_id).collect::<Vec<_>>();
+                report.repair = Some(repair_database(&mut con, &chunk_ids)?);
+            }
+            print_output(json, &report)?;
+        },
+        Command::Vacuum => {
+            let duration = vacuum_database(&con)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&json!({
+                    "seconds": duration.as_secs_f32(),
+                }))?);
+            } else {
+                info!("Vacuumed in {} s", duration.as_secs_f32());
+            }
+        },
+        Command::Stats => {
+            print_output(json, &stats(&con)?)?;
+        },
+        Command::Migrate { to_version, dry_run, no_backup } => {
+            let current = current_migration_version(&con)?;
+            let target = match to_version {
+                Some(version) => version,
+                None => get_migration_files()?.len(),
+            };
+            let plan = plan_migration(current, target)?;
+
+            if dry_run {
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&plan)?);
+                } else {
+                    for step in plan.iter() {
+                        println!("{}\n", step);
+                    }
+                }
+            } else {
+                let mut backup_path = None;
+                if plan.len() > 0 {
+                    if !no_backup && current > 0 {
+                        backup_path = backup_before_migration(&con, current)?;
+                        if let Some(backup_path) = backup_path.as_ref() {
+                            info!("Backed up the database to {}", backup_path.display());
+                        }
+                    }
+                    // All the steps run in one transaction so a failure leaves it at `current`
+                    get_migrations()?.to_version(&mut con, target)?;
+                }
+
+                let version = current_migration_version(&con)?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&json!({
+                        "from_version": current,
+                        "to_version": version,
+                        "backup": backup_path,
+                    }))?);
+                } else if plan.len() == 0 {
+                    info!("Already at version {}", current);
+                } else {
+                    info!("Migrated from version {} to {}", current, version);
+                }
+            }
+        },
+        Command::Restore { backup } => {
+            restore_database(&mut con, &backup)?;
+            let version = current_migration_version(&con)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&json!({
+                    "restored": backup,
+                    "version": version,
+                }))?);
+            } else {
+                info!("Restored {} (version {})", backup.display(), version);
+            }
+        },
+        // The export is always JSON so `--json` doesn't change anything
+        Command::ExportConfig { guild_id, output } => {
+            let export = serde_json::to_string_pretty(&export_config(&con, guild_id)?)?;
+            match output {
+                Some(path) => fs::write(path, export)?,
+                None => println!("{}", export),
+            }
+        },
+        Command::ImportConfig { input } => {
+            let export: ConfigExport = serde_json::from_slice(&fs::read(input)?)?;
+            let count = import_config(&con, export)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&json!({ "imported": count }))?);
+            } else {
+                info!("Imported {} config values", count);
+            }
+        },
+        Command::Messages(command) => {
+            let mut cache = ChunkCache::new(CHUNK_CACHE_CAPACITY);
+            let log = match command {
+                MessagesCommand::Get { message_id } => {
+                    message_log::get(&con, &mut cache, MessageId::from(message_id))?
+                },
+                MessagesCommand::User { guild_id, user_id } => {
+                    let mut log: Vec<MessageLog> = Vec::new();
+                    loop {
+                        let page = message_log::get_by_user(
+                            &con,
+                            &mut cache,
+                            GuildId::from(guild_id),
+                            UserId::from(user_id),
+                            log.last().map(|m| (m.message_id, m.message_index_id)),
+                            MESSAGE_PAGE_SIZE,
+                        )?;
+                        let done = (page.len() as u64) < MESSAGE_PAGE_SIZE;
+                        log.extend(page);
+                        if done {
+                            break log;
+                        }
+                    }
+                },
+                MessagesCommand::Channel { guild_id, channel_id, from, to, limit } => {
+                    message_log::get_by_channel(
+                        &con,
+                        &mut cache,
+                        GuildId::from(guild_id),
+                        ChannelId::from(channel_id),
+                        date_to_message_id(&from)?,
+                        date_end_to_message_id(&to)?,
+                        limit,
+                    )?
+                },
+            };
+            print_message_log(json, log)?;
+        },
+        Command::Config { guild_id } => {
+            let values = guild_config(&con, guild_id)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&values)?);
+            } else {
+                for (key, value) in values.iter() {
+                    println!("{}: {}", key, value.as_deref().unwrap_or("(not set)"));
+                }
+            }
+        },
+    }
+
+    close_database(con)?;
+    info!("Done");
+    Ok(())
+}
@@ -2,15 +2,15 @@
 //
 // "prune", "Kick inactive users", "gagbot:admin:prune"
 
-use std::{fmt::{Write, Display}, num::ParseIntError, time::Duration};
+use std::{collections::HashSet, fmt::{Write, Display}, num::ParseIntError, path::PathBuf, time::Duration};
 
 use chrono::{DateTime, Utc};
 use clap::Parser;
-use futures::future::{join, select, Either};
+use futures::future::join;
 use gagbot_rs::{
-    commands::{greet::{run_greet, GreetBehaviour}, log::log, promote::{run_promote, OptionallyConfiguredResult}},
+    commands::{confirmation::handle_confirmation, search::handle_search_page, shutdown::{announce_offline, wait_for_shutdown_signal}, greet::{run_greet, GreetBehaviour}, custom_ids::{route_custom_id, split_legacy_button_custom_id, ComponentRoute}, interaction_roles::{check_interaction_roles, diff_role_menu_problems, reconcile_interaction_roles, toggle_interaction_role, ToggleRoleResult}, reaction_roles::handle_legacy_reaction, log::{log, message_to_string}, promote::{run_promote, OptionallyConfiguredResult}},
     db::{
-        background_jobs::spawn_db_background_jobs_task, open_database, queries::{config::LogChannel, message_log::{LogType, MessageLog}}, spawn_db_task, DbCommand
+        background_jobs::spawn_db_background_jobs_task, open_database, remove_partial_backups, BackupConfig, queries::{config::LogChannel, interaction_roles::InteractionRole, message_log::{LogType, MessageLog}}, spawn_db_task, DbCommand, VerifyReport
     },
     *,
 };
@@ -19,11 +19,11 @@ use poise::{
     self,
     serenity_prelude::{
         self as serenity, ActionRowComponent, CacheHttp, ComponentType, Context, GatewayIntents,
-        Guild, Interaction, Message, MessageUpdateEvent, Timestamp, VoiceState,
+        Guild, Interaction, Message, MessageComponentInteraction, MessageUpdateEvent, Timestamp, VoiceState,
     },
     FrameworkContext, FrameworkError,
 };
-use tokio::time;
+use tokio::{sync::oneshot, time};
 use tracing::*;
 
 fn frequency_seconds_valid_range(s: &str) -> Result<u64, String> {
@@ -49,6 +49,22 @@ struct Cli {
     database_command_channel_bound: usize,
     #[clap(long, env, default_value = "3600", value_parser = frequency_seconds_valid_range)]
     background_task_frequency_seconds: u64,
+    /// Directory the scheduled and /backup_now database backups are written to
+    #[clap(long, env, default_value = "backups")]
+    backup_directory: PathBuf,
+    /// Number of backups to keep, older ones are deleted. The newest is always kept
+    #[clap(long, env, default_value = "7", value_parser = clap::value_parser!(u64).range(1..))]
+    backup_keep: u64,
+    /// zstd compress the backups
+    #[clap(long, env)]
+    backup_compress: bool,
+    /// Channel the weekly database verify report is posted in, e.g. one only the bot's
+    /// owner can see. Reports are only logged if it isn't set
+    #[clap(long, env)]
+    verify_report_channel_id: Option<u64>,
+    /// Post the verify report even when nothing is wrong, not just when it finds problems
+    #[clap(long, env)]
+    verify_report_always: bool,
 }
 
 // This simulates a single core vm: #[tokio::main(flavor = "multi_thread", worker_threads = 1)]
@@ -66,11 +82,42 @@ async fn main() -> Result<(), Error> {
     // to discord
     let sqlite_con = open_database(&args.sqlite_connection_string, true, true)?;
     let db_file_path = sqlite_con.path().map(|p| p.to_owned());
+    let backup_config = BackupConfig {
+        sqlite_connection_string: args.sqlite_connection_string.clone(),
+        directory: args.backup_directory.clone(),
+        keep: args.backup_keep as usize,
+        compress: args.backup_compress,
+    };
+    // Nothing is backing up yet so anything partial was left by a backup that was
+    // interrupted last time the bot ran
+    if let Err(e) = remove_partial_backups(&backup_config.directory) {
+        warn!("Failed to remove partial backups: {:?}", e);
+    }
     let (sender, receiver) = flume::bounded::<DbCommand>(args.database_command_channel_bound);
 
-    let db_background_task_handle = spawn_db_background_jobs_task(sender.clone());
+    // Reports from the weekly DB verify are posted once discord is connected
+    let (verify_report_sender, verify_report_receiver) = flume::unbounded::<VerifyReport>();
+    let (background_jobs_shutdown_sender, background_jobs_shutdown_receiver) = flume::bounded::<()>(1);
+    let db_background_task_handle = spawn_db_background_jobs_task(
+        sender.clone(), 
+        args.sqlite_connection_string.clone(), 
+        verify_report_sender,
+        backup_config.clone(),
+        background_jobs_shutdown_receiver,
+    );
     let db_task_handle = spawn_db_task(sqlite_con, receiver);
 
+    let data = BotData::new(
+        sender,
+        db_file_path,
+        background_task_frequency,
+        Some(backup_config),
+    );
+    let verify_report_channel_id = args.verify_report_channel_id.map(ChannelId::from);
+    let verify_report_always = args.verify_report_always;
+    // The shutdown needs the discord context to announce it's going offline
+    let (connected_sender, connected_receiver) = oneshot::channel::<Context>();
+
     let options = poise::FrameworkOptions {
         commands: discord_commands::commands(),
         on_error: |err| Box::pin(on_error(err)),
@@ -106,15 +153,22 @@ async fn main() -> Result<(), Error> {
                 | GatewayIntents::AUTO_MODERATION_CONFIGURATION
                 | GatewayIntents::AUTO_MODERATION_EXECUTION,
         )
-        .setup(move |_ctx, _ready, _framework| {
-            debug!("Discord connected");
-            Box::pin(async move {
-                Ok(BotData::new(
-                    sender,
-                    db_file_path,
-                    background_task_frequency,
-                ))
-            })
+        .setup({
+            let data = data.clone();
+            move |ctx, _ready, _framework| {
+                debug!("Discord connected");
+                let ctx = ctx.clone();
+                Box::pin(async move {
+                    let _ = connected_sender.send(ctx.clone());
+                    tokio::spawn(verify_report_task(
+                        ctx,
+                        verify_report_receiver,
+                        verify_report_channel_id,
+                        verify_report_always,
+                    ));
+                    Ok(data)
+                })
+            }
         })
         .build()
         .await?;
@@ -125,21 +179,44 @@ async fn main() -> Result<(), Error> {
         // task to stop
         framework.start(),
 
-        // In this case however, if the db exits first the framework needs to be shut down
+        // In this case however, if the db exits first or we get a shutdown signal the 
+        // framework needs to be shut down
         async move {
-            let r = select(
-                db_task_handle, 
-                db_background_task_handle,
-            ).await;
+            let mut db_task_handle = db_task_handle;
+            let mut db_background_task_handle = db_background_task_handle;
+            let mut connected_receiver = connected_receiver;
+            let r = tokio::select! {
+                r = &mut db_task_handle => r,
+                r = &mut db_background_task_handle => r,
+                signal = wait_for_shutdown_signal() => Ok(async {
+                    info!("Received {}, shutting down", signal?);
+
+                    // This has to happen while the DB task is still around to look up the
+                    // log channels
+                    if let Ok(ctx) = connected_receiver.try_recv() {
+                        announce_offline(&data, &ctx).await;
+                    }
+
+                    // Stop taking events so nothing new is sent to the DB task
+                    shard_manager_handle.lock().await.shutdown_all().await;
+
+                    // Let any running job finish (a compress can take a while)
+                    let _ = background_jobs_shutdown_sender.send(());
+                    db_background_task_handle.await??;
+
+                    // Runs everything queued before it then optimizes and closes the DB
+                    data.db_close().await?;
+                    db_task_handle.await??;
+
+                    info!("Shutdown complete");
+                    Ok::<_, Error>(())
+                }.await),
+            };
             shard_manager_handle.lock().await.shutdown_all().await;
-            match r {
-                Either::Left((r, _)) => r,
-                Either::Right((r, _)) => r,
-            }
+            r
         },
     )
     .await;
-    // TODO: Handle SIGTERM to cleanup DB before exit
 
     // First ? is for join result, 2nd is for the actual task result
     db_r??;
@@ -274,6 +351,98 @@ async fn background_tasks(data: BotData, ctx: Context, guild: Guild) -> Result<(
     }
 }
 
+/// Checks the guild's role menus every tick. The guild is looked up in the cache each
+/// time so renames and log channel changes are picked up, the task stops once the bot
+/// is no longer in the guild
+async fn role_menu_reconciliation_task(data: BotData, ctx: Context, guild_id: GuildId) {
+    let mut tick_interval = time::interval(data.background_task_frequency);
+    // What's been posted to the log already so the same problems aren't posted every tick
+    let mut reported = HashSet::new();
+
+    loop {
+        tick_interval.tick().await;
+
+        let guild = match ctx.cache.guild(*guild_id) {
+            Some(guild) => guild,
+            None => {
+                info!("No longer in guild {}, stopping role menu checks", guild_id.0);
+                return;
+            }
+        };
+
+        let span = span!(Level::INFO, "Checking role menus");
+        async {
+            let problems = check_interaction_roles(&ctx, &data, guild.id.into()).await?;
+            let (new, resolved) = diff_role_menu_problems(&reported, &problems);
+            if new.len() == 0 && resolved.len() == 0 {
+                return Ok::<_, Error>(());
+            }
+
+            warn!("Role menu problems in guild {} ({}): {:?}", guild.name, guild.id, problems);
+            if let Some(log_channel_id) = data.general_log_channel_or_default(&guild).await? {
+                let mut msg = String::new();
+                if new.len() > 0 {
+                    write!(&mut msg, "{}\n", new.join("\n"))?;
+                }
+                if resolved.len() > 0 {
+                    write!(&mut msg, "Resolved:\n{}\n", resolved.join("\n"))?;
+                }
+                let embed = if new.len() > 0 {
+                    Embed::error().title("Role menu problems")
+                } else {
+                    Embed::success().title("Role menu problems resolved")
+                };
+                embed
+                    .description(msg)
+                    .send_in_channel(log_channel_id, &ctx.http)
+                    .await?;
+            }
+            // Only remembered once posted so a failed post is retried on the next tick
+            reported = problems.into_iter().collect();
+
+            Ok(())
+        }
+        .instrument(span)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Error checking role menus for guild: {} ({}): {:?}", guild.name, guild.id, e);
+        });
+    }
+}
+
+/// Posts the results of the DB verify background job in the verify report channel. The
+/// report covers the whole database so it isn't sent to any guild's log channels
+async fn verify_report_task(
+    ctx: Context,
+    receiver: flume::Receiver<VerifyReport>,
+    channel_id: Option<ChannelId>,
+    always: bool,
+) {
+    while let Ok(report) = receiver.recv_async().await {
+        let channel_id = match channel_id {
+            Some(v) => v,
+            // The background job has already logged it
+            None => continue,
+        };
+        let embed = if report.is_ok() {
+            if !always {
+                continue;
+            }
+            Embed::success().title("Database verified")
+        } else {
+            Embed::error().title("Database verify found problems")
+        };
+
+        if let Err(e) = embed
+            .description(report.to_string())
+            .send_in_channel(channel_id, &ctx.http)
+            .await
+        {
+            error!("Error posting DB verify report in channel {}: {:?}", channel_id.0, e);
+        }
+    }
+}
+
 async fn on_error(error: FrameworkError<'_, BotData, PoiseError>) {
     if error.ctx().is_none() {
         error!("Error with no ctx in poise.on_error: {:?}", error);
@@ -391,6 +560,12 @@ async fn event_handler<'a>(
             old,
             new,
         } => handle_voice_state_update(ctx, data, old, new).await?,
+        ReactionAdd {
+            add_reaction,
+        } => handle_legacy_reaction(ctx, data, add_reaction, true).await?,
+        ReactionRemove {
+            removed_reaction,
+        } => handle_legacy_reaction(ctx, data, removed_reaction, false).await?,
         _ => {}
     }
 
@@ -469,6 +644,10 @@ async fn handle_guild_create<'a>(
         warn!("Failed to get log, system or default channels to log to");
     }
     tokio::spawn(background_tasks(data.clone(), ctx.clone(), guild.clone()));
+    data.spawn_role_menu_task(
+        guild.id.into(),
+        role_menu_reconciliation_task(data.clone(), ctx.clone(), guild.id.into()),
+    );
     Ok(())
 }
 
@@ -758,44 +937,6 @@ async fn handle_message_update(
     Ok(())
 }
 
-fn message_to_string(message: &Message) -> Result<Option<String>, Error> {
-    let mut content = message.content.clone();
-
-    for e in message.embeds.iter() {
-        if content.len() > 0 {
-            content.push('\n');
-        }
-        content.push_str("**Embed**\n");
-        if let Some(v) = e.title.as_ref() {
-            write!(&mut content, "*{v}*\n")?;
-        }
-        if let Some(v) = e.description.as_ref() {
-            write!(&mut content, "{v}")?;
-        }
-    }
-
-    for a in message.attachments.iter() {
-        if content.len() > 0 {
-            content.push('\n');
-        }
-        content.push_str("**Attachment**\n");
-        write!(&mut content, "*{}*\n", a.filename)?;
-        if let Some(v) = a.content_type.as_ref() {
-            write!(&mut content, "{v}\n")?;
-        }
-        write!(&mut content, "{}", a.url)?;
-    }
-
-    let content = if content.len() > 0 {
-        Some(content)
-    } else {
-        debug!("message_to_string empty message: {:#?}", message);
-        None
-    };
-
-    Ok(content)
-}
-
 /// Logs the message history to the provided writer. Also returns the log in
 /// case it can be of futher use
 async fn log_message_history<'a, T: Write>(
@@ -855,28 +996,68 @@ async fn handle_message_component_interaction<'a>(
         return Ok(());
     };
 
-    if message_component.guild_id.is_none()
-        || message_component.data.component_type != ComponentType::Button
-    {
-        return Ok(());
-    }
+    let guild_id = match message_component.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    let route = match route_custom_id(data, guild_id.into(), &message_component.data.custom_id).await? {
+        Some(route) => route,
+        None => {
+            debug!(
+                "Ignoring component interaction with unknown custom_id: {}",
+                message_component.data.custom_id
+            );
+            return Ok(());
+        }
+    };
 
-    fn split_custom_id(custom_id: Option<&str>) -> Result<(String, RoleId), Error> {
-        let custom_id = custom_id.ok_or(anyhow::anyhow!("Interaction custom_id missing"))?;
-        let parts = custom_id
-            .split(INTERACTION_BUTTON_CUSTOM_ID_DELIMITER)
-            .collect::<Vec<_>>();
+    match (message_component.data.component_type, route) {
+        (ComponentType::Button, ComponentRoute::RoleButton { set_name, choice }) => {
+            let ir = data
+                .get_interaction_role(guild_id.into(), set_name.clone())
+                .await?
+                .ok_or(anyhow::anyhow!("Interaction role \"{}\" doesn't exist", set_name))?;
+            let role_id = ir
+                .choices
+                .iter()
+                .find(|c| c.choice == choice)
+                .map(|c| c.role_id)
+                .ok_or(anyhow::anyhow!("Interaction role \"{}\" has no choice \"{}\"", set_name, choice))?;
 
-        if parts.len() == 3 && parts[0] == INTERACTION_BUTTON_CUSTOM_ID_PREFIX {
-            Ok((parts[1].to_string(), RoleId::from(parts[2].parse::<u64>()?)))
-        } else {
-            Err(anyhow::anyhow!("Interaction custom_id didn't match the expected format"))?
+            handle_role_button(ctx, data, message_component, ir, role_id).await
+        }
+        (ComponentType::Button, ComponentRoute::LegacyRoleButton { set_name, role_id }) => {
+            let ir = sync_legacy_role_buttons(data, interaction, message_component, set_name).await?;
+            handle_role_button(ctx, data, message_component, ir, role_id).await
+        }
+        (ComponentType::SelectMenu, ComponentRoute::RoleSelect { set_name, page }) => {
+            handle_role_select(ctx, data, message_component, set_name, page).await
+        }
+        (ComponentType::Button, ComponentRoute::Confirmation { id, confirmed }) => {
+            handle_confirmation(ctx, data, message_component, id, confirmed).await
+        }
+        (ComponentType::Button, ComponentRoute::SearchPage { search_id, query, page }) => {
+            handle_search_page(ctx, data, message_component, search_id, query, page).await
+        }
+        (component_type, route) => {
+            warn!("Component type {:?} doesn't match route {:?}", component_type, route);
+            Ok(())
         }
     }
+}
 
-    let (name, _) = split_custom_id(Some(message_component.data.custom_id.as_str()))?;
-
-    let guild_id = message_component.guild_id.unwrap();
+/// Buttons posted before role menus were managed by the bot only exist on the message.
+/// This creates the interaction role and choices in the DB from them
+async fn sync_legacy_role_buttons(
+    data: &BotData,
+    interaction: &Interaction,
+    message_component: &MessageComponentInteraction,
+    name: String,
+) -> Result<InteractionRole, Error> {
+    let guild_id = message_component
+        .guild_id
+        .ok_or(anyhow::anyhow!("Button interaction missing guild"))?;
 
     let message = &message_component.message;
     let embed = if message.embeds.len() == 1 {
@@ -909,7 +1090,11 @@ async fn handle_message_component_interaction<'a>(
                 "Interaction button with no label not supported"
             ))?
             .clone();
-        let (_, role_id) = split_custom_id(button.custom_id.as_ref().map(|v| v.as_str()))?;
+        let custom_id = button
+            .custom_id
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Interaction custom_id missing"))?;
+        let (_, role_id) = split_legacy_button_custom_id(custom_id)?;
         let emoji = button.emoji.as_ref().map(|v| format!("{}", v));
 
         choices.push((choice, role_id, emoji));
@@ -962,14 +1147,80 @@ async fn handle_message_component_interaction<'a>(
         }
     }
 
+    Ok(ir.ok_or(anyhow::anyhow!("Interaction role \"{}\" missing after sync", name))?)
+}
+
+async fn handle_role_button(
+    ctx: &serenity::Context,
+    data: &BotData,
+    message_component: &MessageComponentInteraction,
+    ir: InteractionRole,
+    role_id: RoleId,
+) -> Result<(), Error> {
+    let mut member = message_component
+        .member
+        .clone()
+        .ok_or(anyhow::anyhow!("Button interaction missing member"))?;
+
+    let embed = match toggle_interaction_role(ctx, data, &ir, &mut member, role_id).await {
+        Ok(result @ ToggleRoleResult::Refused(..)) => Embed::error().description(result.describe()),
+        Ok(result) => Embed::success().description(result.describe()),
+        Err(e) => {
+            error!("Error toggling interaction role \"{}\" for {}: {:?}", ir.name, member, e);
+            Embed::error().description("Sorry, I wasn't able to update your roles. Please let a mod know")
+        }
+    };
+
     message_component
         .create_interaction_response(&ctx.http, |b| {
             b.interaction_response_data(|b| {
-                b.ephemeral(true).embed(|b| {
-                    Embed::default()
-                        .description(format!("{:#?}", ir))
-                        .create_embed(b)
-                })
+                b.ephemeral(true).embed(|b| embed.create_embed(b))
+            })
+        })
+        .await?;
+
+    Ok(())
+}
+
+async fn handle_role_select(
+    ctx: &serenity::Context,
+    data: &BotData,
+    message_component: &MessageComponentInteraction,
+    name: String,
+    page: usize,
+) -> Result<(), Error> {
+    let guild_id = message_component
+        .guild_id
+        .ok_or(anyhow::anyhow!("Select menu interaction missing guild"))?;
+
+    let ir = data
+        .get_interaction_role(guild_id.into(), name.clone())
+        .await?
+        .ok_or(anyhow::anyhow!("Interaction role \"{}\" doesn't exist", name))?;
+    let mut member = message_component
+        .member
+        .clone()
+        .ok_or(anyhow::anyhow!("Select menu interaction missing member"))?;
+
+    let selected = message_component
+        .data
+        .values
+        .iter()
+        .map(|v| Ok(RoleId::from(v.parse::<u64>()?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let embed = match reconcile_interaction_roles(ctx, data, &ir, &mut member, page, selected).await {
+        Ok(result) => Embed::success().description(result.describe()),
+        Err(e) => {
+            error!("Error reconciling interaction role \"{}\" for {}: {:?}", name, member, e);
+            Embed::error().description("Sorry, I wasn't able to update your roles. Please let a mod know")
+        }
+    };
+
+    message_component
+        .create_interaction_response(&ctx.http, |b| {
+            b.interaction_response_data(|b| {
+                b.ephemeral(true).embed(|b| embed.create_embed(b))
             })
         })
         .await?;
@@ -4,7 +4,7 @@ use chrono::Utc;
 use clap::Parser;
 use futures::future::join;
 use gagbot_rs::{
-    commands::greet::{run_greet, GreetBehaviour},
+    commands::{greet::{run_greet, GreetBehaviour}, shutdown::{announce_offline, wait_for_shutdown_signal}},
     db::{
         open_database, spawn_db_task, DbCommand
     },
@@ -18,6 +18,7 @@ use poise::{
     },
     FrameworkContext, FrameworkError,
 };
+use tokio::sync::oneshot;
 use tracing::*;
 
 fn frequency_seconds_valid_range(s: &str) -> Result<u64, String> {
@@ -64,6 +65,15 @@ async fn main() -> Result<(), Error> {
     
     let db_task_handle = spawn_db_task(sqlite_con, receiver);
 
+    let data = BotData::new(
+        sender,
+        db_file_path,
+        background_task_frequency,
+        None,
+    );
+    // The shutdown needs the discord context to announce it's going offline
+    let (connected_sender, connected_receiver) = oneshot::channel::<Context>();
+
     let options = poise::FrameworkOptions {
         commands: discord_commands::chihuahua_commands(),
         on_error: |err| Box::pin(on_error(err)),
@@ -78,15 +88,16 @@ async fn main() -> Result<(), Error> {
         .options(options)
         .token(discord_token)
         .intents(GatewayIntents::GUILDS | GatewayIntents::GUILD_MEMBERS)
-        .setup(move |_ctx, _ready, _framework| {
-            debug!("Discord connected");
-            Box::pin(async move {
-                Ok(BotData::new(
-                    sender,
-                    db_file_path,
-                    background_task_frequency,
-                ))
-            })
+        .setup({
+            let data = data.clone();
+            move |ctx, _ready, _framework| {
+                debug!("Discord connected");
+                let ctx = ctx.clone();
+                Box::pin(async move {
+                    let _ = connected_sender.send(ctx);
+                    Ok(data)
+                })
+            }
         })
         .build()
         .await?;
@@ -96,9 +107,33 @@ async fn main() -> Result<(), Error> {
         // Don't need to do anything special in this case as the dropped sender will cause the db
         // task to stop
         framework.start(),
-        // In this case however, if the db exits first the framework needs to be shut down
+        // In this case however, if the db exits first or we get a shutdown signal the 
+        // framework needs to be shut down
         async move {
-            let r = db_task_handle.await;
+            let mut db_task_handle = db_task_handle;
+            let mut connected_receiver = connected_receiver;
+            let r = tokio::select! {
+                r = &mut db_task_handle => r,
+                signal = wait_for_shutdown_signal() => Ok(async {
+                    info!("Received {}, shutting down", signal?);
+
+                    // This has to happen while the DB task is still around to look up the
+                    // log channels
+                    if let Ok(ctx) = connected_receiver.try_recv() {
+                        announce_offline(&data, &ctx).await;
+                    }
+
+                    // Stop taking events so nothing new is sent to the DB task
+                    shard_manager_handle.lock().await.shutdown_all().await;
+
+                    // Runs everything queued before it then optimizes and closes the DB
+                    data.db_close().await?;
+                    db_task_handle.await??;
+
+                    info!("Shutdown complete");
+                    Ok::<_, Error>(())
+                }.await),
+            };
             shard_manager_handle.lock().await.shutdown_all().await;
             r
         },
@@ -0,0 +1,249 @@
+use poise::serenity_prelude::{
+    self as serenity, ButtonStyle, InteractionResponseType, MessageComponentInteraction, Timestamp,
+};
+use serde_json::Value;
+use tracing::{debug, error};
+
+use crate::{
+    commands::{
+        custom_ids::{confirmation_custom_id, require_component_permission},
+        purge::{run_purge, MessagePurgeRequest},
+    },
+    db::queries::{
+        config::LogChannel,
+        confirmations::{ConfirmationAction, PendingConfirmation},
+    },
+    with_progress_embed, BotData, Context, Embed, Error, GuildId, UserId,
+};
+
+/// How long the confirm button stays valid for
+const CONFIRMATION_TIMEOUT_SECONDS: i64 = 15 * 60;
+
+/// Stores the action and asks the user to confirm it. The action is run by
+/// `handle_confirmation` when the button is clicked, even if the bot has restarted
+/// in between
+pub async fn request_confirmation(
+    ctx: Context<'_>,
+    action: ConfirmationAction,
+    payload: Value,
+    prompt: &str,
+    confirm_label: &str,
+) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or(anyhow::anyhow!("Confirmations are only supported in guilds"))?;
+    let timestamp = ctx.created_at();
+    let expires = timestamp.unix_timestamp() + CONFIRMATION_TIMEOUT_SECONDS;
+
+    let id = ctx
+        .data()
+        .create_pending_confirmation(
+            guild_id.into(),
+            ctx.author().id.into(),
+            action,
+            payload,
+            expires,
+            timestamp,
+        )
+        .await?;
+
+    ctx.send(|m| m
+        .embed(|b| Embed::default()
+            .description(format!("{}\n\nExpires <t:{}:R>", prompt, expires))
+            .create_embed(b)
+        )
+        .ephemeral(true)
+        .components(|c| c
+            .create_action_row(|r| r
+                .create_button(|b| b
+                    .custom_id(confirmation_custom_id(&id, true))
+                    .label(confirm_label)
+                    .style(ButtonStyle::Danger)
+                )
+                .create_button(|b| b
+                    .custom_id(confirmation_custom_id(&id, false))
+                    .label("Cancel")
+                    .style(ButtonStyle::Secondary)
+                )
+            )
+        )
+    ).await?;
+
+    Ok(())
+}
+
+/// Replaces the confirmation message with the embed and removes the buttons
+async fn update_message(
+    ctx: &serenity::Context,
+    message_component: &MessageComponentInteraction,
+    embed: Embed,
+) -> Result<(), Error> {
+    message_component
+        .create_interaction_response(&ctx.http, |b| {
+            b.kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|b| {
+                    b.embed(|b| embed.create_embed(b)).components(|b| b)
+                })
+        })
+        .await?;
+    Ok(())
+}
+
+/// Replies to the click with an ephemeral message, leaving the confirmation as it is
+async fn reply_ephemeral(
+    ctx: &serenity::Context,
+    message_component: &MessageComponentInteraction,
+    embed: Embed,
+) -> Result<(), Error> {
+    message_component
+        .create_interaction_response(&ctx.http, |b| {
+            b.interaction_response_data(|b| {
+                b.ephemeral(true).embed(|b| embed.create_embed(b))
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+/// Handles a click on one of the buttons added by `request_confirmation`
+pub async fn handle_confirmation(
+    ctx: &serenity::Context,
+    data: &BotData,
+    message_component: &MessageComponentInteraction,
+    id: String,
+    confirmed: bool,
+) -> Result<(), Error> {
+    let guild_id: GuildId = message_component
+        .guild_id
+        .ok_or(anyhow::anyhow!("Confirmation interaction missing guild"))?
+        .into();
+
+    let pending = match data.get_pending_confirmation(id.clone()).await? {
+        Some(pending) if pending.guild_id == guild_id => pending,
+        _ => {
+            return update_message(
+                ctx,
+                message_component,
+                Embed::error().description("This has expired or was already handled"),
+            )
+            .await
+        }
+    };
+
+    if *pending.requester_id != message_component.user.id {
+        return reply_ephemeral(
+            ctx,
+            message_component,
+            Embed::error().description("Only the person who ran the command can confirm it"),
+        )
+        .await;
+    }
+
+    if confirmed {
+        if let Err(e) = require_component_permission(
+            ctx,
+            data,
+            message_component,
+            pending.guild_id,
+            pending.action.required_permission(),
+        )
+        .await
+        {
+            debug!("Refusing confirmation {}: {:?}", pending.id, e);
+            return reply_ephemeral(
+                ctx,
+                message_component,
+                Embed::error().description(format!("You can't confirm this: {}", e)),
+            )
+            .await;
+        }
+    }
+
+    // Deleting it first means a double click can't run the action twice
+    if !data.delete_pending_confirmation(id).await? {
+        debug!("Confirmation {} already handled", pending.id);
+        return reply_ephemeral(
+            ctx,
+            message_component,
+            Embed::error().description("This was already handled"),
+        )
+        .await;
+    }
+
+    let timestamp = message_component.id.created_at();
+    if timestamp.unix_timestamp() > pending.expires {
+        return update_message(
+            ctx,
+            message_component,
+            Embed::error().description("Confirmation expired"),
+        )
+        .await;
+    }
+
+    if !confirmed {
+        return update_message(ctx, message_component, Embed::default().description("Cancelled"))
+            .await;
+    }
+
+    update_message(
+        ctx,
+        message_component,
+        Embed::default().description("Processing..."),
+    )
+    .await?;
+
+    let embed = match run_action(ctx, data, &pending, timestamp).await {
+        Ok(msg) => Embed::success().description(msg),
+        Err(e) => {
+            error!("Error running confirmed {:?}: {:?}", pending.action, e);
+            Embed::error().description(format!("Error: {}", e))
+        }
+    };
+
+    message_component
+        .edit_original_interaction_response(&ctx.http, |b| {
+            b.embed(|b| embed.create_embed(b)).components(|b| b)
+        })
+        .await?;
+
+    Ok(())
+}
+
+async fn run_action(
+    ctx: &serenity::Context,
+    data: &BotData,
+    pending: &PendingConfirmation,
+    timestamp: Timestamp,
+) -> Result<String, Error> {
+    Ok(match pending.action {
+        ConfirmationAction::PermissionPurge => {
+            match data.purge_permission(pending.guild_id, timestamp).await? {
+                true => "Purged".to_string(),
+                false => "Nothing to purge :person_shrugging:".to_string(),
+            }
+        }
+        ConfirmationAction::MessagePurge => {
+            let request: MessagePurgeRequest = serde_json::from_value(pending.payload.clone())?;
+            with_progress_embed(
+                data,
+                ctx,
+                pending.guild_id,
+                LogChannel::EditsAndDeletes,
+                "Purge",
+                run_purge,
+                (request, data),
+            )
+            .await?;
+            "Purged".to_string()
+        }
+        ConfirmationAction::ForgetUser => {
+            let user_id = pending
+                .payload
+                .as_u64()
+                .ok_or(anyhow::anyhow!("ForgetUser payload isn't a user id"))?;
+            data.forget_user(Some(pending.guild_id), UserId::from(user_id))
+                .await?
+                .to_string()
+        }
+    })
+}
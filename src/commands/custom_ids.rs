@@ -0,0 +1,187 @@
+use poise::serenity_prelude::{self as serenity, MessageComponentInteraction, Timestamp};
+use serde_json::Value;
+
+use crate::{
+    commands::interaction_roles::parse_select_page_payload,
+    db::queries::{
+        custom_ids::InteractionKind, message_log::MessageSearchQuery, permissions::Permission,
+    },
+    BotData, Error, GuildId, RoleId,
+    INTERACTION_BUTTON_CUSTOM_ID_PREFIX, INTERACTION_CONFIRM_CUSTOM_ID_PREFIX,
+    INTERACTION_CUSTOM_ID_DELIMITER, INTERACTION_CUSTOM_ID_PREFIX,
+};
+
+/// What a message component's custom_id resolved to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComponentRoute {
+    RoleButton {
+        set_name: String,
+        choice: String,
+    },
+    RoleSelect {
+        set_name: String,
+        /// Which of the menu's select menus it was, see `interaction_role_pages`
+        page: usize,
+    },
+    /// Buttons posted before the registry existed carry the set name and role id
+    /// in the custom_id itself
+    LegacyRoleButton {
+        set_name: String,
+        role_id: RoleId,
+    },
+    /// One of the buttons on a pending confirmation
+    Confirmation {
+        id: String,
+        confirmed: bool,
+    },
+    /// A next/previous button on message search results
+    SearchPage {
+        search_id: String,
+        query: MessageSearchQuery,
+        page: u64,
+    },
+}
+
+const CONFIRM_OK: &str = "ok";
+const CONFIRM_CANCEL: &str = "cancel";
+
+/// Registers the payload and returns the custom_id to attach to the component
+pub async fn register_custom_id(
+    data: &BotData,
+    guild_id: GuildId,
+    kind: InteractionKind,
+    set_name: String,
+    choice: String,
+) -> Result<String, Error> {
+    register_custom_id_with_extra(data, guild_id, kind, set_name, choice, None).await
+}
+
+/// `register_custom_id` for components that need more than the set name and choice
+pub async fn register_custom_id_with_extra(
+    data: &BotData,
+    guild_id: GuildId,
+    kind: InteractionKind,
+    set_name: String,
+    choice: String,
+    extra: Option<Value>,
+) -> Result<String, Error> {
+    let id = data
+        .get_or_create_interaction_custom_id(guild_id, kind, set_name, choice, extra, Timestamp::now())
+        .await?;
+
+    Ok(format!(
+        "{}{}{}",
+        INTERACTION_CUSTOM_ID_PREFIX, INTERACTION_CUSTOM_ID_DELIMITER, id
+    ))
+}
+
+/// The custom_id for the confirm or cancel button of a pending confirmation
+pub fn confirmation_custom_id(id: &str, confirmed: bool) -> String {
+    format!(
+        "{}{}{}{}{}",
+        INTERACTION_CONFIRM_CUSTOM_ID_PREFIX,
+        INTERACTION_CUSTOM_ID_DELIMITER,
+        id,
+        INTERACTION_CUSTOM_ID_DELIMITER,
+        if confirmed { CONFIRM_OK } else { CONFIRM_CANCEL }
+    )
+}
+
+/// Parses the legacy "rr¬name¬role_id" button format
+pub fn split_legacy_button_custom_id(custom_id: &str) -> Result<(String, RoleId), Error> {
+    let parts = custom_id
+        .split(INTERACTION_CUSTOM_ID_DELIMITER)
+        .collect::<Vec<_>>();
+
+    if parts.len() == 3 && parts[0] == INTERACTION_BUTTON_CUSTOM_ID_PREFIX {
+        Ok((parts[1].to_string(), RoleId::from(parts[2].parse::<u64>()?)))
+    } else {
+        Err(anyhow::anyhow!("Interaction custom_id didn't match the expected format"))?
+    }
+}
+
+/// Works out what the component with the custom_id does. Returns None if the
+/// custom_id isn't one of ours (or the registry entry has been deleted)
+pub async fn route_custom_id(
+    data: &BotData,
+    guild_id: GuildId,
+    custom_id: &str,
+) -> Result<Option<ComponentRoute>, Error> {
+    let mut parts = custom_id.splitn(2, INTERACTION_CUSTOM_ID_DELIMITER);
+    let (prefix, rest) = match (parts.next(), parts.next()) {
+        (Some(prefix), Some(rest)) => (prefix, rest),
+        _ => return Ok(None),
+    };
+
+    Ok(match prefix {
+        INTERACTION_CUSTOM_ID_PREFIX => {
+            match data.get_interaction_custom_id(rest.to_string()).await? {
+                // Don't let a custom_id from one guild act on another
+                Some(entry) if entry.guild_id == guild_id => Some(match entry.kind {
+                    InteractionKind::RoleButton => ComponentRoute::RoleButton {
+                        set_name: entry.set_name,
+                        choice: entry.choice,
+                    },
+                    InteractionKind::RoleSelect => ComponentRoute::RoleSelect {
+                        set_name: entry.set_name,
+                        page: parse_select_page_payload(&entry.choice)?,
+                    },
+                    InteractionKind::SearchPage => ComponentRoute::SearchPage {
+                        query: MessageSearchQuery::from_json(
+                            entry
+                                .extra
+                                .as_ref()
+                                .ok_or(anyhow::anyhow!("Search page custom_id {} has no query", entry.id))?,
+                        )?,
+                        search_id: entry.set_name,
+                        page: entry.choice.parse()?,
+                    },
+                }),
+                _ => None,
+            }
+        }
+        INTERACTION_BUTTON_CUSTOM_ID_PREFIX => {
+            let (set_name, role_id) = split_legacy_button_custom_id(custom_id)?;
+            Some(ComponentRoute::LegacyRoleButton { set_name, role_id })
+        }
+        INTERACTION_CONFIRM_CUSTOM_ID_PREFIX => {
+            let mut parts = rest.splitn(2, INTERACTION_CUSTOM_ID_DELIMITER);
+            match (parts.next(), parts.next()) {
+                (Some(id), Some(CONFIRM_OK)) => Some(ComponentRoute::Confirmation {
+                    id: id.to_string(),
+                    confirmed: true,
+                }),
+                (Some(id), Some(CONFIRM_CANCEL)) => Some(ComponentRoute::Confirmation {
+                    id: id.to_string(),
+                    confirmed: false,
+                }),
+                _ => None,
+            }
+        }
+        _ => None,
+    })
+}
+
+/// Checks the member clicking a component has the permission it needs. The guild
+/// owner always does, the same as `PermissionCheck::require_permission`
+pub async fn require_component_permission(
+    ctx: &serenity::Context,
+    data: &BotData,
+    message_component: &MessageComponentInteraction,
+    guild_id: GuildId,
+    permission: Permission,
+) -> Result<(), Error> {
+    let guild = ctx
+        .cache
+        .guild(*guild_id)
+        .ok_or(anyhow::anyhow!("Guild {} isn't cached", guild_id.0))?;
+    let member = message_component
+        .member
+        .as_ref()
+        .ok_or(anyhow::anyhow!("Component interaction missing member"))?;
+
+    if cfg!(debug_assertions) || guild.owner_id != member.user.id {
+        data.require_permission(&guild, member, permission).await?;
+    }
+    Ok(())
+}
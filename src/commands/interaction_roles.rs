@@ -0,0 +1,769 @@
+use std::collections::{HashMap, HashSet};
+
+use poise::serenity_prelude::{
+    self as serenity, ButtonStyle, CacheHttp, CreateActionRow, CreateButton, CreateComponents,
+    CreateSelectMenu, CreateSelectMenuOption, Http, HttpError, Member, Permissions, ReactionType,
+    Role, Timestamp,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    commands::custom_ids::register_custom_id,
+    db::queries::{
+        config::ConfigKey,
+        custom_ids::InteractionKind,
+        interaction_roles::{InteractionChoice, InteractionRole, InteractionRoleStyle},
+    },
+    ensure, get_config_bool_option, BotData, Embed, ErrorContext, Error, GuildId, MessageId, RoleId,
+};
+
+/// Discord allows 5 action rows of 5 buttons
+pub const INTERACTION_ROLE_MAX_BUTTONS: usize = 25;
+const INTERACTION_ROLE_BUTTONS_PER_ROW: usize = 5;
+/// Discord allows 25 options in a select menu
+pub const INTERACTION_ROLE_OPTIONS_PER_SELECT: usize = 25;
+/// Dropdown menus are split across up to 5 select menus, one per action row
+pub const INTERACTION_ROLE_MAX_CHOICES: usize = 5 * INTERACTION_ROLE_OPTIONS_PER_SELECT;
+/// Discord limits embed titles to 256 characters
+pub const INTERACTION_ROLE_NAME_MAX_LEN: usize = 256;
+/// Discord's JSON error codes for a channel or message that doesn't exist
+const DISCORD_UNKNOWN_CHANNEL: isize = 10003;
+const DISCORD_UNKNOWN_MESSAGE: isize = 10008;
+
+#[derive(Debug)]
+pub enum ToggleRoleResult {
+    /// The role was added. Any roles removed because the set is exclusive are
+    /// also returned
+    Added(RoleId, Vec<RoleId>),
+    Removed(RoleId),
+    /// The member doesn't meet the choice's requirements. The reason is included
+    Refused(RoleId, String),
+}
+
+impl ToggleRoleResult {
+    /// Friendly description of what changed suitable for showing the member
+    pub fn describe(&self) -> String {
+        match self {
+            ToggleRoleResult::Added(role_id, removed) if removed.len() > 0 => {
+                let verb = if removed.len() > 1 { "were" } else { "was" };
+                let removed = removed
+                    .iter()
+                    .map(|v| format!("<@&{}>", v.0))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("You now have <@&{}>. Only one role from this menu can be held at a time so {} {} removed", role_id.0, removed, verb)
+            }
+            ToggleRoleResult::Added(role_id, _) => format!("You now have <@&{}>", role_id.0),
+            ToggleRoleResult::Removed(role_id) => format!("<@&{}> has been removed", role_id.0),
+            ToggleRoleResult::Refused(role_id, reason) => {
+                format!("You can't have <@&{}> yet: {}", role_id.0, reason)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReconcileRolesResult {
+    pub added: Vec<RoleId>,
+    pub removed: Vec<RoleId>,
+    /// Selected roles the member doesn't meet the requirements for, with the reason
+    pub refused: Vec<(RoleId, String)>,
+}
+
+impl ReconcileRolesResult {
+    /// Friendly description of what changed suitable for showing the member
+    pub fn describe(&self) -> String {
+        fn mentions(roles: &[RoleId]) -> String {
+            roles
+                .iter()
+                .map(|v| format!("<@&{}>", v.0))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+
+        let mut msg = Vec::new();
+        if self.added.len() > 0 {
+            msg.push(format!("Added {}", mentions(&self.added)));
+        }
+        if self.removed.len() > 0 {
+            msg.push(format!("Removed {}", mentions(&self.removed)));
+        }
+        for (role_id, reason) in self.refused.iter() {
+            msg.push(format!("You can't have <@&{}> yet: {}", role_id.0, reason));
+        }
+        if msg.len() == 0 {
+            "Your roles were already up to date".to_string()
+        } else {
+            msg.join("\n")
+        }
+    }
+}
+
+/// Checks the member meets the choice's requirements. Returns the reason they
+/// don't, or None if they can have the role
+pub async fn check_choice_requirements(
+    data: &BotData,
+    member: &Member,
+    choice: &InteractionChoice,
+) -> Result<Option<String>, Error> {
+    if let Some(required) = choice.required_role_id {
+        if !member.roles.contains(&*required) {
+            return Ok(Some(format!("you need <@&{}> first", required.0)));
+        }
+    }
+
+    if let Some(forbidden) = choice.forbidden_role_id {
+        if member.roles.contains(&*forbidden) {
+            return Ok(Some(format!(
+                "it isn't available to members with <@&{}>",
+                forbidden.0
+            )));
+        }
+    }
+
+    if let Some(min_message_count) = choice.min_message_count {
+        let message_count = data
+            .message_count(member.guild_id.into(), member.user.id.into(), None)
+            .await?;
+        if message_count < min_message_count {
+            return Ok(Some(format!(
+                "you need to have posted at least {} messages (you've posted {})",
+                min_message_count, message_count
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The most choices a menu of the given style can show
+pub fn interaction_role_max_choices(style: InteractionRoleStyle) -> usize {
+    match style {
+        InteractionRoleStyle::Buttons => INTERACTION_ROLE_MAX_BUTTONS,
+        InteractionRoleStyle::Dropdown => INTERACTION_ROLE_MAX_CHOICES,
+    }
+}
+
+/// The choices shown in each select menu of a dropdown style menu
+pub fn interaction_role_pages(interaction_role: &InteractionRole) -> Vec<&[InteractionChoice]> {
+    interaction_role
+        .choices
+        .chunks(INTERACTION_ROLE_OPTIONS_PER_SELECT)
+        .collect()
+}
+
+/// The (min, max) number of values that can be selected in one page of a dropdown
+/// style menu, taking the defaults and exclusive flag into account. When the choices
+/// are split across several select menus the max applies to each of them and the min
+/// is ignored, otherwise members would be forced to pick from every one
+pub fn interaction_role_value_limits(interaction_role: &InteractionRole, page: usize) -> (u8, u8) {
+    let pages = interaction_role_pages(interaction_role);
+    let choices = pages.get(page).map_or(0, |p| p.len()) as u8;
+    let max = if interaction_role.exclusive {
+        1
+    } else {
+        interaction_role.max_values.unwrap_or(choices)
+    }
+    .min(choices)
+    .max(1);
+    let min = if pages.len() > 1 {
+        0
+    } else {
+        interaction_role.min_values.unwrap_or(0).min(max)
+    };
+
+    (min, max)
+}
+
+/// Makes the member's roles from one page of the set match the selection. Roles from
+/// the page that weren't selected are removed, selected roles are added. If the set is
+/// exclusive and a role was added, roles held from the other pages are removed too
+pub async fn reconcile_interaction_roles<T>(
+    ctx: &T,
+    data: &BotData,
+    interaction_role: &InteractionRole,
+    member: &mut Member,
+    page: usize,
+    selected: Vec<RoleId>,
+) -> Result<ReconcileRolesResult, Error>
+where
+    T: AsRef<Http>,
+{
+    let pages = interaction_role_pages(interaction_role);
+    let page_choices = *pages.get(page).ok_or(anyhow::anyhow!(
+        "Interaction role set \"{}\" has no dropdown {}",
+        interaction_role.name,
+        page
+    ))?;
+
+    for role_id in selected.iter() {
+        ensure!(
+            page_choices.iter().any(|c| c.role_id == *role_id),
+            "Role {:?} is not a choice in interaction role set \"{}\"",
+            role_id,
+            interaction_role.name
+        );
+    }
+
+    let (min, max) = interaction_role_value_limits(interaction_role, page);
+    ensure!(
+        selected.len() >= min as usize && selected.len() <= max as usize,
+        "Between {} and {} roles must be selected from \"{}\"",
+        min,
+        max,
+        interaction_role.name
+    );
+
+    let mut result = ReconcileRolesResult::default();
+    for choice in page_choices.iter() {
+        let has = member.roles.contains(&*choice.role_id);
+        let wants = selected.contains(&choice.role_id);
+        if has && !wants {
+            result.removed.push(choice.role_id);
+        } else if !has && wants {
+            match check_choice_requirements(data, member, choice).await? {
+                Some(reason) => result.refused.push((choice.role_id, reason)),
+                None => result.added.push(choice.role_id),
+            }
+        }
+    }
+
+    if interaction_role.exclusive && result.added.len() > 0 {
+        for choice in interaction_role.choices.iter() {
+            if member.roles.contains(&*choice.role_id)
+                && !page_choices.iter().any(|c| c.role_id == choice.role_id)
+            {
+                result.removed.push(choice.role_id);
+            }
+        }
+    }
+
+    if result.removed.len() > 0 {
+        debug!("Removing {:?} from {}", result.removed, member);
+        member
+            .remove_roles(ctx, &result.removed.iter().map(|v| **v).collect::<Vec<_>>())
+            .await
+            .context("Removing deselected interaction roles")?;
+    }
+    if result.added.len() > 0 {
+        debug!("Adding {:?} to {}", result.added, member);
+        member
+            .add_roles(ctx, &result.added.iter().map(|v| **v).collect::<Vec<_>>())
+            .await
+            .context("Adding selected interaction roles")?;
+    }
+
+    Ok(result)
+}
+
+/// Toggles the given role on the member. If the member already has it, it is
+/// removed, otherwise it's added. When the set is exclusive any other roles
+/// from the set the member holds are removed when adding.
+pub async fn toggle_interaction_role<T>(
+    ctx: &T,
+    data: &BotData,
+    interaction_role: &InteractionRole,
+    member: &mut Member,
+    role_id: RoleId,
+) -> Result<ToggleRoleResult, Error>
+where
+    T: AsRef<Http>,
+{
+    let choice = interaction_role
+        .choices
+        .iter()
+        .find(|c| c.role_id == role_id)
+        .ok_or(anyhow::anyhow!(
+            "Role {:?} is not a choice in interaction role set \"{}\"",
+            role_id,
+            interaction_role.name
+        ))?;
+
+    if member.roles.contains(&*role_id) {
+        debug!("Removing {:?} from {}", role_id, member);
+        member
+            .remove_role(ctx, *role_id)
+            .await
+            .context("Removing interaction role")?;
+
+        return Ok(ToggleRoleResult::Removed(role_id));
+    }
+
+    if let Some(reason) = check_choice_requirements(data, member, choice).await? {
+        debug!("Refusing {:?} for {}: {}", role_id, member, reason);
+        return Ok(ToggleRoleResult::Refused(role_id, reason));
+    }
+
+    let mut removed = Vec::new();
+    if interaction_role.exclusive {
+        let others = interaction_role
+            .choices
+            .iter()
+            .map(|c| c.role_id)
+            .filter(|r| *r != role_id && member.roles.contains(&**r))
+            .collect::<Vec<_>>();
+
+        if others.len() > 0 {
+            debug!("Removing {:?} from {} (exclusive set)", others, member);
+            member
+                .remove_roles(ctx, &others.iter().map(|v| **v).collect::<Vec<_>>())
+                .await
+                .context("Removing other exclusive interaction roles")?;
+            removed = others;
+        }
+    }
+
+    debug!("Adding {:?} to {}", role_id, member);
+    member
+        .add_role(ctx, *role_id)
+        .await
+        .context("Adding interaction role")?;
+
+    Ok(ToggleRoleResult::Added(role_id, removed))
+}
+
+/// Checks the name will fit in the title of the menu embed
+pub fn validate_interaction_role_name(name: &str) -> Result<(), Error> {
+    ensure!(name.len() > 0, "Role menu name can't be empty");
+    ensure!(
+        name.chars().count() <= INTERACTION_ROLE_NAME_MAX_LEN,
+        "Role menu name must be at most {} characters long",
+        INTERACTION_ROLE_NAME_MAX_LEN
+    );
+    Ok(())
+}
+
+/// Who is putting a role on a role menu, for checking they could hand it out themselves
+#[derive(Debug, Clone, Copy)]
+pub enum RoleAssigner<'a> {
+    /// The guild owner, or the bot's operator from gagbot_admin. They can use any role
+    /// that isn't too powerful
+    Owner,
+    /// Any other member. They can only use roles below their highest role
+    Member(&'a Member),
+}
+
+/// Discord permissions that make a role too powerful to give to anyone who clicks a button
+fn elevated_permissions() -> Permissions {
+    Permissions::ADMINISTRATOR
+        | Permissions::MANAGE_GUILD
+        | Permissions::MANAGE_ROLES
+        | Permissions::MANAGE_CHANNELS
+        | Permissions::MANAGE_MESSAGES
+        | Permissions::MANAGE_NICKNAMES
+        | Permissions::MANAGE_WEBHOOKS
+        | Permissions::MANAGE_EMOJIS_AND_STICKERS
+        | Permissions::MANAGE_EVENTS
+        | Permissions::MANAGE_THREADS
+        | Permissions::KICK_MEMBERS
+        | Permissions::BAN_MEMBERS
+        | Permissions::MODERATE_MEMBERS
+        | Permissions::MUTE_MEMBERS
+        | Permissions::DEAFEN_MEMBERS
+        | Permissions::MOVE_MEMBERS
+        | Permissions::MENTION_EVERYONE
+        | Permissions::VIEW_AUDIT_LOG
+}
+
+/// Checks the role can be handed out by a role menu. Everyone who clicks the menu gets
+/// it so it mustn't be @everyone, managed by an integration, carry moderation or admin
+/// permissions or (unless the owner is setting it up) be at or above the assigner's
+/// highest role
+pub fn check_role_menu_role(
+    guild_id: GuildId,
+    roles: &HashMap<serenity::RoleId, Role>,
+    assigner: RoleAssigner<'_>,
+    role_id: RoleId,
+) -> Result<(), Error> {
+    ensure!(role_id.0 != guild_id.0, "@everyone can't be used in a role menu");
+    let role = roles
+        .get(&*role_id)
+        .ok_or_else(|| anyhow::anyhow!("<@&{}> doesn't exist", role_id.0))?;
+    ensure!(
+        !role.managed,
+        "<@&{}> is managed by an integration so it can't be used in a role menu",
+        role_id.0
+    );
+
+    let elevated = role.permissions & elevated_permissions();
+    ensure!(
+        elevated.is_empty(),
+        "<@&{}> has moderation permissions ({}) so it can't be used in a role menu",
+        role_id.0,
+        elevated.get_permission_names().join(", ")
+    );
+
+    if let RoleAssigner::Member(member) = assigner {
+        let highest = member
+            .roles
+            .iter()
+            .filter_map(|r| roles.get(r))
+            .map(|r| r.position)
+            .max()
+            .unwrap_or(0);
+        ensure!(
+            role.position < highest,
+            "<@&{}> isn't below your highest role so you can't add it to a role menu",
+            role_id.0
+        );
+    }
+
+    Ok(())
+}
+
+pub fn parse_emoji(emoji: &str) -> Result<ReactionType, Error> {
+    Ok(ReactionType::try_from(emoji)
+        .map_err(|_| anyhow::anyhow!("\"{}\" isn't a valid emoji", emoji))?)
+}
+
+/// The registry payload for a page of a dropdown menu. The first page is empty so
+/// menus published before they could be split keep working
+fn select_page_payload(page: usize) -> String {
+    if page == 0 {
+        String::new()
+    } else {
+        page.to_string()
+    }
+}
+
+/// Reverses `select_page_payload`
+pub fn parse_select_page_payload(payload: &str) -> Result<usize, Error> {
+    if payload.is_empty() {
+        Ok(0)
+    } else {
+        Ok(payload.parse()?)
+    }
+}
+
+fn interaction_role_embed(interaction_role: &InteractionRole) -> Embed {
+    let mut embed = Embed::default().title(&interaction_role.name);
+    if let Some(description) = interaction_role.description.as_ref() {
+        embed = embed.description(description);
+    }
+    if interaction_role.exclusive {
+        embed = embed.footer("Only one role can be picked from this menu");
+    }
+    embed
+}
+
+async fn interaction_role_components(
+    data: &BotData,
+    interaction_role: &InteractionRole,
+) -> Result<CreateComponents, Error> {
+    ensure!(
+        interaction_role.choices.len() > 0,
+        "Role menu \"{}\" has no choices",
+        interaction_role.name
+    );
+    let max_choices = interaction_role_max_choices(interaction_role.style);
+    ensure!(
+        interaction_role.choices.len() <= max_choices,
+        "Role menu \"{}\" has more than {} choices",
+        interaction_role.name,
+        max_choices
+    );
+
+    let mut components = CreateComponents::default();
+
+    if interaction_role.style == InteractionRoleStyle::Dropdown {
+        let pages = interaction_role_pages(interaction_role);
+        for (page, page_choices) in pages.iter().enumerate() {
+            let (min, max) = interaction_role_value_limits(interaction_role, page);
+            let mut options = Vec::new();
+            for choice in page_choices.iter() {
+                let mut option = CreateSelectMenuOption::new(&choice.choice, choice.role_id.0);
+                if let Some(emoji) = choice.emoji.as_ref() {
+                    option.emoji(parse_emoji(emoji)?);
+                }
+                options.push(option);
+            }
+
+            let custom_id = register_custom_id(
+                data,
+                interaction_role.guild_id,
+                InteractionKind::RoleSelect,
+                interaction_role.name.clone(),
+                select_page_payload(page),
+            )
+            .await?;
+
+            let mut placeholder = if max > 1 { "Pick your roles" } else { "Pick a role" }.to_string();
+            if pages.len() > 1 {
+                placeholder.push_str(&format!(" ({} of {})", page + 1, pages.len()));
+            }
+
+            let mut menu = CreateSelectMenu::default();
+            menu.custom_id(custom_id)
+                .placeholder(placeholder)
+                .min_values(min as u64)
+                .max_values(max as u64)
+                .options(|o| o.set_options(options));
+
+            let mut row = CreateActionRow::default();
+            row.add_select_menu(menu);
+            components.add_action_row(row);
+        }
+
+        return Ok(components);
+    }
+
+    for row_choices in interaction_role
+        .choices
+        .chunks(INTERACTION_ROLE_BUTTONS_PER_ROW)
+    {
+        let mut row = CreateActionRow::default();
+        for choice in row_choices {
+            let custom_id = register_custom_id(
+                data,
+                interaction_role.guild_id,
+                InteractionKind::RoleButton,
+                interaction_role.name.clone(),
+                choice.choice.clone(),
+            )
+            .await?;
+
+            let mut button = CreateButton::default();
+            button
+                .custom_id(custom_id)
+                .label(&choice.choice)
+                .style(ButtonStyle::Secondary);
+            if let Some(emoji) = choice.emoji.as_ref() {
+                button.emoji(parse_emoji(emoji)?);
+            }
+            row.add_button(button);
+        }
+        components.add_action_row(row);
+    }
+
+    Ok(components)
+}
+
+/// Renders the interaction role message. If `message_id` is set that message is
+/// edited in place, otherwise (or if the edit fails because the message has gone)
+/// a new message is posted. Either way the message_id and channel are stored as the
+/// caller may have pointed `message_id` at a message the set isn't saved with yet
+pub async fn publish_interaction_role<T>(
+    ctx: &T,
+    data: &BotData,
+    interaction_role: &InteractionRole,
+) -> Result<MessageId, Error>
+where
+    T: AsRef<Http>,
+{
+    let channel_id = interaction_role.channel_id;
+
+    let mut edited = None;
+    if let Some(message_id) = interaction_role.message_id {
+        let embed = interaction_role_embed(interaction_role);
+        let components = interaction_role_components(data, interaction_role).await?;
+        match channel_id
+            .edit_message(ctx, *message_id, |m| {
+                m.embed(|b| embed.create_embed(b))
+                    .set_components(components)
+            })
+            .await
+        {
+            Ok(_) => edited = Some(message_id),
+            Err(e) => warn!(
+                "Failed to edit role menu \"{}\" message {:?}, posting a new one: {:?}",
+                interaction_role.name, message_id, e
+            ),
+        }
+    }
+
+    let message_id = match edited {
+        Some(message_id) => message_id,
+        None => {
+            let embed = interaction_role_embed(interaction_role);
+            let components = interaction_role_components(data, interaction_role).await?;
+            let message = channel_id
+                .send_message(ctx, |m| {
+                    m.embed(|b| embed.create_embed(b))
+                        .set_components(components)
+                })
+                .await
+                .context("Posting role menu")?;
+            MessageId::from(message.id)
+        }
+    };
+
+    data.update_interaction_role(
+        interaction_role.guild_id,
+        interaction_role.name.clone(),
+        interaction_role.description.clone(),
+        channel_id,
+        Some(message_id),
+        interaction_role.exclusive,
+        // The caller has likely just updated the set using the command timestamp
+        Timestamp::now(),
+    )
+    .await?;
+
+    Ok(message_id)
+}
+
+/// Re-renders the menu in place if it has been published. Returns the message_id
+/// if it was re-rendered
+pub async fn rerender_interaction_role<T>(
+    ctx: &T,
+    data: &BotData,
+    guild_id: GuildId,
+    name: String,
+) -> Result<Option<MessageId>, Error>
+where
+    T: AsRef<Http>,
+{
+    let interaction_role = data
+        .get_interaction_role(guild_id, name.clone())
+        .await?
+        .ok_or(anyhow::anyhow!("Role menu \"{}\" doesn't exist", name))?;
+
+    if interaction_role.message_id.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        publish_interaction_role(ctx, data, &interaction_role).await?,
+    ))
+}
+
+/// True if the error means the message (or its channel) has been deleted, as opposed to
+/// a rate limit, outage or permission problem that re-posting wouldn't fix
+fn is_missing_message_error(e: &serenity::Error) -> bool {
+    match e {
+        serenity::Error::Http(e) => match &**e {
+            HttpError::UnsuccessfulRequest(response) => {
+                response.status_code.as_u16() == 404
+                    || response.error.code == DISCORD_UNKNOWN_CHANNEL
+                    || response.error.code == DISCORD_UNKNOWN_MESSAGE
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Splits the problems found by a check into the ones that weren't in the last report
+/// and the ones from the last report that have gone away
+pub fn diff_role_menu_problems(
+    reported: &HashSet<String>,
+    problems: &[String],
+) -> (Vec<String>, Vec<String>) {
+    let new = problems
+        .iter()
+        .filter(|p| !reported.contains(*p))
+        .cloned()
+        .collect();
+    let mut resolved = reported
+        .iter()
+        .filter(|p| !problems.contains(p))
+        .cloned()
+        .collect::<Vec<_>>();
+    resolved.sort();
+    (new, resolved)
+}
+
+/// Checks every role menu in the guild still works: the published message exists, the
+/// roles exist and the bot is able to assign them. Returns a description of each problem
+/// found. If `ConfigKey::RoleMenuAutoRepublish` is set, menus whose message has been
+/// deleted are re-posted
+pub async fn check_interaction_roles<T>(
+    ctx: &T,
+    data: &BotData,
+    guild_id: GuildId,
+) -> Result<Vec<String>, Error>
+where
+    T: CacheHttp + AsRef<Http>,
+{
+    let menus = data.get_interaction_roles(guild_id).await?;
+    if menus.len() == 0 {
+        return Ok(Vec::new());
+    }
+
+    let auto_republish =
+        get_config_bool_option!(data, guild_id, ConfigKey::RoleMenuAutoRepublish).unwrap_or(false);
+
+    let roles = guild_id
+        .roles(ctx.http())
+        .await
+        .context("Failed to lookup guild roles")?;
+
+    let bot_id = match ctx.cache() {
+        Some(cache) => cache.current_user_id(),
+        None => ctx.http().get_current_user().await?.id,
+    };
+    let bot_member = guild_id
+        .member(ctx, bot_id)
+        .await
+        .context("Failed to lookup bot member")?;
+
+    // @everyone has the same id as the guild
+    let everyone = serenity::RoleId(guild_id.0 .0);
+    let bot_roles = bot_member
+        .roles
+        .iter()
+        .chain(std::iter::once(&everyone))
+        .filter_map(|r| roles.get(r))
+        .collect::<Vec<_>>();
+    let bot_top_position = bot_roles.iter().map(|r| r.position).max().unwrap_or(0);
+    let can_manage_roles = bot_roles
+        .iter()
+        .any(|r| r.permissions.administrator() || r.permissions.manage_roles());
+
+    let mut problems = Vec::new();
+    if !can_manage_roles {
+        problems.push("I don't have the Manage Roles permission so role menus won't work".to_string());
+    }
+
+    for mut menu in menus {
+        if let Some(message_id) = menu.message_id {
+            match menu.channel_id.message(ctx.http(), *message_id).await {
+                Ok(_) => {}
+                Err(e) if !is_missing_message_error(&e) => {
+                    warn!("Failed to fetch role menu message for \"{}\": {:?}", menu.name, e);
+                    problems.push(format!(
+                        "\"{}\": couldn't check the message in <#{}>, see the bot log for details",
+                        menu.name, menu.channel_id.0
+                    ));
+                }
+                Err(e) if auto_republish => {
+                    debug!("Role menu message for \"{}\" is missing: {:?}", menu.name, e);
+                    menu.message_id = None;
+                    match publish_interaction_role(ctx.http(), data, &menu).await {
+                        Ok(message_id) => problems.push(format!(
+                            "\"{}\": message was missing, re-posted it: https://discord.com/channels/{}/{}/{}",
+                            menu.name, guild_id.0, menu.channel_id.0, message_id.0
+                        )),
+                        Err(e) => problems.push(format!(
+                            "\"{}\": message is missing and re-posting failed: {}",
+                            menu.name, e
+                        )),
+                    }
+                }
+                Err(e) => {
+                    debug!("Role menu message for \"{}\" is missing: {:?}", menu.name, e);
+                    problems.push(format!(
+                        "\"{}\": message in <#{}> is missing. Use `/rolemenu publish` to re-post it",
+                        menu.name, menu.channel_id.0
+                    ));
+                }
+            }
+        }
+
+        for choice in menu.choices.iter() {
+            match roles.get(&*choice.role_id) {
+                None => problems.push(format!(
+                    "\"{}\": role for \"{}\" ({}) no longer exists",
+                    menu.name, choice.choice, choice.role_id.0
+                )),
+                Some(role) if role.position >= bot_top_position => problems.push(format!(
+                    "\"{}\": <@&{}> is above my highest role so I can't assign it",
+                    menu.name, choice.role_id.0
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+
+    Ok(problems)
+}
@@ -1,4 +1,7 @@
-use poise::serenity_prelude::{Cache, CacheHttp, Http};
+use std::fmt::Write;
+
+use poise::serenity_prelude::{Cache, CacheHttp, Http, Message};
+use tracing::debug;
 
 use crate::{
     db::queries::config::{LogChannel},
@@ -37,3 +40,43 @@ T: 'a + Clone + CacheHttp + AsRef<Cache> + AsRef<Http>,
         Ok(OptionallyConfiguredResult::Unconfigured(first_config_key))
     }
 }
+
+/// Renders the content, embeds and attachments of the message as markdown. Returns
+/// None if there's nothing to show
+pub fn message_to_string(message: &Message) -> Result<Option<String>, Error> {
+    let mut content = message.content.clone();
+
+    for e in message.embeds.iter() {
+        if content.len() > 0 {
+            content.push('\n');
+        }
+        content.push_str("**Embed**\n");
+        if let Some(v) = e.title.as_ref() {
+            write!(&mut content, "*{v}*\n")?;
+        }
+        if let Some(v) = e.description.as_ref() {
+            write!(&mut content, "{v}")?;
+        }
+    }
+
+    for a in message.attachments.iter() {
+        if content.len() > 0 {
+            content.push('\n');
+        }
+        content.push_str("**Attachment**\n");
+        write!(&mut content, "*{}*\n", a.filename)?;
+        if let Some(v) = a.content_type.as_ref() {
+            write!(&mut content, "{v}\n")?;
+        }
+        write!(&mut content, "{}", a.url)?;
+    }
+
+    let content = if content.len() > 0 {
+        Some(content)
+    } else {
+        debug!("message_to_string empty message: {:#?}", message);
+        None
+    };
+
+    Ok(content)
+}
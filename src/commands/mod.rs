@@ -2,6 +2,15 @@ pub mod promote;
 pub mod add_member;
 pub mod greet;
 pub mod log;
+pub mod custom_ids;
+pub mod confirmation;
+pub mod purge;
+pub mod interaction_roles;
+pub mod reaction_roles;
+pub mod user_data;
+pub mod transcript;
+pub mod search;
+pub mod shutdown;
 
 #[macro_export]
 macro_rules! get_config_string_option {
@@ -142,4 +151,19 @@ macro_rules! get_config_u64 {
         }    
         value.unwrap()
     }};
-}
\ No newline at end of file
+}
+#[macro_export]
+macro_rules! get_config_bool_option {
+    ($data:expr, $guild_id:expr, $key:expr) => {{
+        use crate::ErrorContext as _;
+        use std::str::FromStr;
+        let string = crate::get_config_string_option!($data, $guild_id, $key);
+        if let Some(string) = string {
+            Some(bool::from_str(&string).with_context(|| {
+                format!("Failed to parse {} ({}) as true or false", $key, string)
+            })?)
+        } else {
+            None
+        }
+    }};
+}
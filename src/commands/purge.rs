@@ -0,0 +1,120 @@
+use chrono::Utc;
+use futures::StreamExt;
+use poise::serenity_prelude::{
+    Cache, CacheHttp, ChannelId, Http, MessageId, MessagesIter, Timestamp, UserId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::promote::OptionallyConfiguredResult, db::queries::message_log::LogType, BotData,
+    Error,
+};
+
+/// Everything needed to run a purge once it has been confirmed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessagePurgeRequest {
+    pub channel_id: ChannelId,
+    /// Only messages after this one are deleted
+    pub after_id: MessageId,
+    /// Only delete messages by this user
+    pub filter_user_id: Option<UserId>,
+    pub limit: u64,
+    /// Messages created after this (i.e. after the command was issued) aren't deleted
+    pub until_timestamp: Timestamp,
+}
+
+pub async fn run_purge<'a, Ctx>(
+    ctx: &'a Ctx,
+    (request, data): (MessagePurgeRequest, &BotData),
+    progress_chan: flume::Sender<String>,
+) -> Result<OptionallyConfiguredResult<()>, Error>
+where
+    Ctx: 'a + CacheHttp + AsRef<Http> + AsRef<Cache>,
+{
+    let MessagePurgeRequest {
+        channel_id,
+        after_id,
+        filter_user_id,
+        mut limit,
+        until_timestamp,
+    } = request;
+
+    let mut batch = Vec::new();
+
+    async fn delete_batch<'a, Ctx: 'a + CacheHttp + AsRef<Http>>(
+        ctx: Ctx,
+        data: &BotData,
+        channel_id: &ChannelId,
+        batch: &mut Vec<MessageId>,
+        progress_chan: &flume::Sender<String>,
+    ) -> Result<(), Error> {
+        if batch.len() > 0 {
+            progress_chan
+                .send_async(format!("Deleting {} messages", batch.len()))
+                .await?;
+            let now = Utc::now().into();
+            // TODO: there should be a transaction around this so an error from discord
+            // reverts it
+            for id in batch.iter() {
+                data.log_message(
+                    id.into(),
+                    now,
+                    LogType::Purge,
+                    None,
+                )
+                .await?;
+            }
+            channel_id.delete_messages(ctx, batch.drain(..)).await?;
+        }
+        Ok(())
+    }
+
+    progress_chan
+        .send_async("Fetching messages".to_string())
+        .await?;
+    let mut messages = MessagesIter::<Http>::stream(ctx, channel_id).boxed();
+    while let Some(r) = messages.next().await {
+        let message = r?;
+        if message.id <= after_id {
+            // They are ordered newest to oldest so once this check is hit, there won't be
+            // any more valid messages coming
+            break;
+        }
+        if message.timestamp > until_timestamp {
+            // This skips any newer than the command so we need to continue
+            continue;
+        }
+        if let Some(user_id) = filter_user_id.as_ref() {
+            if user_id != &message.author.id {
+                continue;
+            }
+        }
+
+        batch.push(message.id);
+        limit -= 1;
+        if limit == 0 {
+            break;
+        }
+        if batch.len() == 100 {
+            delete_batch(
+                ctx,
+                data,
+                &channel_id,
+                &mut batch,
+                &progress_chan,
+            )
+            .await?;
+        }
+    }
+    delete_batch(
+        ctx,
+        data,
+        &channel_id,
+        &mut batch,
+        &progress_chan,
+    )
+    .await?;
+    progress_chan.send_async("Done".to_string()).await?;
+
+    Ok(OptionallyConfiguredResult::Ok(()))
+}
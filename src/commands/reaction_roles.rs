@@ -0,0 +1,442 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+};
+
+use poise::{
+    serenity_prelude::{self as serenity, CacheHttp, Http, Reaction, ReactionType, Role, Timestamp},
+    ChoiceParameter,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    commands::interaction_roles::{
+        check_role_menu_role, parse_emoji, publish_interaction_role,
+        validate_interaction_role_name, RoleAssigner, INTERACTION_ROLE_MAX_BUTTONS,
+    },
+    db::queries::reaction_roles::ReactionRoleTemp,
+    BotData, ChannelId, Error, ErrorContext, GuildId, MessageId, RoleId,
+};
+
+/// Discord limits button labels to 80 characters
+const BUTTON_LABEL_MAX_LEN: usize = 80;
+
+/// What to do with the original reaction role message when converting it
+#[derive(Debug, Clone, Copy, PartialEq, ChoiceParameter, clap::ValueEnum)]
+pub enum ConvertMode {
+    /// Only create the role menu in the database, leave it unpublished. The original
+    /// keeps working until the menu is published
+    #[name = "Database only"]
+    DbOnly,
+    /// Post a new role menu message, leaving the original working until the menu
+    /// is published again
+    #[name = "Repost"]
+    Repost,
+    /// Edit the original message to have buttons if possible, otherwise post a
+    /// new one. The old reactions are cleared either way
+    #[name = "Replace"]
+    Replace,
+}
+
+#[derive(Debug)]
+pub enum ConvertOutcome {
+    /// The set was converted. `missing_roles` lists the choices that were
+    /// dropped because the role no longer exists
+    Converted {
+        message_id: Option<MessageId>,
+        missing_roles: Vec<RoleId>,
+    },
+    MissingChannel(ChannelId),
+    MissingRoles(Vec<RoleId>),
+    AlreadyExists,
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct ConvertReport {
+    pub guild_id: GuildId,
+    pub name: String,
+    pub outcome: ConvertOutcome,
+}
+
+impl Display for ConvertReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn mentions(roles: &[RoleId]) -> String {
+            roles
+                .iter()
+                .map(|v| format!("<@&{}>", v.0))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+
+        write!(f, "\"{}\": ", self.name)?;
+        match &self.outcome {
+            ConvertOutcome::Converted {
+                message_id,
+                missing_roles,
+            } => {
+                if message_id.is_some() {
+                    write!(f, "converted and published")?;
+                } else {
+                    write!(f, "converted (not published)")?;
+                }
+                if missing_roles.len() > 0 {
+                    write!(
+                        f,
+                        ", dropped choices for deleted roles {}",
+                        mentions(missing_roles)
+                    )?;
+                }
+                Ok(())
+            }
+            ConvertOutcome::MissingChannel(channel_id) => {
+                write!(f, "channel {} no longer exists", channel_id.0)
+            }
+            ConvertOutcome::MissingRoles(roles) => {
+                write!(f, "none of the roles exist any more ({})", mentions(roles))
+            }
+            ConvertOutcome::AlreadyExists => {
+                write!(f, "a role menu with that name already exists")
+            }
+            ConvertOutcome::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// Converts the emoji that was reacted with into something `parse_emoji` accepts.
+/// discord.js stored custom emoji as just the id
+fn legacy_emoji(choice: &str) -> Option<String> {
+    if parse_emoji(choice).is_ok() {
+        Some(choice.to_string())
+    } else if let Ok(id) = choice.parse::<u64>() {
+        Some(format!("<:emoji:{}>", id))
+    } else {
+        None
+    }
+}
+
+/// Checks if the reaction emoji is the one stored for a legacy choice
+fn legacy_emoji_matches(choice: &str, emoji: &ReactionType) -> bool {
+    match emoji {
+        // Some clients send the emoji with a trailing variation selector and some without
+        ReactionType::Unicode(s) => s.trim_end_matches('\u{fe0f}') == choice.trim_end_matches('\u{fe0f}'),
+        ReactionType::Custom { id, .. } => match legacy_emoji(choice).map(|v| parse_emoji(&v)) {
+            Some(Ok(ReactionType::Custom { id: choice_id, .. })) => choice_id == *id,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn truncate_label(label: &str) -> String {
+    label.chars().take(BUTTON_LABEL_MAX_LEN).collect()
+}
+
+/// Adds or removes the role for a reaction on one of the legacy reaction role messages.
+/// Reactions on any other message are ignored
+pub async fn handle_legacy_reaction<T>(
+    ctx: &T,
+    data: &BotData,
+    reaction: &Reaction,
+    added: bool,
+) -> Result<(), Error>
+where
+    T: CacheHttp + AsRef<Http>,
+{
+    let (guild_id, user_id) = match (reaction.guild_id, reaction.user_id) {
+        (Some(guild_id), Some(user_id)) => (guild_id, user_id),
+        _ => return Ok(()),
+    };
+
+    // Filter out bots early if we can, removals don't include the member
+    if reaction
+        .member
+        .as_ref()
+        .and_then(|m| m.user.as_ref())
+        .map(|u| u.bot)
+        .unwrap_or(false)
+    {
+        return Ok(());
+    }
+
+    let set = match data
+        .get_reaction_role_temp_by_message(
+            guild_id.into(),
+            reaction.channel_id.into(),
+            reaction.message_id.into(),
+        )
+        .await?
+    {
+        Some(set) => set,
+        None => return Ok(()),
+    };
+
+    let choice = match set
+        .choices
+        .iter()
+        .find(|c| legacy_emoji_matches(&c.choice, &reaction.emoji))
+    {
+        Some(choice) => choice,
+        None => {
+            debug!(
+                "Reaction {} on legacy reaction role set \"{}\" doesn't match any choice",
+                reaction.emoji, set.name
+            );
+            return Ok(());
+        }
+    };
+
+    let mut member = guild_id
+        .member(ctx, user_id)
+        .await
+        .context("Failed to lookup member for legacy reaction role")?;
+    if member.user.bot {
+        return Ok(());
+    }
+
+    let has_role = member.roles.contains(&*choice.role_id);
+    if added && !has_role {
+        if set.exclusive {
+            let others = set
+                .choices
+                .iter()
+                .filter(|c| c.role_id != choice.role_id && member.roles.contains(&*c.role_id))
+                .collect::<Vec<_>>();
+
+            if others.len() > 0 {
+                debug!("Removing exclusive legacy reaction roles {:?} from {}", others, member);
+                member
+                    .remove_roles(ctx, &others.iter().map(|c| *c.role_id).collect::<Vec<_>>())
+                    .await
+                    .context("Removing exclusive legacy reaction roles")?;
+
+                // Clear their other reactions so the message reflects the roles they hold
+                for other in others {
+                    if let Some(Ok(emoji)) = legacy_emoji(&other.choice).map(|v| parse_emoji(&v)) {
+                        if let Err(e) = reaction
+                            .channel_id
+                            .delete_reaction(ctx, reaction.message_id, Some(user_id), emoji)
+                            .await
+                        {
+                            warn!("Failed to remove exclusive legacy reaction: {:?}", e);
+                        }
+                    }
+                }
+            }
+        }
+
+        debug!("Adding legacy reaction role {:?} to {}", choice.role_id, member);
+        member
+            .add_role(ctx, *choice.role_id)
+            .await
+            .context("Adding legacy reaction role")?;
+    } else if !added && has_role {
+        debug!("Removing legacy reaction role {:?} from {}", choice.role_id, member);
+        member
+            .remove_role(ctx, *choice.role_id)
+            .await
+            .context("Removing legacy reaction role")?;
+    }
+
+    Ok(())
+}
+
+/// Converts each of the legacy reaction role sets for the guild into an interaction role.
+/// Sets converted with `ConvertMode::Replace` are removed from the temp tables straight
+/// away. With the other modes the original message keeps handing out roles until the
+/// menu is published (see `retire_legacy_set`). Sets that can't be converted are
+/// left in place so they can be fixed up and retried. Sets with a role the assigner
+/// couldn't put on a role menu themselves (see `check_role_menu_role`) fail
+pub async fn convert_reaction_roles<T>(
+    ctx: &T,
+    data: &BotData,
+    guild_id: GuildId,
+    mode: ConvertMode,
+    assigner: RoleAssigner<'_>,
+) -> Result<Vec<ConvertReport>, Error>
+where
+    T: AsRef<Http>,
+{
+    let sets = data.get_reaction_role_temps(Some(guild_id)).await?;
+    if sets.len() == 0 {
+        return Ok(Vec::new());
+    }
+
+    let roles = guild_id
+        .roles(ctx)
+        .await
+        .context("Failed to lookup guild roles")?;
+    let channels = guild_id
+        .channels(ctx)
+        .await
+        .context("Failed to lookup guild channels")?;
+
+    let mut reports = Vec::new();
+    for set in sets {
+        let name = set.name.clone();
+        let outcome = if !channels.contains_key(&*set.channel_id) {
+            ConvertOutcome::MissingChannel(set.channel_id)
+        } else {
+            match convert_set(ctx, data, &set, &roles, mode, assigner).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    warn!("Error converting reaction role set \"{}\": {:?}", name, e);
+                    ConvertOutcome::Failed(e.to_string())
+                }
+            }
+        };
+
+        if let ConvertOutcome::Converted { .. } = outcome {
+            if mode == ConvertMode::Replace {
+                data.delete_reaction_role_temp(guild_id, name.clone()).await?;
+            }
+        }
+
+        info!("Reaction role conversion for {:?} \"{}\": {:?}", guild_id, name, outcome);
+        reports.push(ConvertReport {
+            guild_id,
+            name,
+            outcome,
+        });
+    }
+
+    Ok(reports)
+}
+
+/// Retires the legacy reaction role set converted into the role menu `name`, if there
+/// is one. Called once the menu has been published so members have somewhere else to
+/// get the roles: the reactions on the original message are cleared and the set is
+/// removed from the temp tables
+pub async fn retire_legacy_set<T>(
+    ctx: &T,
+    data: &BotData,
+    guild_id: GuildId,
+    name: &str,
+) -> Result<bool, Error>
+where
+    T: AsRef<Http>,
+{
+    let set = match data
+        .get_reaction_role_temps(Some(guild_id))
+        .await?
+        .into_iter()
+        .find(|s| s.name == name)
+    {
+        Some(set) => set,
+        None => return Ok(false),
+    };
+
+    debug!("Retiring legacy reaction role message {:?}", set.message_id);
+    if let Err(e) = set
+        .channel_id
+        .delete_reactions(ctx, *set.message_id)
+        .await
+    {
+        warn!("Failed to clear reactions on legacy reaction role message: {:?}", e);
+    }
+
+    data.delete_reaction_role_temp(guild_id, set.name).await
+}
+
+async fn convert_set<T>(
+    ctx: &T,
+    data: &BotData,
+    set: &ReactionRoleTemp,
+    roles: &HashMap<serenity::RoleId, Role>,
+    mode: ConvertMode,
+    assigner: RoleAssigner<'_>,
+) -> Result<ConvertOutcome, Error>
+where
+    T: AsRef<Http>,
+{
+    validate_interaction_role_name(&set.name)?;
+
+    if data
+        .get_interaction_role(set.guild_id, set.name.clone())
+        .await?
+        .is_some()
+    {
+        return Ok(ConvertOutcome::AlreadyExists);
+    }
+
+    let mut missing_roles = Vec::new();
+    let mut choices = Vec::new();
+    for choice in set.choices.iter() {
+        if let Some(role) = roles.get(&*choice.role_id) {
+            check_role_menu_role(set.guild_id, roles, assigner, choice.role_id)?;
+            let mut label = truncate_label(&role.name);
+            if choices.iter().any(|(l, _, _)| l == &label) {
+                label = truncate_label(&format!("{} ({})", role.name, choice.choice));
+            }
+            choices.push((label, legacy_emoji(&choice.choice), choice.role_id));
+        } else {
+            missing_roles.push(choice.role_id);
+        }
+    }
+
+    if choices.len() == 0 {
+        return Ok(ConvertOutcome::MissingRoles(missing_roles));
+    }
+    if choices.len() > INTERACTION_ROLE_MAX_BUTTONS {
+        Err(anyhow::anyhow!(
+            "Set has {} choices, button role menus can have at most {}",
+            choices.len(),
+            INTERACTION_ROLE_MAX_BUTTONS
+        ))?;
+    }
+
+    let timestamp = Timestamp::now();
+    data.update_interaction_role(
+        set.guild_id,
+        set.name.clone(),
+        None,
+        set.channel_id,
+        None,
+        set.exclusive,
+        timestamp,
+    )
+    .await?;
+
+    for (label, emoji, role_id) in choices {
+        data.update_interaction_choice(
+            set.guild_id,
+            set.name.clone(),
+            label,
+            emoji,
+            role_id,
+            timestamp,
+        )
+        .await?;
+    }
+
+    let message_id = if mode == ConvertMode::DbOnly {
+        None
+    } else {
+        let mut ir = data
+            .get_interaction_role(set.guild_id, set.name.clone())
+            .await?
+            .ok_or(anyhow::anyhow!("Interaction role \"{}\" missing after conversion", set.name))?;
+
+        if mode == ConvertMode::Replace {
+            // Editing in place only works if the original was posted by the bot, publish
+            // falls back to posting a new message if it wasn't
+            ir.message_id = Some(set.message_id);
+
+            debug!("Clearing reactions on {:?}", set.message_id);
+            if let Err(e) = set
+                .channel_id
+                .delete_reactions(ctx, *set.message_id)
+                .await
+            {
+                warn!("Failed to clear reactions on legacy reaction role message: {:?}", e);
+            }
+        }
+
+        Some(publish_interaction_role(ctx, data, &ir).await?)
+    };
+
+    Ok(ConvertOutcome::Converted {
+        message_id,
+        missing_roles,
+    })
+}
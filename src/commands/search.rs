@@ -0,0 +1,261 @@
+use std::{collections::HashSet, fmt::Write, mem};
+
+use chrono::NaiveDate;
+use poise::serenity_prelude::{
+    self as serenity, ButtonStyle, Channel, ChannelType, CreateComponents, Guild,
+    InteractionResponseType, Member, MessageComponentInteraction, Permissions,
+};
+use tracing::debug;
+
+use crate::{
+    commands::custom_ids::{register_custom_id_with_extra, require_component_permission},
+    db::queries::{
+        custom_ids::InteractionKind,
+        message_log::{MessageSearchQuery, MessageSearchResult},
+        permissions::Permission,
+    },
+    BotData, ChannelId, Embed, Error, GuildId, MessageId,
+};
+
+const SEARCH_PAGE_SIZE: u64 = 10;
+/// How much of each message is shown in the results
+const SEARCH_CONTENT_PREVIEW_LEN: usize = 200;
+
+/// The smallest message id that could have been sent at the start of the date
+pub fn date_to_message_id(date: &str) -> Result<MessageId, Error> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")?;
+    let ms = date
+        .and_hms_opt(0, 0, 0)
+        .ok_or(anyhow::anyhow!("Invalid time"))?
+        .timestamp_millis();
+    Ok(MessageId::from_unix_millis(ms))
+}
+
+/// The smallest message id that could have been sent the day after the date. It's the
+/// end of ranges that include the whole of the date, e.g. transcripts, which stop before it
+pub fn date_end_to_message_id(date: &str) -> Result<MessageId, Error> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")?
+        .succ_opt()
+        .ok_or(anyhow::anyhow!("Invalid date"))?;
+    date_to_message_id(&date.format("%Y-%m-%d").to_string())
+}
+
+/// The channels, and their cached threads, that the member can see and read the
+/// history of. Anything read out of the message log for them is limited to these
+pub fn readable_channels(guild: &Guild, member: &Member) -> Vec<ChannelId> {
+    let permissions_in = |channel| {
+        guild
+            .user_permissions_in(channel, member)
+            .unwrap_or_else(|_| Permissions::empty())
+    };
+    let needed = Permissions::VIEW_CHANNEL | Permissions::READ_MESSAGE_HISTORY;
+
+    let mut readable = HashSet::new();
+    let mut can_manage_threads = HashSet::new();
+    for channel in guild.channels.values() {
+        if let Channel::Guild(channel) = channel {
+            let permissions = permissions_in(channel);
+            if permissions.contains(needed) {
+                readable.insert(channel.id);
+                if permissions.contains(Permissions::MANAGE_THREADS) {
+                    can_manage_threads.insert(channel.id);
+                }
+            }
+        }
+    }
+
+    // Threads don't have overwrites of their own, they go by the channel they're in.
+    // Only people who can manage threads get to read the private ones they're not in,
+    // membership isn't cached so they're left out for everyone else
+    let threads = guild
+        .threads
+        .iter()
+        .filter(|t| match t.parent_id {
+            Some(parent_id) if t.kind == ChannelType::PrivateThread => {
+                can_manage_threads.contains(&parent_id)
+            }
+            Some(parent_id) => readable.contains(&parent_id),
+            None => false,
+        })
+        .map(|t| t.id)
+        .collect::<Vec<_>>();
+
+    readable
+        .into_iter()
+        .chain(threads)
+        .map(ChannelId::from)
+        .collect()
+}
+
+/// One page of search results ready to be sent
+pub struct SearchPage {
+    pub embed: Embed,
+    /// The custom_ids of the previous and next buttons. None if there's only one page
+    pub buttons: Option<(String, String)>,
+    pub page: u64,
+    pub pages: u64,
+}
+
+fn results_embed(
+    guild_id: GuildId,
+    results: &[MessageSearchResult],
+    total: u64,
+    page: u64,
+    pages: u64,
+) -> Result<Embed, Error> {
+    if results.len() == 0 {
+        return Ok(Embed::default().description("No messages found"));
+    }
+
+    let mut description = String::new();
+    for r in results.iter() {
+        let mut content = r
+            .content
+            .chars()
+            .take(SEARCH_CONTENT_PREVIEW_LEN)
+            .collect::<String>()
+            .replace('\n', " ");
+        if content.len() < r.content.len() {
+            content.push_str("...");
+        }
+        write!(
+            &mut description,
+            "<t:{}:f> <@{}> in <#{}> [Jump](https://discord.com/channels/{}/{}/{})\n> {}\n",
+            r.message_id.created_at().unix_timestamp(),
+            r.user_id.0,
+            r.channel_id.0,
+            guild_id.0,
+            r.channel_id.0,
+            r.message_id.0,
+            content,
+        )?;
+    }
+
+    Ok(Embed::default()
+        .title("Message search")
+        .description(description)
+        .footer(format!("Page {} of {} ({} messages)", page + 1, pages, total)))
+}
+
+/// Runs the search and builds the page of results. `search_id` identifies the search
+/// so each one gets its own pagination buttons
+pub async fn render_search_page(
+    data: &BotData,
+    search_id: &str,
+    query: &MessageSearchQuery,
+    page: u64,
+) -> Result<SearchPage, Error> {
+    let (total, results) = data
+        .search_messages(query.clone(), SEARCH_PAGE_SIZE, page * SEARCH_PAGE_SIZE)
+        .await?;
+    let pages = (total + SEARCH_PAGE_SIZE - 1) / SEARCH_PAGE_SIZE;
+    let embed = results_embed(query.guild_id, &results, total, page, pages)?;
+
+    let buttons = if pages > 1 {
+        let register = |target: u64| {
+            register_custom_id_with_extra(
+                data,
+                query.guild_id,
+                InteractionKind::SearchPage,
+                search_id.to_string(),
+                target.to_string(),
+                Some(query.to_json()),
+            )
+        };
+        let prev_id = register(page.saturating_sub(1)).await?;
+        let next_id = register((page + 1).min(pages - 1)).await?;
+        Some((prev_id, next_id))
+    } else {
+        None
+    };
+
+    Ok(SearchPage { embed, buttons, page, pages })
+}
+
+/// Adds the previous and next buttons for the page, if it has them
+pub fn search_page_components<'a>(
+    c: &'a mut CreateComponents,
+    page: &SearchPage,
+) -> &'a mut CreateComponents {
+    if let Some((prev_id, next_id)) = page.buttons.as_ref() {
+        c.create_action_row(|r| r
+            .create_button(|b| b
+                .custom_id(prev_id)
+                .label("Previous")
+                .style(ButtonStyle::Secondary)
+                .disabled(page.page == 0)
+            )
+            .create_button(|b| b
+                .custom_id(next_id)
+                .label("Next")
+                .style(ButtonStyle::Secondary)
+                .disabled(page.page + 1 >= page.pages)
+            )
+        );
+    }
+    c
+}
+
+/// Handles a click on one of the buttons added by `search_page_components`. They're
+/// looked up in the custom_id registry so they keep working after a restart
+pub async fn handle_search_page(
+    ctx: &serenity::Context,
+    data: &BotData,
+    message_component: &MessageComponentInteraction,
+    search_id: String,
+    query: MessageSearchQuery,
+    page: u64,
+) -> Result<(), Error> {
+    if let Err(e) = require_component_permission(
+        ctx,
+        data,
+        message_component,
+        query.guild_id,
+        Permission::MessageSearch,
+    )
+    .await
+    {
+        debug!("Refusing search page {}: {:?}", search_id, e);
+        message_component
+            .create_interaction_response(&ctx.http, |b| {
+                b.interaction_response_data(|b| {
+                    b.ephemeral(true).embed(|b| {
+                        Embed::error()
+                            .description(format!("You can't use this search: {}", e))
+                            .create_embed(b)
+                    })
+                })
+            })
+            .await?;
+        return Ok(());
+    }
+
+    // Whoever clicked may not be able to read everything the person who ran the search could
+    let guild = ctx
+        .cache
+        .guild(*query.guild_id)
+        .ok_or(anyhow::anyhow!("Guild {} isn't cached", query.guild_id.0))?;
+    let member = message_component
+        .member
+        .as_ref()
+        .ok_or(anyhow::anyhow!("Component interaction missing member"))?;
+    let query = MessageSearchQuery {
+        channel_ids: Some(readable_channels(&guild, member)),
+        ..query
+    };
+
+    let mut page = render_search_page(data, &search_id, &query, page).await?;
+    let embed = mem::take(&mut page.embed);
+
+    message_component
+        .create_interaction_response(&ctx.http, |b| {
+            b.kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|d| {
+                    d.embed(|b| embed.create_embed(b))
+                        .components(|c| search_page_components(c, &page))
+                })
+        })
+        .await?;
+
+    Ok(())
+}
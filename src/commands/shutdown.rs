@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::future::join_all;
+use poise::serenity_prelude::Context;
+use tokio::time::timeout;
+use tracing::{error, warn};
+
+use crate::{BotData, Embed, Error};
+
+/// How long the going offline notes get before shutdown carries on without them
+const ANNOUNCE_OFFLINE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for SIGTERM (container stop) or SIGINT (ctrl+c) and returns the name of the one
+/// that arrived
+#[cfg(unix)]
+pub async fn wait_for_shutdown_signal() -> Result<&'static str, Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    Ok(tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    })
+}
+
+/// Waits for ctrl+c, the only shutdown signal tokio supports everywhere
+#[cfg(not(unix))]
+pub async fn wait_for_shutdown_signal() -> Result<&'static str, Error> {
+    tokio::signal::ctrl_c().await?;
+    Ok("ctrl+c")
+}
+
+/// Posts a "going offline" note to every guild's general log channel. This needs the DB
+/// task to look up the channels so it has to run before it's closed. The notes are sent
+/// together and given up on after `ANNOUNCE_OFFLINE_TIMEOUT` so a slow or rate limited
+/// discord can't hold up the shutdown
+pub async fn announce_offline(data: &BotData, ctx: &Context) {
+    let now = Utc::now().timestamp();
+
+    let announcements = ctx.cache.guilds().into_iter().map(|guild_id| async move {
+        let r = async {
+            let guild = match ctx.cache.guild(guild_id) {
+                Some(guild) => guild,
+                None => {
+                    warn!("Guild {} missing from the cache, not announcing shutdown", guild_id);
+                    return Ok(());
+                }
+            };
+            if let Some(channel_id) = data.general_log_channel_or_default(&guild).await? {
+                Embed::default()
+                    .title("Going offline")
+                    .description(format!("<t:{}>\nVersion: {}", now, env!("CARGO_PKG_VERSION")))
+                    .send_in_channel(channel_id, &ctx.http)
+                    .await?;
+            }
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(e) = r {
+            error!("Error posting going offline note in guild {}: {:?}", guild_id, e);
+        }
+    });
+
+    if timeout(ANNOUNCE_OFFLINE_TIMEOUT, join_all(announcements)).await.is_err() {
+        warn!(
+            "Gave up on the going offline notes after {} s",
+            ANNOUNCE_OFFLINE_TIMEOUT.as_secs()
+        );
+    }
+}
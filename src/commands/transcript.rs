@@ -0,0 +1,290 @@
+use std::fmt::Write;
+
+use poise::{serenity_prelude::Message, ChoiceParameter};
+
+use crate::{
+    db::queries::message_log::{LogType, MessageLog},
+    BotData, ChannelId, Error, GuildId, MessageId,
+};
+
+/// Caps how many messages a single transcript pulls out of the database
+pub const TRANSCRIPT_MAX_MESSAGES: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, ChoiceParameter)]
+pub enum TranscriptFormat {
+    Html,
+    Markdown,
+}
+
+impl TranscriptFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TranscriptFormat::Html => "html",
+            TranscriptFormat::Markdown => "md",
+        }
+    }
+}
+
+/// One message as it was last seen
+#[derive(Debug)]
+pub struct TranscriptEntry {
+    pub message_id: MessageId,
+    /// The latest version of the message
+    pub message: Message,
+    pub edited: bool,
+    /// Set to Delete or Purge if the message was removed
+    pub removed: Option<LogType>,
+}
+
+pub struct Transcript {
+    pub entries: Vec<TranscriptEntry>,
+    /// True if there were more messages than `TRANSCRIPT_MAX_MESSAGES`
+    pub truncated: bool,
+}
+
+/// Rebuilds the conversation in the channel from the message log. It covers messages
+/// from `after_id` up to, but not including, `before_id`
+pub async fn build_transcript(
+    data: &BotData,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    after_id: MessageId,
+    before_id: MessageId,
+) -> Result<Transcript, Error> {
+    // One extra message so it can tell there were more, rather than guessing from a
+    // range that happens to hold exactly the limit
+    let mut log = data
+        .get_channel_message_log(guild_id, channel_id, after_id, before_id, TRANSCRIPT_MAX_MESSAGES + 1)
+        .await?;
+    let mut message_count = 0;
+    let mut last_message_id = None;
+    let mut extra_start = None;
+    for (i, entry) in log.iter().enumerate() {
+        if last_message_id != Some(entry.message_id) {
+            message_count += 1;
+            last_message_id = Some(entry.message_id);
+            if message_count > TRANSCRIPT_MAX_MESSAGES {
+                extra_start = Some(i);
+                break;
+            }
+        }
+    }
+    let truncated = extra_start.is_some();
+    if let Some(extra_start) = extra_start {
+        log.truncate(extra_start);
+    }
+
+    let mut entries: Vec<TranscriptEntry> = Vec::new();
+    let mut pending_removal = None;
+
+    // The log is ordered by message and then by when the entry was logged so each
+    // message's history is contiguous
+    for MessageLog { message_id, type_, message, .. } in log.into_iter() {
+        let current = entries
+            .last_mut()
+            .filter(|e| e.message_id == message_id);
+
+        match type_ {
+            LogType::Create | LogType::Edit => {
+                let message = match message {
+                    Some(message) => message,
+                    None => continue,
+                };
+                if let Some(entry) = current {
+                    entry.edited = true;
+                    entry.message = message;
+                } else {
+                    entries.push(TranscriptEntry {
+                        message_id,
+                        edited: type_ == LogType::Edit,
+                        removed: pending_removal.take(),
+                        message,
+                    });
+                }
+            }
+            LogType::Delete | LogType::Purge => {
+                if let Some(entry) = current {
+                    entry.removed = Some(type_);
+                } else {
+                    // Shouldn't happen as removals are only logged for messages that
+                    // were logged first, but hang on to it in case the order is off
+                    pending_removal = Some(type_);
+                }
+            }
+        }
+    }
+
+    Ok(Transcript { entries, truncated })
+}
+
+fn removed_label(removed: &Option<LogType>) -> Option<&'static str> {
+    match removed {
+        Some(LogType::Purge) => Some("purged"),
+        Some(_) => Some("deleted"),
+        None => None,
+    }
+}
+
+/// Backslash escapes anything markdown would otherwise format, so what people wrote
+/// comes out as they wrote it. Lines are escaped one at a time so list markers at the
+/// start can be caught
+fn escape_markdown(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    // "- item", "+ item" and "1. item" would turn into lists
+    let indent = line.chars().take_while(|c| *c == ' ').count();
+    let digits = line[indent..].chars().take_while(|c| c.is_ascii_digit()).count();
+    let list_marker = match line[indent + digits..].chars().next() {
+        Some('-') | Some('+') if digits == 0 => Some(indent),
+        Some('.') | Some(')') if digits > 0 => Some(indent + digits),
+        _ => None,
+    };
+
+    for (i, c) in line.chars().enumerate() {
+        if Some(i) == list_marker
+            || matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '>' | '#' | '[' | ']' | '(' | ')' | '<')
+        {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+pub fn render_markdown(transcript: &Transcript, title: &str) -> Result<String, Error> {
+    let mut out = String::new();
+    write!(&mut out, "# {}\n\n", escape_markdown(title))?;
+
+    for entry in transcript.entries.iter() {
+        let m = &entry.message;
+        write!(
+            &mut out,
+            "**{}** ({}) - {}",
+            escape_markdown(&m.author.tag()),
+            m.author.id.0,
+            m.timestamp.to_rfc3339()
+        )?;
+        if entry.edited {
+            out.push_str(" *(edited)*");
+        }
+        if let Some(label) = removed_label(&entry.removed) {
+            write!(&mut out, " **[{}]**", label)?;
+        }
+        out.push('\n');
+
+        for line in m.content.lines() {
+            write!(&mut out, "> {}\n", escape_markdown(line))?;
+        }
+        for e in m.embeds.iter() {
+            write!(
+                &mut out,
+                "> *Embed: {}*\n",
+                escape_markdown(e.title.as_deref().unwrap_or(""))
+            )?;
+            for line in e.description.as_deref().unwrap_or("").lines() {
+                write!(&mut out, "> {}\n", escape_markdown(line))?;
+            }
+        }
+        for a in m.attachments.iter() {
+            // Angle brackets keep any spaces or brackets in the url from ending the link
+            write!(
+                &mut out,
+                "> Attachment: [{}](<{}>)\n",
+                escape_markdown(&a.filename),
+                a.url.replace('>', "%3E")
+            )?;
+        }
+        out.push('\n');
+    }
+
+    if transcript.truncated {
+        out.push_str("*Transcript truncated, narrow the date range to see the rest*\n");
+    }
+
+    Ok(out)
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn render_html(transcript: &Transcript, title: &str) -> Result<String, Error> {
+    let mut out = String::new();
+    write!(
+        &mut out,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; background: #36393f; color: #dcddde; }}
+.message {{ margin: 0.5em 0; padding: 0.25em 0.5em; }}
+.removed {{ background: #4a2c2f; }}
+.author {{ font-weight: bold; color: #fff; }}
+.meta {{ font-size: 0.8em; color: #a3a6aa; }}
+.content {{ white-space: pre-wrap; }}
+a {{ color: #00aff4; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+"#,
+        title = escape_html(title)
+    )?;
+
+    for entry in transcript.entries.iter() {
+        let m = &entry.message;
+        write!(
+            &mut out,
+            "<div class=\"message{}\">\n<span class=\"author\">{}</span> <span class=\"meta\">{} - {}",
+            if entry.removed.is_some() { " removed" } else { "" },
+            escape_html(&m.author.tag()),
+            m.author.id.0,
+            m.timestamp.to_rfc3339()
+        )?;
+        if entry.edited {
+            out.push_str(" (edited)");
+        }
+        if let Some(label) = removed_label(&entry.removed) {
+            write!(&mut out, " [{}]", label)?;
+        }
+        out.push_str("</span>\n");
+
+        write!(&mut out, "<div class=\"content\">{}</div>\n", escape_html(&m.content))?;
+        for e in m.embeds.iter() {
+            write!(
+                &mut out,
+                "<div class=\"content\"><em>Embed: {}</em>\n{}</div>\n",
+                escape_html(e.title.as_deref().unwrap_or("")),
+                escape_html(e.description.as_deref().unwrap_or(""))
+            )?;
+        }
+        for a in m.attachments.iter() {
+            write!(
+                &mut out,
+                "<div>Attachment: <a href=\"{}\">{}</a></div>\n",
+                escape_html(&a.url),
+                escape_html(&a.filename)
+            )?;
+        }
+        out.push_str("</div>\n");
+    }
+
+    if transcript.truncated {
+        out.push_str("<p><em>Transcript truncated, narrow the date range to see the rest</em></p>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+
+    Ok(out)
+}
@@ -0,0 +1,105 @@
+use poise::serenity_prelude::{Guild, Member, Timestamp};
+use serde::Serialize;
+
+use crate::{
+    db::{message_record::MessageRecordV1, queries::message_log::MessageLog},
+    BotData, Error,
+};
+
+#[derive(Debug, Serialize)]
+struct ExportedLogEntry {
+    message_id: u64,
+    channel_id: Option<u64>,
+    timestamp: String,
+    #[serde(rename = "type")]
+    type_: String,
+    /// Everything stored for the message. Only Create and Edit entries have one
+    message: Option<MessageRecordV1>,
+}
+
+impl From<MessageLog> for ExportedLogEntry {
+    fn from(log: MessageLog) -> Self {
+        ExportedLogEntry {
+            message_id: log.message_id.0,
+            channel_id: log.channel_id.map(|v| v.0),
+            timestamp: log.timestamp.to_rfc3339(),
+            type_: format!("{:?}", log.type_),
+            message: log.message.as_ref().map(MessageRecordV1::from),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedMessageCount {
+    channel_id: u64,
+    count: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedRoleMenuChoice {
+    menu: String,
+    choice: String,
+    role_id: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct UserDataExport {
+    user_id: u64,
+    guild_id: u64,
+    generated: String,
+    messages: Vec<ExportedLogEntry>,
+    message_counts: Vec<ExportedMessageCount>,
+    /// Bot permissions the member has through their roles
+    permissions: Vec<String>,
+    /// Role menu choices whose role the member currently holds
+    role_menu_choices: Vec<ExportedRoleMenuChoice>,
+}
+
+/// Collects everything the bot holds about the member into a JSON document
+pub async fn export_user_data(
+    data: &BotData,
+    guild: &Guild,
+    member: &Member,
+) -> Result<Vec<u8>, Error> {
+    let guild_id = guild.id.into();
+    let user_data = data.get_user_data(guild_id, member.user.id.into()).await?;
+
+    let permissions = data
+        .get_member_permissions(guild, member)
+        .await?
+        .into_iter()
+        .map(|p| format!("{} (from role {})", p.permission, p.role.0))
+        .collect();
+
+    let mut role_menu_choices = Vec::new();
+    for ir in data.get_interaction_roles(guild_id).await? {
+        for choice in ir.choices.iter() {
+            if member.roles.contains(&*choice.role_id) {
+                role_menu_choices.push(ExportedRoleMenuChoice {
+                    menu: ir.name.clone(),
+                    choice: choice.choice.clone(),
+                    role_id: choice.role_id.0,
+                });
+            }
+        }
+    }
+
+    let export = UserDataExport {
+        user_id: member.user.id.0,
+        guild_id: guild.id.0,
+        generated: Timestamp::now().to_rfc3339(),
+        messages: user_data.messages.into_iter().map(Into::into).collect(),
+        message_counts: user_data
+            .message_counts
+            .into_iter()
+            .map(|(channel_id, count)| ExportedMessageCount {
+                channel_id: channel_id.0,
+                count,
+            })
+            .collect(),
+        permissions,
+        role_menu_choices,
+    };
+
+    Ok(serde_json::to_vec_pretty(&export)?)
+}
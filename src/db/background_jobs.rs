@@ -2,8 +2,8 @@ use std::time::Duration;
 
 use croner::Cron;
 use tokio::{sync::oneshot, task::JoinHandle, time::{Instant, sleep_until}};
-use tracing::{info, span, Instrument, Level};
-use crate::{db::DbCommand, Error};
+use tracing::{error, info, span, warn, Instrument, Level};
+use crate::{db::{open_read_only_database, run_backup, BackupConfig, queries::config::{parse_retention_days, ConfigKey}, verify_database, CompressionWorker, DbCommand, RepairReceipt, VerifyReport, REPAIR_CHUNK_BATCH_SIZE}, Error, MessageId};
 use chrono::{ DateTime, Utc };
 
 use super::CommandSender;
@@ -20,19 +20,44 @@ use super::CommandSender;
 const DB_OPTIMIZE_CRON_SCHEDULE: &str = "10 */3 * * *";
 const DB_VACUUM_CRON_SCHEDULE: &str = "30 4 */2 * *";
 const DB_COMPRESS_MESSAGES_CRON_SCHEDULE: &str = "50 2 * * *";
+const DB_RETENTION_CRON_SCHEDULE: &str = "20 3 * * *";
+const DB_VERIFY_CRON_SCHEDULE: &str = "40 5 * * 0";
+const DB_BACKUP_CRON_SCHEDULE: &str = "0 5 * * *";
 //const DB_COMPRESS_MESSAGES_CRON_SCHEDULE: &str = "*/2 * * * *";
 // This caps the max sleep the cron jobs will do. The reason for this is in case the montonic
 // timer gets out of sync due to device sleep. This makes it so we can miss the assigned time 
 // by at most this - 1 second
 const MAX_SLEEP_DURATION: Duration = Duration::from_secs(60*10);
 const MAX_COMPRESS_DURATION: Duration = Duration::from_secs(5);
+// Each guild gets this long so one with a big backlog can't starve the rest. Anything
+// left over is deleted on the next run
+const MAX_RETENTION_DURATION: Duration = Duration::from_secs(60);
+// Chunks with only messages older than this are recompressed at a higher level
+const COLD_CHUNK_AGE_DAYS: i64 = 30;
+// Recompressing at a high level is slow so this gets its own budget. It picks up where
+// it left off on the next run
+const MAX_COLD_RECOMPRESS_DURATION: Duration = Duration::from_secs(30);
+// Merge adjacent cold chunks that have been shrunk by deletes
+const MERGE_COLD_CHUNKS: bool = true;
+// Quarantine chunks that fail verification. Off by default so a verifier bug can't hide
+// the log, `gagbot_admin verify --repair` does it by hand
+const REPAIR_BAD_CHUNKS: bool = false;
 
 
 #[must_use]
-pub fn spawn_db_background_jobs_task(command_sender: CommandSender) -> JoinHandle<Result<(), Error>> {
+pub fn spawn_db_background_jobs_task(
+    command_sender: CommandSender,
+    sqlite_connection_string: String,
+    verify_report_sender: flume::Sender<VerifyReport>,
+    backup_config: BackupConfig,
+    shutdown_receiver: flume::Receiver<()>,
+) -> JoinHandle<Result<(), Error>> {
     let optimize_schedule = Cron::new(DB_OPTIMIZE_CRON_SCHEDULE).parse().expect("Invalid schedule specified by DB_OPTIMIZE_CRON_SCHEDULE");
     let vacuum_schedule = Cron::new(DB_VACUUM_CRON_SCHEDULE).parse().expect("Invalid schedule specified by DB_VACUUM_CRON_SCHEDULE");
     let compress_schedule = Cron::new(DB_COMPRESS_MESSAGES_CRON_SCHEDULE).parse().expect("Invalid schedule specified by DB_COMPRESS_MESSAGES_CRON_SCHEDULE");
+    let retention_schedule = Cron::new(DB_RETENTION_CRON_SCHEDULE).parse().expect("Invalid schedule specified by DB_RETENTION_CRON_SCHEDULE");
+    let verify_schedule = Cron::new(DB_VERIFY_CRON_SCHEDULE).parse().expect("Invalid schedule specified by DB_VERIFY_CRON_SCHEDULE");
+    let backup_schedule = Cron::new(DB_BACKUP_CRON_SCHEDULE).parse().expect("Invalid schedule specified by DB_BACKUP_CRON_SCHEDULE");
     
     fn get_next(cron: &Cron) -> Result<DateTime<Utc>, Error> {
         let next = cron.find_next_occurrence(&Utc::now(), false)?;
@@ -51,12 +76,21 @@ pub fn spawn_db_background_jobs_task(command_sender: CommandSender) -> JoinHandl
     }
 
     tokio::spawn(async move {
+        // Compression is done on a separate connection so the DB task is only busy
+        // for the commit
+        let mut compression_worker = CompressionWorker::new(sqlite_connection_string.clone());
         let mut next_optimize = get_next(&optimize_schedule)?;
         let mut next_vacuum = get_next(&vacuum_schedule)?;
         let mut next_compress = get_next(&compress_schedule)?;
+        let mut next_retention = get_next(&retention_schedule)?;
+        let mut next_verify = get_next(&verify_schedule)?;
+        let mut next_backup = get_next(&backup_schedule)?;
         let mut optimize_instant;
         let mut vacuum_instant;
         let mut compress_instant;
+        let mut retention_instant;
+        let mut verify_instant;
+        let mut backup_instant;
 
         loop {
             {
@@ -64,12 +98,24 @@ pub fn spawn_db_background_jobs_task(command_sender: CommandSender) -> JoinHandl
                 optimize_instant = get_instant(&next_optimize)?;
                 vacuum_instant = get_instant(&next_vacuum)?;
                 compress_instant = get_instant(&next_compress)?;
+                retention_instant = get_instant(&next_retention)?;
+                verify_instant = get_instant(&next_verify)?;
+                backup_instant = get_instant(&next_backup)?;
 
                 info!("Next optimize: {next_optimize}");
                 info!("Next vacuum: {next_vacuum}");
                 info!("Next compress: {next_compress}");
+                info!("Next retention: {next_retention}");
+                info!("Next verify: {next_verify}");
+                info!("Next backup: {next_backup}");
             }
             tokio::select! {
+                // Only checked between jobs so one that's running (a compress for example)
+                // gets to finish first
+                _ = shutdown_receiver.recv_async() => {
+                    info!("Background jobs shutting down");
+                    return Ok(());
+                },
                 _ = sleep_until(optimize_instant) => {    
                     if Utc::now() < next_optimize {
                         continue;
@@ -124,17 +170,56 @@ pub fn spawn_db_background_jobs_task(command_sender: CommandSender) -> JoinHandl
                     async {
                         let mut tot_time = Duration::from_secs(0);
                         while tot_time < MAX_COMPRESS_DURATION {
+                            let (duration, more) = compression_worker
+                                .compress(&command_sender)
+                                .await?;
+                            tot_time += duration;
+                            if !more {
+                                break;
+                            }
+                        }
+                        info!("DB compress ran in {} s", tot_time.as_secs_f32());
+
+                        // Any time left over is spent backfilling chunks compressed before the
+                        // search index, message_index ids and message records existed
+                        let mut backfill_time = Duration::from_secs(0);
+                        while tot_time + backfill_time < MAX_COMPRESS_DURATION {
                             let (s, r) = oneshot::channel();
                             command_sender
-                                .send_async(DbCommand::Compress { respond_to: s })
+                                .send_async(DbCommand::BackfillSearchIndex { respond_to: s })
                                 .await?;
                             let (duration, more) = r.await??;
-                            tot_time += duration;
+                            backfill_time += duration;
                             if !more {
                                 break;
                             }
                         }
-                        info!("DB compress ran in {} s", tot_time.as_secs_f32());
+                        while tot_time + backfill_time < MAX_COMPRESS_DURATION {
+                            let (duration, more) = compression_worker
+                                .convert_to_records(&command_sender)
+                                .await?;
+                            backfill_time += duration;
+                            if !more {
+                                break;
+                            }
+                        }
+                        info!("DB chunk backfill ran in {} s", backfill_time.as_secs_f32());
+
+                        let cutoff = MessageId::from_unix_millis(
+                            (Utc::now() - chrono::Duration::days(COLD_CHUNK_AGE_DAYS)).timestamp_millis());
+                        let mut cold_time = Duration::from_secs(0);
+                        while cold_time < MAX_COLD_RECOMPRESS_DURATION {
+                            // Built on the compression worker's connection, the DB task
+                            // only commits it
+                            let (duration, more) = compression_worker
+                                .recompress_cold(&command_sender, cutoff, MERGE_COLD_CHUNKS)
+                                .await?;
+                            cold_time += duration;
+                            if !more {
+                                break;
+                            }
+                        }
+                        info!("DB cold chunk recompress ran in {} s", cold_time.as_secs_f32());
                     
                         // Update the next run time
                         next_compress = get_next(&compress_schedule)?;
@@ -144,7 +229,147 @@ pub fn spawn_db_background_jobs_task(command_sender: CommandSender) -> JoinHandl
                     .instrument(span)
                     .await?
                 },
+                _ = sleep_until(retention_instant) => {    
+                    if Utc::now() < next_retention {
+                        continue;
+                    }            
+
+                    let span = span!(Level::INFO, "Running message log retention background task");
+                    async {
+                        let (s, r) = oneshot::channel();
+                        command_sender
+                            .send_async(DbCommand::GetConfigStringAllGuilds { 
+                                key: ConfigKey::LoggingRetentionDays, 
+                                respond_to: s,
+                            })
+                            .await?;
+                        let policies = r.await??;
+
+                        let mut tot_time = Duration::from_secs(0);
+                        for (guild_id, days) in policies {
+                            // Values set before they were validated could still be out of range
+                            let cutoff = match parse_retention_days(&days)
+                                .ok()
+                                .and_then(|days| Utc::now().checked_sub_signed(chrono::Duration::days(days)))
+                            {
+                                Some(cutoff) => MessageId::from_unix_millis(cutoff.timestamp_millis()),
+                                None => {
+                                    error!("Invalid {} ({}) for {:?}", ConfigKey::LoggingRetentionDays, days, guild_id);
+                                    continue;
+                                }
+                            };
+
+                            let mut guild_time = Duration::from_secs(0);
+                            while guild_time < MAX_RETENTION_DURATION {
+                                let (s, r) = oneshot::channel();
+                                command_sender
+                                    .send_async(DbCommand::ApplyRetention { guild_id, cutoff, respond_to: s })
+                                    .await?;
+                                let (duration, more) = r.await??;
+                                guild_time += duration;
+                                if !more {
+                                    break;
+                                }
+                            }
+                            if guild_time >= MAX_RETENTION_DURATION {
+                                warn!("Message log retention for {:?} ran out of time, the rest is left for the next run", guild_id);
+                            }
+                            tot_time += guild_time;
+                        }
+                        info!("Message log retention ran in {} s", tot_time.as_secs_f32());
+                    
+                        // Update the next run time
+                        next_retention = get_next(&retention_schedule)?;
+                        
+                        Ok::<_, Error>(())
+                    }
+                    .instrument(span)
+                    .await?
+                },
+                _ = sleep_until(verify_instant) => {    
+                    if Utc::now() < next_verify {
+                        continue;
+                    }            
+
+                    let span = span!(Level::INFO, "Running database verify background task");
+                    async {
+                        // Reading every chunk takes a while so it's done on its own read only
+                        // connection rather than holding up the DB task
+                        let connection_string = sqlite_connection_string.clone();
+                        let (duration, mut report) = tokio::task::spawn_blocking(move || {
+                            let con = open_read_only_database(&connection_string)?;
+                            verify_database(&con)
+                        })
+                        .await??;
+                        info!("DB verify ran in {} s", duration.as_secs_f32());
+
+                        if REPAIR_BAD_CHUNKS && !report.is_ok() {
+                            let chunk_ids = report.bad_chunks.iter().map(|v| v.chunk_id).collect::<Vec<_>>();
+                            report.repair = Some(repair_database(&command_sender, &chunk_ids).await?);
+                        }
+
+                        if report.is_ok() {
+                            info!("DB verify found no problems:\n{report}");
+                        } else {
+                            error!("DB verify found problems:\n{report}");
+                        }
+                        if let Err(e) = verify_report_sender.send_async(report).await {
+                            warn!("Nothing is listening for DB verify reports: {e}");
+                        }
+                    
+                        // Update the next run time
+                        next_verify = get_next(&verify_schedule)?;
+                        
+                        Ok::<_, Error>(())
+                    }
+                    .instrument(span)
+                    .await?
+                },
+                _ = sleep_until(backup_instant) => {    
+                    if Utc::now() < next_backup {
+                        continue;
+                    }            
+
+                    let span = span!(Level::INFO, "Running database backup background task");
+                    async {
+                        // A full disk or missing directory shouldn't take the bot down, it'll
+                        // try again on the next run
+                        if let Err(e) = run_backup(&backup_config).await {
+                            error!("DB backup to {} failed: {e:?}", backup_config.directory.display());
+                        }
+                    
+                        // Update the next run time
+                        next_backup = get_next(&backup_schedule)?;
+                        
+                        Ok::<_, Error>(())
+                    }
+                    .instrument(span)
+                    .await?
+                },
             }
         }
     })
-}
\ No newline at end of file
+}
+/// `db::repair_database` through the DB task, a batch per command
+async fn repair_database(command_sender: &CommandSender, chunk_ids: &[u64]) -> Result<RepairReceipt, Error> {
+    let mut receipt = RepairReceipt::default();
+    for batch in chunk_ids.chunks(REPAIR_CHUNK_BATCH_SIZE) {
+        let (s, r) = oneshot::channel();
+        command_sender
+            .send_async(DbCommand::RepairChunks { chunk_ids: batch.to_vec(), respond_to: s })
+            .await?;
+        receipt.add(r.await??);
+    }
+
+    let mut after = Some(0);
+    while let Some(a) = after {
+        let (s, r) = oneshot::channel();
+        command_sender
+            .send_async(DbCommand::ReassignIndexEntries { after: a, respond_to: s })
+            .await?;
+        let (batch, next) = r.await??;
+        receipt.add(batch);
+        after = next;
+    }
+    Ok(receipt)
+}
@@ -0,0 +1,257 @@
+use std::{
+    ffi::c_int,
+    fmt::Display,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::Utc;
+use rusqlite::{backup::Backup, Connection};
+use tokio::time::Instant;
+use tracing::{debug, info, instrument};
+
+use crate::{db::open_read_only_database, Error};
+
+const BACKUP_FILE_PREFIX: &str = "gagbot-";
+const BACKUP_FILE_EXTENSION: &str = ".sqlite";
+const COMPRESSED_BACKUP_FILE_EXTENSION: &str = ".sqlite.zst";
+// Written to this first so a half finished backup is never mistaken for a real one
+const PARTIAL_BACKUP_FILE_EXTENSION: &str = ".partial";
+// Copy every page in one step. A step is one read transaction so this gets a consistent
+// copy, where smaller steps would start over each time the bot wrote in between them.
+// The database is in WAL mode so the bot can keep writing while it runs
+const BACKUP_PAGES_PER_STEP: c_int = -1;
+
+/// Where backups are written and how many are kept
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    /// The database to back up. It's opened read only for each backup
+    pub sqlite_connection_string: String,
+    pub directory: PathBuf,
+    /// The oldest backups past this many are deleted after each backup. 0 is treated
+    /// as 1 so the backup that was just taken is never deleted
+    pub keep: usize,
+    /// zstd compress the backups
+    pub compress: bool,
+}
+
+/// What `run_backup` did
+#[derive(Debug, Clone)]
+pub struct BackupReceipt {
+    pub path: PathBuf,
+    pub bytes: u64,
+    /// How long copying the database took
+    pub copy_duration: Duration,
+    pub total_duration: Duration,
+    /// Old backups removed to stay within `BackupConfig::keep`
+    pub deleted: Vec<PathBuf>,
+}
+
+impl Display for BackupReceipt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Backed up to {} ({} bytes)\n", self.path.display(), self.bytes)?;
+        write!(
+            f,
+            "- Took {:.1} s ({:.1} s copying)\n",
+            self.total_duration.as_secs_f32(),
+            self.copy_duration.as_secs_f32()
+        )?;
+        write!(f, "- Old backups deleted: {}", self.deleted.len())
+    }
+}
+
+/// Copies the database into a new file at `path` with sqlite's online backup API. It's
+/// meant for a read only connection of its own (see `run_backup`) rather than the DB
+/// task's so it doesn't hold up the bot
+#[instrument(skip(con))]
+pub fn backup_database(con: &Connection, path: &Path) -> Result<Duration, Error> {
+    let start = Instant::now();
+    let mut dst = Connection::open(path)?;
+    {
+        let backup = Backup::new(con, &mut dst)?;
+        backup.run_to_completion(BACKUP_PAGES_PER_STEP, Duration::ZERO, None)?;
+    }
+    if let Err((_dst, e)) = dst.close() {
+        Err(e)?;
+    }
+    Ok(start.elapsed())
+}
+
+fn is_backup_file(name: &str) -> bool {
+    name.starts_with(BACKUP_FILE_PREFIX)
+        && (name.ends_with(BACKUP_FILE_EXTENSION) || name.ends_with(COMPRESSED_BACKUP_FILE_EXTENSION))
+}
+
+fn is_partial_backup_file(name: &str) -> bool {
+    name.starts_with(BACKUP_FILE_PREFIX) && name.ends_with(PARTIAL_BACKUP_FILE_EXTENSION)
+}
+
+fn list_backup_files(directory: &Path, filter: fn(&str) -> bool) -> Result<Vec<PathBuf>, Error> {
+    let mut files = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry
+            .file_name()
+            .to_str()
+            .map_or(false, filter))
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// Deletes the partial copies left behind by backups that were interrupted, e.g. by the
+/// bot being killed. It should only be run when no backup is in progress
+pub fn remove_partial_backups(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+    let mut deleted = Vec::new();
+    for path in list_backup_files(directory, is_partial_backup_file)? {
+        fs::remove_file(&path)?;
+        info!("Deleted partial backup {}", path.display());
+        deleted.push(path);
+    }
+    Ok(deleted)
+}
+
+/// Deletes all but the newest `keep` (at least 1) backups in the directory. The file
+/// names start with the time they were taken so sorting them by name sorts them by age
+fn rotate_backups(directory: &Path, keep: usize) -> Result<Vec<PathBuf>, Error> {
+    let backups = list_backup_files(directory, is_backup_file)?;
+
+    let expired = backups.len().saturating_sub(keep.max(1));
+    let mut deleted = Vec::with_capacity(expired);
+    for path in backups.into_iter().take(expired) {
+        fs::remove_file(&path)?;
+        debug!("Deleted old backup {}", path.display());
+        deleted.push(path);
+    }
+    Ok(deleted)
+}
+
+/// Moves the finished copy into place, compressing it if configured to
+fn finish_backup(partial_path: &Path, path: &Path, compress: bool) -> Result<u64, Error> {
+    if compress {
+        let mut writer = BufWriter::new(File::create(path)?);
+        zstd::stream::copy_encode(File::open(partial_path)?, &mut writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::remove_file(partial_path)?;
+    } else {
+        fs::rename(partial_path, path)?;
+    }
+    Ok(fs::metadata(path)?.len())
+}
+
+/// Takes a timestamped backup into the configured directory then deletes the oldest
+/// backups past the configured count. It all runs on the blocking pool with its own read
+/// only connection so the DB task carries on as normal
+pub async fn run_backup(config: &BackupConfig) -> Result<BackupReceipt, Error> {
+    let start = Instant::now();
+    tokio::fs::create_dir_all(&config.directory).await?;
+
+    let name = format!(
+        "{BACKUP_FILE_PREFIX}{}{}",
+        Utc::now().format("%Y%m%dT%H%M%SZ"),
+        if config.compress { COMPRESSED_BACKUP_FILE_EXTENSION } else { BACKUP_FILE_EXTENSION }
+    );
+    let path = config.directory.join(&name);
+    let partial_path = config.directory.join(format!("{name}{PARTIAL_BACKUP_FILE_EXTENSION}"));
+
+    let config = config.clone();
+    let finish_path = path.clone();
+    let (copy_duration, bytes, deleted) = tokio::task::spawn_blocking(move || -> Result<_, Error> {
+        let copied = open_read_only_database(&config.sqlite_connection_string)
+            .and_then(|con| backup_database(&con, &partial_path))
+            .and_then(|copy_duration| Ok((
+                copy_duration,
+                finish_backup(&partial_path, &finish_path, config.compress)?,
+            )));
+        let (copy_duration, bytes) = match copied {
+            Ok(v) => v,
+            Err(e) => {
+                let _ = fs::remove_file(&partial_path);
+                Err(e)?
+            }
+        };
+        let deleted = rotate_backups(&config.directory, config.keep)?;
+        Ok((copy_duration, bytes, deleted))
+    })
+    .await??;
+
+    let receipt = BackupReceipt {
+        path,
+        bytes,
+        copy_duration,
+        total_duration: start.elapsed(),
+        deleted,
+    };
+    info!("{}", receipt);
+    Ok(receipt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for each test
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("gagbot-backup-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn touch(directory: &Path, name: &str) {
+        fs::write(directory.join(name), b"").unwrap();
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<String> {
+        paths
+            .iter()
+            .map(|p| p.file_name().unwrap().to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn rotate_keeps_the_newest_backups() {
+        let directory = test_directory("rotate");
+        touch(&directory, "gagbot-20230101T050000Z.sqlite");
+        touch(&directory, "gagbot-20230102T050000Z.sqlite.zst");
+        touch(&directory, "gagbot-20230103T050000Z.sqlite");
+        // Not backups so they're left alone
+        touch(&directory, "gagbot.sqlite");
+        touch(&directory, "gagbot-20230104T050000Z.sqlite.partial");
+
+        let deleted = rotate_backups(&directory, 2).unwrap();
+        assert_eq!(names(&deleted), vec!["gagbot-20230101T050000Z.sqlite"]);
+        assert!(directory.join("gagbot.sqlite").exists());
+        assert!(directory.join("gagbot-20230104T050000Z.sqlite.partial").exists());
+
+        // The newest backup is always kept
+        let deleted = rotate_backups(&directory, 0).unwrap();
+        assert_eq!(names(&deleted), vec!["gagbot-20230102T050000Z.sqlite.zst"]);
+        assert!(directory.join("gagbot-20230103T050000Z.sqlite").exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn partial_backups_are_removed() {
+        let directory = test_directory("partial");
+        touch(&directory, "gagbot-20230101T050000Z.sqlite");
+        touch(&directory, "gagbot-20230102T050000Z.sqlite.partial");
+        touch(&directory, "gagbot-20230103T050000Z.sqlite.zst.partial");
+
+        let deleted = remove_partial_backups(&directory).unwrap();
+        assert_eq!(names(&deleted), vec![
+            "gagbot-20230102T050000Z.sqlite.partial",
+            "gagbot-20230103T050000Z.sqlite.zst.partial",
+        ]);
+        assert!(directory.join("gagbot-20230101T050000Z.sqlite").exists());
+        assert_eq!(remove_partial_backups(&directory.join("missing")).unwrap().len(), 0);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
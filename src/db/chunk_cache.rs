@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rusqlite::Connection;
+
+use crate::{ensure, Error};
+
+/// A decompressed message chunk along with where each entry starts
+#[derive(Debug)]
+pub struct CachedChunk {
+    pub start_id: u64,
+    pub end_id: u64,
+    pub data: Vec<u8>,
+    /// The offset into `data` of each COBS frame, one per message_index_id from
+    /// `start_id` to `end_id`
+    pub offsets: Vec<u32>,
+}
+
+impl CachedChunk {
+    pub fn new(start_id: u64, end_id: u64, data: Vec<u8>, offsets: Vec<u32>) -> Result<Self, Error> {
+        ensure!(
+            offsets.len() as u64 == end_id - start_id + 1,
+            "Chunk has {} frames but its bounds ({start_id} -> {end_id}) need {}",
+            offsets.len(),
+            end_id - start_id + 1
+        );
+        Ok(Self { start_id, end_id, data, offsets })
+    }
+
+    /// The COBS frame (including the trailing zero) for the message_index_id
+    pub fn frame(&self, message_index_id: u64) -> Option<&[u8]> {
+        if message_index_id < self.start_id || message_index_id > self.end_id {
+            return None;
+        }
+        let i = (message_index_id - self.start_id) as usize;
+        let start = self.offsets[i] as usize;
+        let end = self
+            .offsets
+            .get(i + 1)
+            .map(|v| *v as usize)
+            .unwrap_or(self.data.len());
+        self.data.get(start..end)
+    }
+}
+
+/// Finds the start of each COBS frame in decompressed chunk data
+pub fn scan_frame_offsets(data: &[u8]) -> Vec<u32> {
+    let mut offsets = Vec::new();
+    let mut start_i = 0;
+    for (end_i, _) in data
+        .iter()
+        .enumerate()
+        .filter(|(_, byte)| **byte == corncobs::ZERO)
+    {
+        offsets.push(start_i as u32);
+        start_i = end_i + 1;
+    }
+    offsets
+}
+
+/// Packs the frame offsets for storing alongside the chunk
+pub fn encode_frame_offsets(offsets: &[u32]) -> Vec<u8> {
+    offsets.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// The inverse of `encode_frame_offsets`
+pub fn decode_frame_offsets(data: &[u8]) -> Result<Vec<u32>, Error> {
+    ensure!(data.len() % 4 == 0, "Frame offsets length ({}) isn't a multiple of 4", data.len());
+    Ok(data
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+/// Temp (so connection local) triggers that record every chunk written on the connection,
+/// so the cache can drop just those chunks. A rewrite can reuse a chunk_id so inserts count too
+const TRACK_CHUNK_WRITES_SQL: &str = "
+    CREATE TEMP TABLE IF NOT EXISTS chunk_cache_writes (chunk_id INTEGER PRIMARY KEY);
+    CREATE TEMP TRIGGER IF NOT EXISTS chunk_cache_insert AFTER INSERT ON main.message_chunk BEGIN
+        INSERT OR IGNORE INTO chunk_cache_writes (chunk_id) VALUES (new.chunk_id);
+    END;
+    CREATE TEMP TRIGGER IF NOT EXISTS chunk_cache_update AFTER UPDATE ON main.message_chunk BEGIN
+        INSERT OR IGNORE INTO chunk_cache_writes (chunk_id) VALUES (old.chunk_id);
+    END;
+    CREATE TEMP TRIGGER IF NOT EXISTS chunk_cache_delete AFTER DELETE ON main.message_chunk BEGIN
+        INSERT OR IGNORE INTO chunk_cache_writes (chunk_id) VALUES (old.chunk_id);
+    END;";
+
+/// An LRU of decompressed chunks. It's owned by the DB task so lookups that keep
+/// hitting the same chunk (like a burst of deletes) only decompress it once.
+///
+/// Chunks written on the owning connection are tracked by the triggers above and
+/// invalidated one by one on the next lookup. `PRAGMA data_version` doesn't change for
+/// those, but it does for changes made through another connection (e.g. the compression
+/// worker), which clear the whole cache
+#[derive(Debug)]
+pub struct ChunkCache {
+    capacity: usize,
+    /// chunk_id -> (last used tick, chunk)
+    chunks: HashMap<u64, (u64, CachedChunk)>,
+    /// last used tick -> chunk_id, so the least recently used chunk is the first entry
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    data_version: Option<i64>,
+    tracking_writes: bool,
+    hits: u64,
+    misses: u64,
+}
+
+impl ChunkCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            chunks: HashMap::with_capacity(capacity),
+            lru: BTreeMap::new(),
+            tick: 0,
+            data_version: None,
+            tracking_writes: false,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Gets the chunk from the cache or calls `load` to decompress it
+    pub fn get_or_load<F>(
+        &mut self,
+        db: &Connection,
+        chunk_id: u64,
+        load: F,
+    ) -> Result<&CachedChunk, Error>
+    where
+        F: FnOnce() -> Result<CachedChunk, Error>,
+    {
+        self.invalidate_written(db)?;
+
+        self.tick += 1;
+        if let Some(entry) = self.chunks.get_mut(&chunk_id) {
+            self.hits += 1;
+            self.lru.remove(&entry.0);
+            self.lru.insert(self.tick, chunk_id);
+            entry.0 = self.tick;
+        } else {
+            self.misses += 1;
+            let chunk = load()?;
+            if self.chunks.len() >= self.capacity {
+                if let Some((_, oldest)) = self.lru.pop_first() {
+                    self.chunks.remove(&oldest);
+                }
+            }
+            self.lru.insert(self.tick, chunk_id);
+            self.chunks.insert(chunk_id, (self.tick, chunk));
+        }
+
+        Ok(&self.chunks[&chunk_id].1)
+    }
+
+    /// Drops everything that's changed since the last lookup
+    fn invalidate_written(&mut self, db: &Connection) -> Result<(), Error> {
+        if !self.tracking_writes {
+            // Nothing can be cached before this so there's nothing it could have missed
+            db.execute_batch(TRACK_CHUNK_WRITES_SQL)?;
+            self.tracking_writes = true;
+        }
+
+        let data_version: i64 = db.query_row("PRAGMA data_version", (), |r| r.get(0))?;
+        if self.data_version != Some(data_version) {
+            self.clear();
+            self.data_version = Some(data_version);
+        }
+
+        let written: Vec<u64> = db
+            .prepare_cached("DELETE FROM temp.chunk_cache_writes RETURNING chunk_id")?
+            .query_map((), |r| r.get(0))?
+            .collect::<Result<_, _>>()?;
+        for chunk_id in written {
+            self.invalidate(chunk_id);
+        }
+        Ok(())
+    }
+
+    pub fn invalidate(&mut self, chunk_id: u64) {
+        if let Some((last_used, _)) = self.chunks.remove(&chunk_id) {
+            self.lru.remove(&last_used);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.lru.clear();
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_utils::test_database;
+
+    fn insert_chunk(db: &Connection, chunk_id: u64) {
+        db.execute(
+            "INSERT INTO message_chunk (chunk_id, start_message_index_id, end_message_index_id, data)
+            VALUES (?1, ?1, ?1, x'00')",
+            [chunk_id],
+        )
+        .unwrap();
+    }
+
+    /// Loads the chunk and returns true if it wasn't already cached
+    fn load(db: &Connection, cache: &mut ChunkCache, chunk_id: u64) -> bool {
+        let mut loaded = false;
+        cache
+            .get_or_load(db, chunk_id, || {
+                loaded = true;
+                CachedChunk::new(chunk_id, chunk_id, vec![0], vec![0])
+            })
+            .unwrap();
+        loaded
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let db = test_database();
+        let mut cache = ChunkCache::new(2);
+        assert!(load(&db, &mut cache, 1));
+        assert!(load(&db, &mut cache, 2));
+        assert!(!load(&db, &mut cache, 1));
+        // 2 is now the oldest
+        assert!(load(&db, &mut cache, 3));
+        assert!(!load(&db, &mut cache, 1));
+        assert!(load(&db, &mut cache, 2));
+        assert_eq!(cache.hits(), 2);
+        assert_eq!(cache.misses(), 4);
+    }
+
+    #[test]
+    fn invalidates_chunks_written_on_the_same_connection() {
+        let db = test_database();
+        let mut cache = ChunkCache::new(4);
+        for chunk_id in 1..=3 {
+            insert_chunk(&db, chunk_id);
+            assert!(load(&db, &mut cache, chunk_id));
+        }
+
+        db.execute("UPDATE message_chunk SET data = x'0000' WHERE chunk_id = 1", ()).unwrap();
+        db.execute("DELETE FROM message_chunk WHERE chunk_id = 2", ()).unwrap();
+        assert!(load(&db, &mut cache, 1));
+        assert!(load(&db, &mut cache, 2));
+        assert!(!load(&db, &mut cache, 3));
+
+        // A reused chunk_id is a different chunk
+        db.execute("DELETE FROM message_chunk WHERE chunk_id = 3", ()).unwrap();
+        insert_chunk(&db, 3);
+        assert!(load(&db, &mut cache, 3));
+        assert!(!load(&db, &mut cache, 1));
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use rusqlite::Connection;
+use tokio::{sync::oneshot, time::Instant};
+use tracing::debug;
+
+use crate::{
+    db::{open_read_only_database, queries::message_log, CommandSender, DbCommand},
+    Error, MessageId,
+};
+
+/// Builds message chunks on its own read only connection so the zstd work doesn't hold
+/// up the DB task. The DB task only has to commit the finished chunk
+pub struct CompressionWorker {
+    connection_string: String,
+    // Taken while the connection is in use on the blocking pool
+    con: Option<Connection>,
+}
+
+impl CompressionWorker {
+    pub fn new(connection_string: String) -> Self {
+        Self {
+            connection_string,
+            con: None,
+        }
+    }
+
+    /// Runs `f` with the worker's connection on the blocking pool
+    async fn with_connection<F, T>(&mut self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Connection) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let con = match self.con.take() {
+            Some(con) => con,
+            None => open_read_only_database(&self.connection_string)?,
+        };
+
+        let (con, r) = tokio::task::spawn_blocking(move || {
+            let r = f(&con);
+            (con, r)
+        })
+        .await?;
+        self.con = Some(con);
+
+        r
+    }
+
+    /// Compresses the next chunk if there are enough uncompressed messages.
+    ///
+    /// Returns how long it took and whether there are enough messages left for
+    /// another chunk
+    pub async fn compress(&mut self, command_sender: &CommandSender) -> Result<(Duration, bool), Error> {
+        let start = Instant::now();
+
+        let chunk = match self.with_connection(message_log::prepare_chunk).await? {
+            Some(chunk) => chunk,
+            None => return Ok((start.elapsed(), false)),
+        };
+        debug!("Chunk prepared in {} s", start.elapsed().as_secs_f32());
+
+        let (s, r) = oneshot::channel();
+        command_sender
+            .send_async(DbCommand::CommitChunk { chunk, respond_to: s })
+            .await?;
+        let more = r.await??;
+
+        Ok((start.elapsed(), more))
+    }
+
+    /// Converts the next chunk compressed before message records existed, see
+    /// `message_log::prepare_record_conversion`.
+    ///
+    /// Returns how long it took and whether there might be more chunks to convert
+    pub async fn convert_to_records(&mut self, command_sender: &CommandSender) -> Result<(Duration, bool), Error> {
+        let start = Instant::now();
+
+        let chunk = match self.with_connection(message_log::prepare_record_conversion).await? {
+            Some(chunk) => chunk,
+            None => return Ok((start.elapsed(), false)),
+        };
+        debug!("Record conversion prepared in {} s", start.elapsed().as_secs_f32());
+
+        self.commit_rewritten_chunk(command_sender, chunk).await?;
+        Ok((start.elapsed(), true))
+    }
+
+    /// Recompresses the next cold chunk, see `message_log::prepare_cold_chunk`.
+    ///
+    /// Returns how long it took and whether there might be more chunks to recompress
+    pub async fn recompress_cold(
+        &mut self,
+        command_sender: &CommandSender,
+        cutoff: MessageId,
+        merge: bool,
+    ) -> Result<(Duration, bool), Error> {
+        let start = Instant::now();
+
+        let chunk = match self
+            .with_connection(move |con| message_log::prepare_cold_chunk(con, cutoff, merge))
+            .await?
+        {
+            Some(chunk) => chunk,
+            None => return Ok((start.elapsed(), false)),
+        };
+        debug!("Cold chunk prepared in {} s", start.elapsed().as_secs_f32());
+
+        self.commit_rewritten_chunk(command_sender, chunk).await?;
+        Ok((start.elapsed(), true))
+    }
+
+    async fn commit_rewritten_chunk(
+        &self,
+        command_sender: &CommandSender,
+        chunk: message_log::RewrittenChunk,
+    ) -> Result<bool, Error> {
+        let (s, r) = oneshot::channel();
+        command_sender
+            .send_async(DbCommand::CommitRewrittenChunk { chunk, respond_to: s })
+            .await?;
+        Ok(r.await??)
+    }
+}
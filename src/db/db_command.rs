@@ -1,14 +1,18 @@
-use std::time::Duration;
+use std::{fmt::Display, time::Duration};
 
 use poise::serenity_prelude::{Message, Timestamp};
+use serde::Serialize;
 use tokio::sync::oneshot::Sender;
 
 use crate::{
     db::queries::{
         config::{ConfigKey, LogChannel},
-        interaction_roles::InteractionRole,
-        message_log::{LogType, MessageLog},
+        confirmations::{ConfirmationAction, PendingConfirmation},
+        custom_ids::{InteractionCustomId, InteractionKind},
+        interaction_roles::{InteractionRole, InteractionRoleStyle},
+        message_log::{DeleteReceipt, LogType, MessageLog, MessageSearchQuery, MessageSearchResult, PreparedChunk, RewrittenChunk},
         permissions::{EffectivePermission, Permission},
+        reaction_roles::ReactionRoleTemp,
     },
     ChannelId, GuildId, MessageId, RoleId, UserId, Error
 };
@@ -17,13 +21,136 @@ use crate::{
 pub type CommandSender = flume::Sender<DbCommand>;
 pub type CommandReceiver = flume::Receiver<DbCommand>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct CompressionState {
     pub uncompressed_messages: u64,
     pub uncompressed_bytes: u64,
     pub compressed_messages: u64,
     pub compressed_bytes: u64,
     pub chunks: u64,
+    pub chunk_cache_hits: u64,
+    pub chunk_cache_misses: u64,
+}
+
+impl CompressionState {
+    /// The fraction of chunk lookups served from the cache since the bot started
+    pub fn chunk_cache_hit_rate(&self) -> f64 {
+        let total = self.chunk_cache_hits + self.chunk_cache_misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.chunk_cache_hits as f64 / total as f64
+        }
+    }
+}
+
+/// Everything stored about a user in a guild
+#[derive(Debug)]
+pub struct UserData {
+    pub messages: Vec<MessageLog>,
+    pub message_counts: Vec<(ChannelId, u64)>,
+}
+
+/// Everything removed by `forget_user`
+#[derive(Debug, Clone)]
+pub struct ForgetUserReceipt {
+    pub user_id: UserId,
+    pub guild_id: Option<GuildId>,
+    pub message_log: DeleteReceipt,
+    pub message_counts: u64,
+}
+
+impl ForgetUserReceipt {
+    pub fn add(&mut self, other: ForgetUserReceipt) {
+        self.message_log.add(other.message_log);
+        self.message_counts += other.message_counts;
+    }
+}
+
+impl Display for ForgetUserReceipt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Deleted data for user {}", self.user_id.0)?;
+        if let Some(guild_id) = self.guild_id {
+            write!(f, " in guild {}", guild_id.0)?;
+        }
+        write!(f, ":\n")?;
+        write!(f, "- Message log entries: {}\n", self.message_log.index_entries)?;
+        write!(f, "- Uncompressed message bodies: {}\n", self.message_log.uncompressed_bodies)?;
+        write!(f, "- Search index entries: {}\n", self.message_log.search_entries)?;
+        write!(f, "- Compressed chunks rewritten: {}\n", self.message_log.chunks_rewritten)?;
+        write!(f, "- Compressed chunks dropped: {}\n", self.message_log.chunks_dropped)?;
+        write!(f, "- Per channel message counts: {}", self.message_counts)
+    }
+}
+
+/// A compressed chunk that failed verification
+#[derive(Debug, Clone, Serialize)]
+pub struct BadChunk {
+    pub chunk_id: u64,
+    pub reason: String,
+}
+
+/// What `repair_compressed_chunks` changed
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepairReceipt {
+    pub chunks_quarantined: u64,
+    pub index_entries_reassigned: u64,
+    /// Create and Edit entries left without a body
+    pub bodies_lost: u64,
+}
+
+impl RepairReceipt {
+    pub fn add(&mut self, other: RepairReceipt) {
+        self.chunks_quarantined += other.chunks_quarantined;
+        self.index_entries_reassigned += other.index_entries_reassigned;
+        self.bodies_lost += other.bodies_lost;
+    }
+}
+
+/// The result of checking the database for corruption
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    /// The rows returned by `PRAGMA integrity_check`. Just "ok" if nothing is wrong
+    pub integrity_check: Vec<String>,
+    pub chunks_checked: u64,
+    pub bad_chunks: Vec<BadChunk>,
+    /// message_index entries pointing at a chunk that doesn't cover them
+    pub misplaced_index_entries: u64,
+    /// Set if a repair was run after verifying
+    pub repair: Option<RepairReceipt>,
+}
+
+impl VerifyReport {
+    /// How many bad chunks are listed individually by Display
+    const MAX_LISTED_BAD_CHUNKS: usize = 10;
+
+    pub fn is_ok(&self) -> bool {
+        self.integrity_check.iter().all(|v| v == "ok")
+            && self.bad_chunks.len() == 0
+            && self.misplaced_index_entries == 0
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Integrity check: {}\n", self.integrity_check.join(", "))?;
+        write!(f, "Compressed chunks checked: {}\n", self.chunks_checked)?;
+        write!(f, "Bad chunks: {}\n", self.bad_chunks.len())?;
+        for bad in self.bad_chunks.iter().take(Self::MAX_LISTED_BAD_CHUNKS) {
+            write!(f, "- {}: {}\n", bad.chunk_id, bad.reason)?;
+        }
+        if self.bad_chunks.len() > Self::MAX_LISTED_BAD_CHUNKS {
+            write!(f, "- ...and {} more\n", self.bad_chunks.len() - Self::MAX_LISTED_BAD_CHUNKS)?;
+        }
+        write!(f, "Misplaced message index entries: {}", self.misplaced_index_entries)?;
+        if let Some(repair) = &self.repair {
+            write!(f, "\nRepair:\n")?;
+            write!(f, "- Chunks quarantined: {}\n", repair.chunks_quarantined)?;
+            write!(f, "- Message index entries reassigned: {}\n", repair.index_entries_reassigned)?;
+            write!(f, "- Message bodies lost: {}", repair.bodies_lost)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, strum::Display)]
@@ -37,7 +164,61 @@ pub enum DbCommand {
     Vacuum {
         respond_to: Sender<Result<Duration, Error>>,
     },
-    Compress {
+    /// Stops the DB task once the commands queued before it have run. Responds after
+    /// `close_database` has optimized and closed the connection
+    Close {
+        respond_to: Sender<Result<(), Error>>,
+    },
+    CommitChunk {
+        chunk: PreparedChunk,
+        respond_to: Sender<Result<bool, Error>>,
+    },
+    /// Commits a cold recompression or message record conversion built by the
+    /// compression worker. Responds with false if the chunk changed in the meantime
+    CommitRewrittenChunk {
+        chunk: RewrittenChunk,
+        respond_to: Sender<Result<bool, Error>>,
+    },
+    ApplyRetention {
+        guild_id: GuildId,
+        cutoff: MessageId,
+        respond_to: Sender<Result<(Duration, bool), Error>>,
+    },
+    /// Quarantines a batch of the chunks found by a verify, see `repair_database`
+    RepairChunks {
+        chunk_ids: Vec<u64>,
+        respond_to: Sender<Result<RepairReceipt, Error>>,
+    },
+    /// A batch of `message_log::reassign_index_entries`
+    ReassignIndexEntries {
+        after: u64,
+        respond_to: Sender<Result<(RepairReceipt, Option<u64>), Error>>,
+    },
+    GetConfigStringAllGuilds {
+        key: ConfigKey,
+        respond_to: Sender<Result<Vec<(GuildId, String)>, Error>>,
+    },
+    /// A page of `message_log::get_by_user`
+    GetUserMessages {
+        guild_id: GuildId,
+        user_id: UserId,
+        after: Option<(MessageId, u64)>,
+        limit: u64,
+        respond_to: Sender<Result<Vec<MessageLog>, Error>>,
+    },
+    GetUserMessageCounts {
+        guild_id: GuildId,
+        user_id: UserId,
+        respond_to: Sender<Result<Vec<(ChannelId, u64)>, Error>>,
+    },
+    /// Does one batch of the deletion, the bool is true if there's more to do
+    ForgetUser {
+        guild_id: Option<GuildId>,
+        user_id: UserId,
+        respond_to: Sender<Result<(ForgetUserReceipt, bool), Error>>,
+    },
+    /// Adds one of the chunks compressed before the search index existed to it
+    BackfillSearchIndex {
         respond_to: Sender<Result<(Duration, bool), Error>>,
     },
     GetGreet {
@@ -123,6 +304,15 @@ pub enum DbCommand {
         timestamp: Timestamp,
         respond_to: Sender<Result<bool, Error>>,
     },
+    UpdateInteractionRoleStyle {
+        guild_id: GuildId,
+        name: String,
+        style: InteractionRoleStyle,
+        min_values: Option<u8>,
+        max_values: Option<u8>,
+        timestamp: Timestamp,
+        respond_to: Sender<Result<bool, Error>>,
+    },
     UpdateInteractionRoleChoice {
         guild_id: GuildId,
         set_name: String,
@@ -132,6 +322,76 @@ pub enum DbCommand {
         timestamp: Timestamp,
         respond_to: Sender<Result<bool, Error>>,
     },
+    UpdateInteractionRoleChoiceRequirements {
+        guild_id: GuildId,
+        set_name: String,
+        choice: String,
+        required_role_id: Option<RoleId>,
+        forbidden_role_id: Option<RoleId>,
+        min_message_count: Option<usize>,
+        timestamp: Timestamp,
+        respond_to: Sender<Result<bool, Error>>,
+    },
+    DeleteInteractionRoleSet {
+        guild_id: GuildId,
+        name: String,
+        respond_to: Sender<Result<bool, Error>>,
+    },
+    DeleteInteractionRoleChoice {
+        guild_id: GuildId,
+        set_name: String,
+        choice: String,
+        respond_to: Sender<Result<bool, Error>>,
+    },
+    GetReactionRoleTemps {
+        guild_id: Option<GuildId>,
+        respond_to: Sender<Result<Vec<ReactionRoleTemp>, Error>>,
+    },
+    GetReactionRoleTempByMessage {
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        respond_to: Sender<Result<Option<ReactionRoleTemp>, Error>>,
+    },
+    DeleteReactionRoleTemp {
+        guild_id: GuildId,
+        name: String,
+        respond_to: Sender<Result<bool, Error>>,
+    },
+    CreatePendingConfirmation {
+        guild_id: GuildId,
+        requester_id: UserId,
+        action: ConfirmationAction,
+        payload: serde_json::Value,
+        expires: i64,
+        timestamp: Timestamp,
+        respond_to: Sender<Result<String, Error>>,
+    },
+    GetPendingConfirmation {
+        id: String,
+        respond_to: Sender<Result<Option<PendingConfirmation>, Error>>,
+    },
+    DeletePendingConfirmation {
+        id: String,
+        respond_to: Sender<Result<bool, Error>>,
+    },
+    GetInteractionCustomId {
+        id: String,
+        respond_to: Sender<Result<Option<InteractionCustomId>, Error>>,
+    },
+    GetOrCreateInteractionCustomId {
+        guild_id: GuildId,
+        kind: InteractionKind,
+        set_name: String,
+        choice: String,
+        extra: Option<serde_json::Value>,
+        timestamp: Timestamp,
+        respond_to: Sender<Result<String, Error>>,
+    },
+    GetInteractionRoles {
+        guild_id: GuildId,
+        respond_to: Sender<Result<Vec<InteractionRole>, Error>>,
+    },
     GetInteractionRole {
         guild_id: GuildId,
         name: String,
@@ -148,12 +408,26 @@ pub enum DbCommand {
         message_id: MessageId,
         respond_to: Sender<Result<Vec<MessageLog>, Error>>,
     },
-    // GetUserFromLogMessages {
-    //     guild_id: GuildId,
-    //     channel_id: ChannelId,
-    //     message_id: MessageId,
-    //     respond_to: Sender<Result<Option<UserId>, Error>>,
-    // },
+    GetLogMessagesByChannel {
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        after_id: MessageId,
+        before_id: MessageId,
+        limit: u64,
+        respond_to: Sender<Result<Vec<MessageLog>, Error>>,
+    },
+    SearchMessages {
+        query: MessageSearchQuery,
+        limit: u64,
+        offset: u64,
+        respond_to: Sender<Result<(u64, Vec<MessageSearchResult>), Error>>,
+    },
+    GetUserFromLogMessages {
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        respond_to: Sender<Result<Option<UserId>, Error>>,
+    },
     GetTableBytesAndCount {
         respond_to: Sender<Result<Vec<(String, u64, u64)>, Error>>,
     },
@@ -0,0 +1,302 @@
+//! The format message bodies are stored in.
+//!
+//! Each body starts with a version byte followed by the record encoded as
+//! MessagePack. Bodies logged before records existed are serenity's JSON which
+//! always starts with `{` so the two can't be confused.
+//!
+//! Migration 21 doesn't convert the existing bodies, most of them are in zstd chunks
+//! which SQL can't read. Uncompressed bodies are upgraded as they're compressed and
+//! the compression worker converts the chunks in the background (see
+//! `message_log::prepare_record_conversion`). Until that's finished both formats are
+//! stored and every reader has to go through `decode_message`, which handles either.
+use poise::serenity_prelude::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::Error;
+
+pub const MESSAGE_RECORD_V1: u8 = 1;
+const LEGACY_JSON_START: u8 = b'{';
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthorRecord {
+    pub id: u64,
+    pub name: String,
+    pub discriminator: u16,
+    pub bot: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttachmentRecord {
+    pub id: u64,
+    pub filename: String,
+    pub url: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbedRecord {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReferenceRecord {
+    pub message_id: Option<u64>,
+    pub channel_id: u64,
+    pub guild_id: Option<u64>,
+}
+
+/// The parts of a message that are shown when it's looked up in the log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageRecordV1 {
+    pub id: u64,
+    pub channel_id: u64,
+    pub guild_id: Option<u64>,
+    pub author: AuthorRecord,
+    pub content: String,
+    pub timestamp: String,
+    pub edited_timestamp: Option<String>,
+    pub attachments: Vec<AttachmentRecord>,
+    pub embeds: Vec<EmbedRecord>,
+    pub mentions: Vec<u64>,
+    pub mention_roles: Vec<u64>,
+    pub reference: Option<ReferenceRecord>,
+}
+
+impl From<&Message> for MessageRecordV1 {
+    fn from(m: &Message) -> Self {
+        MessageRecordV1 {
+            id: m.id.0,
+            channel_id: m.channel_id.0,
+            guild_id: m.guild_id.map(|v| v.0),
+            author: AuthorRecord {
+                id: m.author.id.0,
+                name: m.author.name.clone(),
+                discriminator: m.author.discriminator,
+                bot: m.author.bot,
+            },
+            content: m.content.clone(),
+            timestamp: m.timestamp.to_rfc3339(),
+            edited_timestamp: m.edited_timestamp.as_ref().map(|v| v.to_rfc3339()),
+            attachments: m
+                .attachments
+                .iter()
+                .map(|a| AttachmentRecord {
+                    id: a.id.0,
+                    filename: a.filename.clone(),
+                    url: a.url.clone(),
+                    content_type: a.content_type.clone(),
+                    size: a.size,
+                })
+                .collect(),
+            embeds: m
+                .embeds
+                .iter()
+                .map(|e| EmbedRecord {
+                    title: e.title.clone(),
+                    description: e.description.clone(),
+                    url: e.url.clone(),
+                })
+                .collect(),
+            mentions: m.mentions.iter().map(|u| u.id.0).collect(),
+            mention_roles: m.mention_roles.iter().map(|r| r.0).collect(),
+            reference: m.message_reference.as_ref().map(|r| ReferenceRecord {
+                message_id: r.message_id.map(|v| v.0),
+                channel_id: r.channel_id.0,
+                guild_id: r.guild_id.map(|v| v.0),
+            }),
+        }
+    }
+}
+
+impl MessageRecordV1 {
+    /// Rebuilds a serenity Message from the record. Anything that isn't stored is
+    /// left empty, mentioned users only have their id
+    pub fn to_message(&self) -> Result<Message, Error> {
+        let user = |id: u64, name: &str, discriminator: u16, bot: bool| json!({
+            "id": id.to_string(),
+            "username": name,
+            "discriminator": format!("{:04}", discriminator),
+            "avatar": null,
+            "bot": bot,
+        });
+
+        let value = json!({
+            "id": self.id.to_string(),
+            "channel_id": self.channel_id.to_string(),
+            "guild_id": self.guild_id.map(|v| v.to_string()),
+            "author": user(self.author.id, &self.author.name, self.author.discriminator, self.author.bot),
+            "content": self.content,
+            "timestamp": self.timestamp,
+            "edited_timestamp": self.edited_timestamp,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": self
+                .mentions
+                .iter()
+                .map(|id| user(*id, "", 0, false))
+                .collect::<Vec<_>>(),
+            "mention_roles": self
+                .mention_roles
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>(),
+            "attachments": self
+                .attachments
+                .iter()
+                .map(|a| json!({
+                    "id": a.id.to_string(),
+                    "filename": a.filename,
+                    "url": a.url,
+                    "proxy_url": a.url,
+                    "content_type": a.content_type,
+                    "size": a.size,
+                }))
+                .collect::<Vec<_>>(),
+            "embeds": self
+                .embeds
+                .iter()
+                .map(|e| json!({
+                    "title": e.title,
+                    "description": e.description,
+                    "url": e.url,
+                }))
+                .collect::<Vec<_>>(),
+            "pinned": false,
+            "type": 0,
+            "message_reference": self.reference.as_ref().map(|r| json!({
+                "message_id": r.message_id.map(|v| v.to_string()),
+                "channel_id": r.channel_id.to_string(),
+                "guild_id": r.guild_id.map(|v| v.to_string()),
+            })),
+        });
+
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// Encodes the message as the latest record version
+pub fn encode_message(message: &Message) -> Result<Vec<u8>, Error> {
+    let mut body = vec![MESSAGE_RECORD_V1];
+    rmp_serde::encode::write(&mut body, &MessageRecordV1::from(message))?;
+    Ok(body)
+}
+
+/// Decodes a stored message body in any of the formats
+pub fn decode_message(body: &[u8]) -> Result<Message, Error> {
+    match body.first() {
+        Some(&LEGACY_JSON_START) => Ok(serde_json::from_slice(body)?),
+        Some(&MESSAGE_RECORD_V1) => rmp_serde::from_slice::<MessageRecordV1>(&body[1..])?.to_message(),
+        Some(v) => Err(anyhow::anyhow!("Unknown message record version ({v})"))?,
+        None => Err(anyhow::anyhow!("Empty message body"))?,
+    }
+}
+
+/// True if the body is in an older format than `encode_message` produces
+pub fn is_outdated(body: &[u8]) -> bool {
+    body.first() != Some(&MESSAGE_RECORD_V1)
+}
+
+/// Re-encodes the body as the latest record version if it isn't already
+pub fn upgrade(body: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if is_outdated(&body) {
+        encode_message(&decode_message(&body)?)
+    } else {
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> MessageRecordV1 {
+        MessageRecordV1 {
+            id: 1000,
+            channel_id: 2,
+            guild_id: Some(1),
+            author: AuthorRecord {
+                id: 3,
+                name: "someone".to_string(),
+                discriminator: 42,
+                bot: false,
+            },
+            content: "Hello **world**".to_string(),
+            timestamp: "2023-01-01T00:00:00Z".to_string(),
+            edited_timestamp: Some("2023-01-01T00:05:00Z".to_string()),
+            attachments: vec![AttachmentRecord {
+                id: 5,
+                filename: "cat.png".to_string(),
+                url: "https://cdn.discordapp.com/attachments/2/5/cat.png".to_string(),
+                content_type: Some("image/png".to_string()),
+                size: 1234,
+            }],
+            embeds: vec![EmbedRecord {
+                title: Some("A title".to_string()),
+                description: Some("A description".to_string()),
+                url: None,
+            }],
+            mentions: vec![4],
+            mention_roles: vec![6],
+            reference: Some(ReferenceRecord {
+                message_id: Some(999),
+                channel_id: 2,
+                guild_id: Some(1),
+            }),
+        }
+    }
+
+    #[test]
+    fn message_round_trips_through_a_record() {
+        let message = record().to_message().unwrap();
+        let body = encode_message(&message).unwrap();
+        assert!(!is_outdated(&body));
+        let decoded = decode_message(&body).unwrap();
+
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.author.id, message.author.id);
+        assert_eq!(decoded.content, message.content);
+        assert_eq!(decoded.timestamp, message.timestamp);
+        assert_eq!(decoded.edited_timestamp, message.edited_timestamp);
+
+        assert_eq!(decoded.attachments.len(), 1);
+        assert_eq!(decoded.attachments[0].id, message.attachments[0].id);
+        assert_eq!(decoded.attachments[0].filename, "cat.png");
+        assert_eq!(decoded.attachments[0].url, message.attachments[0].url);
+        assert_eq!(decoded.attachments[0].content_type.as_deref(), Some("image/png"));
+        assert_eq!(decoded.attachments[0].size, 1234);
+
+        assert_eq!(decoded.embeds.len(), 1);
+        assert_eq!(decoded.embeds[0].title.as_deref(), Some("A title"));
+        assert_eq!(decoded.embeds[0].description.as_deref(), Some("A description"));
+        assert_eq!(decoded.embeds[0].url, None);
+
+        assert_eq!(MessageRecordV1::from(&decoded), MessageRecordV1::from(&message));
+    }
+
+    #[test]
+    fn legacy_json_bodies_are_read_and_upgraded() {
+        let message = record().to_message().unwrap();
+        let json = serde_json::to_vec(&message).unwrap();
+        assert!(is_outdated(&json));
+        assert_eq!(decode_message(&json).unwrap().content, message.content);
+
+        let upgraded = upgrade(json).unwrap();
+        assert!(!is_outdated(&upgraded));
+        assert_eq!(
+            MessageRecordV1::from(&decode_message(&upgraded).unwrap()),
+            MessageRecordV1::from(&message)
+        );
+        // Already the latest version so it's left as it is
+        assert_eq!(upgrade(upgraded.clone()).unwrap(), upgraded);
+    }
+
+    #[test]
+    fn unknown_and_empty_bodies_are_errors() {
+        assert!(decode_message(&[]).is_err());
+        assert!(decode_message(&[MESSAGE_RECORD_V1 + 1, 0x80]).is_err());
+    }
+}
@@ -1,25 +1,208 @@
 pub mod queries;
 pub mod background_jobs;
+pub mod message_record;
 mod db_command;
-use std::{ffi::c_int, sync::Once, time::Duration};
+mod chunk_cache;
+mod compression_worker;
+mod backup;
+#[cfg(test)]
+mod test_utils;
+use std::{ffi::c_int, fmt::Display, path::{Path, PathBuf}, sync::Once, time::Duration};
 
 pub use db_command::*;
+pub use chunk_cache::*;
+pub use compression_worker::*;
+pub use backup::*;
+use chrono::Utc;
 use include_dir::{include_dir, Dir};
-use rusqlite::{Connection, OpenFlags, TransactionBehavior};
-use rusqlite_migration::Migrations;
+use rusqlite::{backup::Progress, params, Connection, DatabaseName, OpenFlags, TransactionBehavior};
+use rusqlite_migration::{Migrations, SchemaVersion};
+use serde::Serialize;
 use tokio::{sync::oneshot, task::JoinHandle, time::Instant};
 use tracing::{debug, error, info, instrument, span, trace, warn, Level};
-use crate::{db::queries::*, Error};
+use crate::{db::queries::*, ensure, Error, GuildId, MessageId, UserId};
 
 static MIGRATIONS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/migrations");
 
 const MESSAGE_LOG_CHUNK_SIZE: u64 = 1024 * 100;
 const COMPRESSION_LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+// Old chunks are rarely read so they're worth the extra CPU to make smaller
+const COLD_COMPRESSION_LEVEL: i32 = 19;
+// How many decompressed chunks the DB task keeps in memory (up to ~100KiB each)
+const CHUNK_CACHE_CAPACITY: usize = 32;
+// How many bad chunks are quarantined per transaction by a repair
+const REPAIR_CHUNK_BATCH_SIZE: usize = 8;
+
+/// A migration from the migrations directory. The version is its 1 based position,
+/// which is what rusqlite_migration stores in `user_version`
+#[derive(Debug, Clone)]
+pub struct MigrationFile {
+    pub version: usize,
+    pub name: String,
+    pub up: &'static str,
+    /// None if the migration can't be reverted
+    pub down: Option<&'static str>,
+}
+
+/// One migration that would be run to get the database to another version
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationStep {
+    pub version: usize,
+    pub name: String,
+    pub down: bool,
+    pub sql: &'static str,
+}
+
+impl Display for MigrationStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "-- {} {} ({})\n{}",
+            self.version,
+            self.name,
+            if self.down { "down" } else { "up" },
+            self.sql
+        )
+    }
+}
+
+/// Loads the `up.sql` and optional `down.sql` of each migration, in order. It's the
+/// same as `get_migrations` loads but with the SQL visible so migrations can be planned
+/// and printed. A migration without a `down.sql` can't be reverted
+pub fn get_migration_files() -> Result<Vec<MigrationFile>, Error> {
+    let mut dirs = MIGRATIONS_DIR.dirs().collect::<Vec<_>>();
+    dirs.sort_by(|a, b| a.path().cmp(b.path()));
+
+    dirs.into_iter()
+        .enumerate()
+        .map(|(i, dir)| -> Result<MigrationFile, Error> {
+            let version = i + 1;
+            let name = dir
+                .path()
+                .file_name()
+                .and_then(|v| v.to_str())
+                .unwrap_or_default()
+                .to_string();
+            ensure!(
+                name.starts_with(&format!("{:02}-", version)),
+                "Migration {name} is out of sequence, expected it to be {version}"
+            );
+
+            let read = |file: &str| -> Result<Option<&'static str>, Error> {
+                dir.get_file(dir.path().join(file))
+                    .map(|f| f
+                        .contents_utf8()
+                        .ok_or_else(|| anyhow::anyhow!("Migration {name}/{file} isn't valid UTF-8")))
+                    .transpose()
+                    .map_err(Error::from)
+            };
+            let up = read("up.sql")?
+                .ok_or_else(|| anyhow::anyhow!("Migration {name} is missing up.sql"))?;
+            let down = read("down.sql")?;
+
+            Ok(MigrationFile { version, name, up, down })
+        })
+        .collect()
+}
 
 pub fn get_migrations() -> Result<Migrations<'static>, Error> {
     Ok(Migrations::from_directory(&MIGRATIONS_DIR)?)
 }
 
+/// The version the database is migrated to. 0 if no migrations have been run
+pub fn current_migration_version(con: &Connection) -> Result<usize, Error> {
+    Ok(match get_migrations()?.current_version(con)? {
+        SchemaVersion::NoneSet => 0,
+        SchemaVersion::Inside(v) | SchemaVersion::Outside(v) => v.get(),
+    })
+}
+
+/// Works out which migrations need to be run, and in which direction, to get from
+/// `current` to `target`. Going down fails before anything is run if any of the
+/// migrations can't be reverted
+pub fn plan_migration(current: usize, target: usize) -> Result<Vec<MigrationStep>, Error> {
+    let files = get_migration_files()?;
+    ensure!(
+        current <= files.len(),
+        "The database is at version {current} which is newer than the latest migration ({})",
+        files.len()
+    );
+    ensure!(
+        target <= files.len(),
+        "There is no migration {target}, the latest is {}",
+        files.len()
+    );
+
+    if target >= current {
+        Ok(files[current..target]
+            .iter()
+            .map(|f| MigrationStep {
+                version: f.version,
+                name: f.name.clone(),
+                down: false,
+                sql: f.up,
+            })
+            .collect())
+    } else {
+        let irreversible = files[target..current]
+            .iter()
+            .filter(|f| f.down.is_none())
+            .collect::<Vec<_>>();
+        if let Some(lowest) = irreversible.last() {
+            Err(anyhow::anyhow!(
+                "Can't migrate down to {target}, these migrations can't be reverted: {}. \
+                The lowest version this database can go down to is {}, restore a backup \
+                from before them instead",
+                irreversible.iter().map(|f| f.name.as_str()).collect::<Vec<_>>().join(", "),
+                lowest.version,
+            ))?;
+        }
+
+        Ok(files[target..current]
+            .iter()
+            .rev()
+            .filter_map(|f| f.down.map(|down| MigrationStep {
+                version: f.version,
+                name: f.name.clone(),
+                down: true,
+                sql: down,
+            }))
+            .collect())
+    }
+}
+
+/// Copies the database next to itself before it's migrated so a bad migration can be
+/// undone with `restore_database`. Returns where the copy was written or None if the
+/// database isn't a file
+#[instrument(skip(con))]
+pub fn backup_before_migration(con: &Connection, version: usize) -> Result<Option<PathBuf>, Error> {
+    let path = match con.path() {
+        Some(path) if path.as_os_str().len() > 0 => path.to_owned(),
+        _ => return Ok(None),
+    };
+    let file_name = path
+        .file_name()
+        .and_then(|v| v.to_str())
+        .unwrap_or("gagbot.sqlite");
+    let backup_path = path.with_file_name(format!(
+        "{file_name}.pre-migration-v{version}-{}",
+        Utc::now().format("%Y%m%dT%H%M%S")
+    ));
+
+    // Unlike copying the file this includes anything still in the WAL
+    con.execute("VACUUM INTO ?1", params![backup_path.to_string_lossy()])?;
+    Ok(Some(backup_path))
+}
+
+/// Replaces the contents of the database with the backup. Nothing else should have
+/// the database open while this runs
+#[instrument(skip(con))]
+pub fn restore_database(con: &mut Connection, backup_path: &Path) -> Result<(), Error> {
+    ensure!(backup_path.is_file(), "Backup ({}) doesn't exist", backup_path.display());
+    con.restore(DatabaseName::Main, backup_path, None::<fn(Progress)>)?;
+    Ok(())
+}
+
 fn sqlite_tracing_callback(sqlite_code: c_int, msg: &str) {
     use rusqlite::ffi;
     let err_code = ffi::Error::new(sqlite_code);
@@ -64,6 +247,12 @@ pub fn open_database(connection_string: &str, create: bool, run_migrations: bool
         let migrations = get_migrations()?;
         { 
             let _span = span!(Level::INFO, "Running migrations").entered();
+            let current = current_migration_version(&con)?;
+            if current > 0 && current < get_migration_files()?.len() {
+                if let Some(backup_path) = backup_before_migration(&con, current)? {
+                    info!("Backed up the database to {} before migrating", backup_path.display());
+                }
+            }
             migrations.to_latest(&mut con)?;
         }
     }
@@ -78,6 +267,21 @@ pub fn open_database(connection_string: &str, create: bool, run_migrations: bool
     Ok(con)
 }
 
+/// Opens a second connection to an existing database that can only read from it.
+/// The database must already be in WAL mode (`open_database` sets it) so reads on
+/// this connection don't block the writer
+#[instrument]
+pub fn open_read_only_database(connection_string: &str) -> Result<Connection, Error> {
+    let open_flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+
+    let mut con = Connection::open_with_flags(connection_string, open_flags)?;
+    con.profile(Some(sqlite_connection_profiling_callback));
+
+    Ok(con)
+}
+
 /// Runs an optimize on the database. Should be run periodically to keep the
 /// database running optimally. It should be very fast if run regularly
 #[instrument(skip(con))]
@@ -108,14 +312,87 @@ pub fn vacuum_database(con: &Connection) -> Result<Duration, Error> {
     Ok(start.elapsed())
 }
 
-// Compress database contents (currently just the message log)
+// Recompresses a chunk of messages older than the cutoff at the cold compression level
+#[instrument(skip(con))]
+pub fn recompress_cold_chunks(con: &mut Connection, cutoff: MessageId, merge: bool) -> Result<(Duration, bool), Error> {
+    let start = Instant::now();
+    let more = message_log::recompress_cold_chunk(con, cutoff, merge)?;
+    Ok((start.elapsed(), more))
+}
+
+// Deletes the message log entries of a guild that are older than its retention period
+#[instrument(skip(con))]
+pub fn apply_retention(con: &mut Connection, guild_id: GuildId, cutoff: MessageId) -> Result<(Duration, bool), Error> {
+    let start = Instant::now();
+    let more = message_log::apply_retention(con, guild_id, cutoff)?;
+    Ok((start.elapsed(), more))
+}
+
+// Deletes a batch of what's stored about the user's messages, in one guild or all of them.
+// The message counts go once the message log is done. Returns true if there's more to do
+#[instrument(skip(con))]
+pub fn forget_user(con: &mut Connection, guild_id: Option<GuildId>, user_id: UserId) -> Result<(ForgetUserReceipt, bool), Error> {
+    let (message_log, more) = message_log::forget_user(con, guild_id, user_id)?;
+    let message_counts = if more {
+        0
+    } else {
+        message_count::delete_user(con, guild_id, user_id)?
+    };
+    Ok((ForgetUserReceipt { user_id, guild_id, message_log, message_counts }, more))
+}
+
+// Runs sqlite's integrity check and verifies every compressed chunk. It only reads so it
+// can be run on a read only connection without holding up the DB task
+#[instrument(skip(con))]
+pub fn verify_database(con: &Connection) -> Result<(Duration, VerifyReport), Error> {
+    let start = Instant::now();
+    let integrity_check = con
+        .prepare("PRAGMA integrity_check")?
+        .query_map((), |r| r.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let report = VerifyReport {
+        integrity_check,
+        ..message_log::verify_compressed_chunks(con)?
+    };
+    Ok((start.elapsed(), report))
+}
+
+// Quarantines the bad chunks found by `verify_database` and fixes up message_index. The
+// verify background job does the same a batch per DbCommand so it doesn't hold up the
+// DB task
+#[instrument(skip(con))]
+pub fn repair_database(con: &mut Connection, chunk_ids: &[u64]) -> Result<RepairReceipt, Error> {
+    let mut receipt = RepairReceipt::default();
+    for batch in chunk_ids.chunks(REPAIR_CHUNK_BATCH_SIZE) {
+        receipt.add(message_log::repair_compressed_chunks(con, batch)?);
+    }
+    let mut after = Some(0);
+    while let Some(a) = after {
+        let (batch, next) = message_log::reassign_index_entries(con, a)?;
+        receipt.add(batch);
+        after = next;
+    }
+    Ok(receipt)
+}
+
+// Adds one of the chunks compressed before the search index existed to it. The message
+// record conversion is done by the compression worker
 #[instrument(skip(con))]
-pub fn compress_database(con: &mut Connection) -> Result<(Duration, bool), Error> {
+pub fn backfill_search_index(con: &mut Connection) -> Result<(Duration, bool), Error> {
     let start = Instant::now();
-    let more = message_log::compress(con)?;
+    let more = message_log::backfill_chunk(con)?;
     Ok((start.elapsed(), more))
 }
 
+// Fills in data that didn't exist when old message chunks were compressed
+#[instrument(skip(con))]
+pub fn backfill_compressed_chunks(con: &mut Connection) -> Result<(Duration, bool), Error> {
+    let start = Instant::now();
+    let more_search = message_log::backfill_chunk(con)?;
+    let more_records = message_log::convert_chunk_to_records(con)?;
+    Ok((start.elapsed(), more_search || more_records))
+}
+
 pub fn spawn_db_task(mut db_con: Connection, receiver: CommandReceiver) -> JoinHandle<Result<(), Error>> {
     fn respond<T, E>(respond_to: oneshot::Sender<Result<T, E>>, response: Result<T, E>, cmd_name: &str) -> Result<(), Error> {
         respond_to.send(response)
@@ -125,6 +402,8 @@ pub fn spawn_db_task(mut db_con: Connection, receiver: CommandReceiver) -> JoinH
 
     tokio::task::spawn_blocking(move || {
         debug!("DB TASK: started");
+        let mut chunk_cache = ChunkCache::new(CHUNK_CACHE_CAPACITY);
+        let mut close_respond_to = None;
         loop {
             match receiver.recv() {
                 // The only error it returns is Disconnected (which we use to shut down)
@@ -134,7 +413,13 @@ pub fn spawn_db_task(mut db_con: Connection, receiver: CommandReceiver) -> JoinH
                     let _span = span!(Level::INFO, "DB TASK", cmd = cmd_name).entered();
                     match cmd {
                         DbCommand::GetCompressionState { respond_to } => {
-                            respond(respond_to, message_log::get_compression_state(&db_con), &cmd_name)?;
+                            let state = message_log::get_compression_state(&db_con)
+                                .map(|state| CompressionState {
+                                    chunk_cache_hits: chunk_cache.hits(),
+                                    chunk_cache_misses: chunk_cache.misses(),
+                                    ..state
+                                });
+                            respond(respond_to, state, &cmd_name)?;
                         },
                         DbCommand::Optimize { respond_to } => {
                             respond(respond_to, optimize_database(&db_con), &cmd_name)?;
@@ -142,8 +427,40 @@ pub fn spawn_db_task(mut db_con: Connection, receiver: CommandReceiver) -> JoinH
                         DbCommand::Vacuum { respond_to } => {
                             respond(respond_to, vacuum_database(&db_con), &cmd_name)?;
                         },
-                        DbCommand::Compress { respond_to } => {
-                            respond(respond_to, compress_database(&mut db_con), &cmd_name)?;
+                        DbCommand::Close { respond_to } => {
+                            // Anything sent after this is dropped along with the receiver
+                            close_respond_to = Some((respond_to, cmd_name.clone()));
+                            break;
+                        },
+                        DbCommand::CommitChunk { chunk, respond_to } => {
+                            respond(respond_to, message_log::commit_chunk(&mut db_con, chunk), &cmd_name)?;
+                        },
+                        DbCommand::CommitRewrittenChunk { chunk, respond_to } => {
+                            respond(respond_to, message_log::commit_rewritten_chunk(&mut db_con, chunk), &cmd_name)?;
+                        },
+                        DbCommand::ApplyRetention { guild_id, cutoff, respond_to } => {
+                            respond(respond_to, apply_retention(&mut db_con, guild_id, cutoff), &cmd_name)?;
+                        },
+                        DbCommand::RepairChunks { chunk_ids, respond_to } => {
+                            respond(respond_to, message_log::repair_compressed_chunks(&mut db_con, &chunk_ids), &cmd_name)?;
+                        },
+                        DbCommand::ReassignIndexEntries { after, respond_to } => {
+                            respond(respond_to, message_log::reassign_index_entries(&mut db_con, after), &cmd_name)?;
+                        },
+                        DbCommand::GetConfigStringAllGuilds { key, respond_to } => {
+                            respond(respond_to, config::get_all_guilds(&db_con, key), &cmd_name)?;
+                        },
+                        DbCommand::GetUserMessages { guild_id, user_id, after, limit, respond_to } => {
+                            respond(respond_to, message_log::get_by_user(&db_con, &mut chunk_cache, guild_id, user_id, after, limit), &cmd_name)?;
+                        },
+                        DbCommand::GetUserMessageCounts { guild_id, user_id, respond_to } => {
+                            respond(respond_to, message_count::get_by_user(&db_con, guild_id, user_id), &cmd_name)?;
+                        },
+                        DbCommand::ForgetUser { guild_id, user_id, respond_to } => {
+                            respond(respond_to, forget_user(&mut db_con, guild_id, user_id), &cmd_name)?;
+                        },
+                        DbCommand::BackfillSearchIndex { respond_to } => {
+                            respond(respond_to, backfill_search_index(&mut db_con), &cmd_name)?;
                         },
                         DbCommand::GetConfigString { guild_id, key, respond_to } => {
                             respond(respond_to, config::get(&db_con, guild_id, key), &cmd_name)?;
@@ -187,21 +504,66 @@ pub fn spawn_db_task(mut db_con: Connection, receiver: CommandReceiver) -> JoinH
                         DbCommand::UpdateInteractionRoleSet { guild_id, name, description, channel_id, message_id, exclusive, timestamp, respond_to } => {
                             respond(respond_to, interaction_roles::update(&db_con, guild_id, name, description, channel_id, message_id, exclusive, timestamp), &cmd_name)?;
                         },
+                        DbCommand::UpdateInteractionRoleStyle { guild_id, name, style, min_values, max_values, timestamp, respond_to } => {
+                            respond(respond_to, interaction_roles::update_style(&db_con, guild_id, name, style, min_values, max_values, timestamp), &cmd_name)?;
+                        },
+                        DbCommand::CreatePendingConfirmation { guild_id, requester_id, action, payload, expires, timestamp, respond_to } => {
+                            respond(respond_to, confirmations::create(&mut db_con, guild_id, requester_id, action, payload, expires, timestamp), &cmd_name)?;
+                        },
+                        DbCommand::GetPendingConfirmation { id, respond_to } => {
+                            respond(respond_to, confirmations::get(&db_con, id), &cmd_name)?;
+                        },
+                        DbCommand::DeletePendingConfirmation { id, respond_to } => {
+                            respond(respond_to, confirmations::delete(&db_con, id), &cmd_name)?;
+                        },
+                        DbCommand::GetInteractionCustomId { id, respond_to } => {
+                            respond(respond_to, custom_ids::get(&db_con, id), &cmd_name)?;
+                        },
+                        DbCommand::GetOrCreateInteractionCustomId { guild_id, kind, set_name, choice, extra, timestamp, respond_to } => {
+                            respond(respond_to, custom_ids::get_or_create(&mut db_con, guild_id, kind, set_name, choice, extra, timestamp), &cmd_name)?;
+                        },
+                        DbCommand::GetInteractionRoles { guild_id, respond_to } => {
+                            respond(respond_to, interaction_roles::get_all(&db_con, guild_id), &cmd_name)?;
+                        },
                         DbCommand::GetInteractionRole { guild_id, name, respond_to } => {
                             respond(respond_to, interaction_roles::get(&db_con, guild_id, name ), &cmd_name)?;
                         },
                         DbCommand::UpdateInteractionRoleChoice { guild_id, set_name, choice, emoji, role_id, timestamp, respond_to } => {
                             respond(respond_to, interaction_roles::update_choice(&db_con, guild_id, set_name, choice, emoji, role_id, timestamp ), &cmd_name)?;
                         },
+                        DbCommand::UpdateInteractionRoleChoiceRequirements { guild_id, set_name, choice, required_role_id, forbidden_role_id, min_message_count, timestamp, respond_to } => {
+                            respond(respond_to, interaction_roles::update_choice_requirements(&db_con, guild_id, set_name, choice, required_role_id, forbidden_role_id, min_message_count, timestamp), &cmd_name)?;
+                        },
+                        DbCommand::DeleteInteractionRoleSet { guild_id, name, respond_to } => {
+                            respond(respond_to, interaction_roles::delete(&mut db_con, guild_id, name), &cmd_name)?;
+                        },
+                        DbCommand::DeleteInteractionRoleChoice { guild_id, set_name, choice, respond_to } => {
+                            respond(respond_to, interaction_roles::delete_choice(&mut db_con, guild_id, set_name, choice), &cmd_name)?;
+                        },
+                        DbCommand::GetReactionRoleTemps { guild_id, respond_to } => {
+                            respond(respond_to, reaction_roles::get_all(&db_con, guild_id), &cmd_name)?;
+                        },
+                        DbCommand::GetReactionRoleTempByMessage { guild_id, channel_id, message_id, respond_to } => {
+                            respond(respond_to, reaction_roles::get_by_message(&db_con, guild_id, channel_id, message_id), &cmd_name)?;
+                        },
+                        DbCommand::DeleteReactionRoleTemp { guild_id, name, respond_to } => {
+                            respond(respond_to, reaction_roles::delete(&mut db_con, guild_id, name), &cmd_name)?;
+                        },
                         DbCommand::LogMessage { message_id, timestamp, type_, message, respond_to } => {
                             respond(respond_to, message_log::log(&mut db_con, message_id, timestamp, type_, message), &cmd_name)?;
                         },
+                        DbCommand::GetLogMessagesByChannel { guild_id, channel_id, after_id, before_id, limit, respond_to } => {
+                            respond(respond_to, message_log::get_by_channel(&db_con, &mut chunk_cache, guild_id, channel_id, after_id, before_id, limit), &cmd_name)?;
+                        },
+                        DbCommand::SearchMessages { query, limit, offset, respond_to } => {
+                            respond(respond_to, message_log::search(&db_con, &mut chunk_cache, query, limit, offset), &cmd_name)?;
+                        },
                         DbCommand::GetLogMessages { message_id, respond_to } => {
-                            respond(respond_to, message_log::get(&db_con, message_id), &cmd_name)?;
+                            respond(respond_to, message_log::get(&db_con, &mut chunk_cache, message_id), &cmd_name)?;
+                        },
+                        DbCommand::GetUserFromLogMessages{ guild_id, channel_id, message_id, respond_to } => {
+                            respond(respond_to, message_log::get_user(&db_con, guild_id, channel_id, message_id), &cmd_name)?;
                         },
-                        // DbCommand::GetUserFromLogMessages{ guild_id, channel_id, message_id, respond_to } => {
-                        //     respond(respond_to, message_log::get_user(&sqlite_con, guild_id, channel_id, message_id), &cmd_name)?;
-                        // },
                         DbCommand::GetTableBytesAndCount { respond_to } => {
                             respond(respond_to, queries::get_table_size_in_bytes(&db_con), &cmd_name)?;
                         }
@@ -211,8 +573,82 @@ pub fn spawn_db_task(mut db_con: Connection, receiver: CommandReceiver) -> JoinH
         }
         debug!("DB TASK: exiting");
 
-        close_database(db_con)?;
+        let result = close_database(db_con);
+        match close_respond_to {
+            Some((respond_to, cmd_name)) => respond(respond_to, result, &cmd_name)?,
+            None => result?,
+        }
 
         Ok::<_, Error>(())
     })
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version_of(name: &str) -> usize {
+        get_migration_files()
+            .unwrap()
+            .iter()
+            .find(|f| f.name == name)
+            .unwrap()
+            .version
+    }
+
+    /// Every table, index and trigger in the database. The SQL has its whitespace
+    /// collapsed as dropping a column can leave the surrounding whitespace different
+    fn schema(con: &Connection) -> Vec<(String, String, Option<String>)> {
+        con.prepare("SELECT type, name, sql FROM sqlite_master ORDER BY name")
+            .unwrap()
+            .query_map((), |r| Ok((
+                r.get(0)?,
+                r.get(1)?,
+                r.get::<_, Option<String>>(2)?
+                    .map(|sql| sql.split_whitespace().collect::<Vec<_>>().join(" ")),
+            )))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn every_down_migration_reverts_its_up() {
+        let files = get_migration_files().unwrap();
+        let migrations = get_migrations().unwrap();
+        let mut con = Connection::open_in_memory().unwrap();
+
+        for f in files.iter() {
+            let before = schema(&con);
+            migrations.to_version(&mut con, f.version).unwrap();
+            if f.down.is_some() {
+                migrations.to_version(&mut con, f.version - 1).unwrap();
+                assert_eq!(current_migration_version(&con).unwrap(), f.version - 1);
+                assert_eq!(schema(&con), before, "{} down.sql doesn't revert its up.sql", f.name);
+                migrations.to_version(&mut con, f.version).unwrap();
+            }
+        }
+        assert_eq!(current_migration_version(&con).unwrap(), files.len());
+    }
+
+    #[test]
+    fn plan_stops_at_irreversible_migrations() {
+        let dictionary = version_of("18-message_dictionary");
+        let record = version_of("21-message_record");
+
+        let plan = plan_migration(record - 1, dictionary).unwrap();
+        assert!(plan.iter().all(|s| s.down));
+        assert_eq!(
+            plan.iter().map(|s| s.version).collect::<Vec<_>>(),
+            ((dictionary + 1)..record).rev().collect::<Vec<_>>()
+        );
+
+        let e = plan_migration(record + 1, dictionary - 1).unwrap_err().to_string();
+        assert!(e.contains("18-message_dictionary"), "{e}");
+        assert!(e.contains("21-message_record"), "{e}");
+        assert!(e.contains(&format!("go down to is {record}")), "{e}");
+
+        let plan = plan_migration(dictionary - 1, record).unwrap();
+        assert!(plan.iter().all(|s| !s.down));
+        assert_eq!(plan.len(), record - dictionary + 1);
+    }
+}
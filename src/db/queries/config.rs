@@ -4,7 +4,10 @@ use poise::{serenity_prelude::Timestamp, ChoiceParameter};
 use rusqlite::{params, types::ToSqlOutput, Connection, OptionalExtension, ToSql};
 use tracing::debug;
 
-use crate::{ChannelId, GuildId, ErrorContext, Error};
+use crate::{ensure, ChannelId, GuildId, ErrorContext, Error};
+
+/// Longer than anything will have been logged for, it stops the retention cutoff overflowing
+pub const LOGGING_RETENTION_MAX_DAYS: i64 = 36_500;
 
 #[derive(Debug, Clone, Copy, ChoiceParameter)]
 pub enum ConfigKey {
@@ -44,6 +47,10 @@ pub enum ConfigKey {
     LoggingErrors,
     #[name = "logging.voice_activity"]
     LoggingVoiceActivity,
+    #[name = "logging.retention_days"]
+    LoggingRetentionDays,
+    #[name = "rolemenu.auto_republish"]
+    RoleMenuAutoRepublish,
 }
 
 impl ConfigKey {
@@ -68,6 +75,7 @@ impl ConfigKey {
             ConfigKey::LoggingJoiningAndLeaving => "Channel to log join and leave events in",
             ConfigKey::LoggingErrors => "Channel to log bot errors in",
             ConfigKey::LoggingVoiceActivity => "Channel to log member voice activity in",
+            ConfigKey::LoggingRetentionDays => "How many days to keep the message log for. Older messages are deleted every night. Leave unset to keep them forever",
             ConfigKey::GreetRole => "Role given by a mod as part of the add member process",
             ConfigKey::GreetDefaultRole => "Role given automatically when a member joins the server",
             ConfigKey::PromoteJuniorRole => "Role given once an introduction has been done",
@@ -77,10 +85,27 @@ impl ConfigKey {
             ConfigKey::PromoteNewChatMinMessages => "How many messages new members have to post in into channel",
             ConfigKey::PromoteJuniorChatMinMessages => "How many messages juniors have to post to show they are active",
             ConfigKey::PromoteJuniorMinAge => "How long (in days) juniors have to stick around to be promoted",
+            ConfigKey::RoleMenuAutoRepublish => "Set to true to automatically re-post role menus if their message is deleted",
         }
     }
 }
 
+/// Parses a logging.retention_days value, it has to be between 1 and `LOGGING_RETENTION_MAX_DAYS`
+pub fn parse_retention_days(value: &str) -> Result<i64, Error> {
+    let days = value
+        .trim()
+        .parse::<i64>()
+        .with_context(|| format!("{} ({}) isn't a number of days", ConfigKey::LoggingRetentionDays, value))?;
+    ensure!(
+        days >= 1 && days <= LOGGING_RETENTION_MAX_DAYS,
+        "{} must be between 1 and {} days, not {}",
+        ConfigKey::LoggingRetentionDays,
+        LOGGING_RETENTION_MAX_DAYS,
+        days
+    );
+    Ok(days)
+}
+
 impl ToSql for ConfigKey {
     fn to_sql(&self) -> Result<ToSqlOutput, rusqlite::Error> {
         self.name().to_sql()
@@ -133,6 +158,10 @@ pub fn update(
     value: &str,
     timestamp: Timestamp,
 ) -> Result<(), Error> {
+    if let ConfigKey::LoggingRetentionDays = key {
+        parse_retention_days(value)?;
+    }
+
     let mut stmt = db.prepare_cached(
         "INSERT INTO config (guild_id, key, value, last_updated)
                          VALUES (?1, ?2, ?3, ?4)
@@ -232,6 +261,21 @@ where
     Ok(value)
 }
 
+/// Gets the value of `key` for every guild that has it set
+pub fn get_all_guilds(db: &Connection, key: ConfigKey) -> Result<Vec<(GuildId, String)>, Error> {
+    let mut stmt = db.prepare_cached(
+        "SELECT guild_id, value FROM config WHERE key = ?1",
+    )?;
+
+    let values = stmt
+        .query_map(params![key], |r| {
+            Ok((GuildId::from(r.get::<_, u64>(0)?), r.get::<_, String>(1)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(values)
+}
+
 /// Attempts to find the best log channel by looking for each `purposes` value
 /// in sequence Returns None if none of the are configured
 pub fn get_log_channel(
@@ -276,3 +320,26 @@ pub fn get_greet(
 
     Ok(Some((channel.unwrap(), message.unwrap())))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_utils::test_database;
+
+    #[test]
+    fn retention_days_are_validated_when_set() {
+        let db = test_database();
+        let guild_id = GuildId::from(1);
+        let set = |value: &str| update(&db, guild_id, ConfigKey::LoggingRetentionDays, value, Timestamp::now());
+
+        assert!(set("0").is_err());
+        assert!(set("-5").is_err());
+        assert!(set("forever").is_err());
+        assert!(set(&i64::MAX.to_string()).is_err());
+        assert!(set(&(LOGGING_RETENTION_MAX_DAYS + 1).to_string()).is_err());
+        assert_eq!(get::<String>(&db, guild_id, ConfigKey::LoggingRetentionDays).unwrap(), None);
+
+        set("30").unwrap();
+        assert_eq!(get::<i64>(&db, guild_id, ConfigKey::LoggingRetentionDays).unwrap(), Some(30));
+    }
+}
@@ -0,0 +1,140 @@
+use std::str;
+
+use poise::serenity_prelude::Timestamp;
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, ToSql,
+};
+use serde_json::Value;
+use tracing::error;
+
+use crate::{
+    db::queries::{custom_ids::new_id, permissions::Permission},
+    Error, GuildId, UserId,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfirmationAction {
+    /// Payload is empty
+    PermissionPurge,
+    /// Payload is a `commands::purge::MessagePurgeRequest`
+    MessagePurge,
+    /// Payload is the id of the user to forget, as a number
+    ForgetUser,
+}
+
+impl ConfirmationAction {
+    /// The permission the command that requested the confirmation checks. It's checked
+    /// again on confirm in case it was revoked in the meantime
+    pub fn required_permission(&self) -> Permission {
+        match self {
+            ConfirmationAction::PermissionPurge => Permission::PermissionManage,
+            ConfirmationAction::MessagePurge => Permission::MessagePurge,
+            ConfirmationAction::ForgetUser => Permission::MemberForget,
+        }
+    }
+}
+
+impl ToSql for ConfirmationAction {
+    fn to_sql(&self) -> Result<ToSqlOutput, rusqlite::Error> {
+        Ok(match self {
+            ConfirmationAction::PermissionPurge => ToSqlOutput::Borrowed("PERMISSION_PURGE".into()),
+            ConfirmationAction::MessagePurge => ToSqlOutput::Borrowed("MESSAGE_PURGE".into()),
+            ConfirmationAction::ForgetUser => ToSqlOutput::Borrowed("FORGET_USER".into()),
+        })
+    }
+}
+
+impl FromSql for ConfirmationAction {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        if let ValueRef::Text(v) = value {
+            match str::from_utf8(v).map_err(|e| FromSqlError::Other(Box::new(e)))? {
+                "PERMISSION_PURGE" => Ok(ConfirmationAction::PermissionPurge),
+                "MESSAGE_PURGE" => Ok(ConfirmationAction::MessagePurge),
+                "FORGET_USER" => Ok(ConfirmationAction::ForgetUser),
+                e => {
+                    error!("Unexpected enum variant {} for ConfirmationAction", e);
+                    Err(FromSqlError::InvalidType)
+                }
+            }
+        } else {
+            Err(FromSqlError::InvalidType)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingConfirmation {
+    pub id: String,
+    pub guild_id: GuildId,
+    pub requester_id: UserId,
+    pub action: ConfirmationAction,
+    pub payload: Value,
+    /// Unix timestamp after which the confirmation can no longer be accepted
+    pub expires: i64,
+}
+
+/// Stores the confirmation and returns its id. Expired confirmations are cleaned up
+/// at the same time
+pub fn create(
+    db: &mut Connection,
+    guild_id: GuildId,
+    requester_id: UserId,
+    action: ConfirmationAction,
+    payload: Value,
+    expires: i64,
+    timestamp: Timestamp,
+) -> Result<String, Error> {
+    let tx = db.transaction()?;
+
+    tx.prepare_cached("DELETE FROM pending_confirmation WHERE expires < ?1")?
+        .execute(params![timestamp.unix_timestamp()])?;
+
+    let id = new_id();
+    tx.prepare_cached(
+        "INSERT INTO pending_confirmation (id, guild_id, requester_id, action, payload, created, expires)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?
+    .execute(params![
+        &id,
+        guild_id,
+        requester_id,
+        action,
+        payload,
+        &timestamp.to_rfc3339(),
+        expires
+    ])?;
+
+    tx.commit()?;
+
+    Ok(id)
+}
+
+pub fn get(db: &Connection, id: String) -> Result<Option<PendingConfirmation>, Error> {
+    let mut stmt = db.prepare_cached(
+        "SELECT guild_id, requester_id, action, payload, expires
+            FROM pending_confirmation
+            WHERE id = ?1",
+    )?;
+
+    Ok(stmt
+        .query_row(params![&id], |r| {
+            Ok(PendingConfirmation {
+                id: id.clone(),
+                guild_id: GuildId::from(r.get::<_, u64>(0)?),
+                requester_id: UserId::from(r.get::<_, u64>(1)?),
+                action: r.get(2)?,
+                payload: r.get(3)?,
+                expires: r.get(4)?,
+            })
+        })
+        .optional()?)
+}
+
+/// Returns false if it didn't exist, which means it has already been handled
+pub fn delete(db: &Connection, id: String) -> Result<bool, Error> {
+    let mut stmt = db.prepare_cached("DELETE FROM pending_confirmation WHERE id = ?1")?;
+
+    Ok(stmt.execute(params![&id])? > 0)
+}
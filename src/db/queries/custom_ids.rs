@@ -0,0 +1,156 @@
+use std::str;
+
+use poise::serenity_prelude::Timestamp;
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, ToSql,
+};
+use serde_json::Value;
+use tracing::error;
+
+use crate::{Error, GuildId};
+
+/// Long enough that guessing a valid id is impractical while leaving plenty of
+/// room in discord's 100 character custom_id
+const CUSTOM_ID_LEN: usize = 16;
+/// How long the next/previous buttons on search results keep working
+const SEARCH_PAGE_LIFETIME_SECONDS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InteractionKind {
+    /// A button on a role menu. `set_name` and `choice` identify the choice
+    RoleButton,
+    /// The dropdown on a role menu. `set_name` identifies the menu
+    RoleSelect,
+    /// A next/previous button on message search results. `set_name` identifies the
+    /// search, `choice` is the page to show and `extra` has the query
+    SearchPage,
+}
+
+impl ToSql for InteractionKind {
+    fn to_sql(&self) -> Result<ToSqlOutput, rusqlite::Error> {
+        Ok(match self {
+            InteractionKind::RoleButton => ToSqlOutput::Borrowed("ROLE_BUTTON".into()),
+            InteractionKind::RoleSelect => ToSqlOutput::Borrowed("ROLE_SELECT".into()),
+            InteractionKind::SearchPage => ToSqlOutput::Borrowed("SEARCH_PAGE".into()),
+        })
+    }
+}
+
+impl FromSql for InteractionKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        if let ValueRef::Text(v) = value {
+            match str::from_utf8(v).map_err(|e| FromSqlError::Other(Box::new(e)))? {
+                "ROLE_BUTTON" => Ok(InteractionKind::RoleButton),
+                "ROLE_SELECT" => Ok(InteractionKind::RoleSelect),
+                "SEARCH_PAGE" => Ok(InteractionKind::SearchPage),
+                e => {
+                    error!("Unexpected enum variant {} for InteractionKind", e);
+                    Err(FromSqlError::InvalidType)
+                }
+            }
+        } else {
+            Err(FromSqlError::InvalidType)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InteractionCustomId {
+    pub id: String,
+    pub guild_id: GuildId,
+    pub kind: InteractionKind,
+    pub set_name: String,
+    pub choice: String,
+    pub extra: Option<Value>,
+}
+
+/// Generates a new random opaque id
+pub fn new_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CUSTOM_ID_LEN)
+        .map(char::from)
+        .collect()
+}
+
+pub fn get(db: &Connection, id: String) -> Result<Option<InteractionCustomId>, Error> {
+    let mut stmt = db.prepare_cached(
+        "SELECT guild_id, kind, set_name, choice, extra
+            FROM interaction_custom_id
+            WHERE id = ?1",
+    )?;
+
+    Ok(stmt
+        .query_row(params![&id], |r| {
+            Ok(InteractionCustomId {
+                id: id.clone(),
+                guild_id: GuildId::from(r.get::<_, u64>(0)?),
+                kind: r.get(1)?,
+                set_name: r.get(2)?,
+                choice: r.get(3)?,
+                extra: r.get(4)?,
+            })
+        })
+        .optional()?)
+}
+
+/// Gets the id for the payload, creating a new one if it doesn't exist yet. Ids are
+/// reused so re-rendering a menu doesn't leave a trail of dead rows behind. Expired
+/// search page ids are cleaned up at the same time
+pub fn get_or_create(
+    db: &mut Connection,
+    guild_id: GuildId,
+    kind: InteractionKind,
+    set_name: String,
+    choice: String,
+    extra: Option<Value>,
+    timestamp: Timestamp,
+) -> Result<String, Error> {
+    let tx = db.transaction()?;
+
+    // Every search gets its own ids so they'd pile up otherwise
+    let expired = Timestamp::from_unix_timestamp(timestamp.unix_timestamp() - SEARCH_PAGE_LIFETIME_SECONDS)
+        .map_err(|e| anyhow::anyhow!("Invalid search page expiry: {e}"))?;
+    tx.prepare_cached("DELETE FROM interaction_custom_id WHERE kind = ?1 AND created < ?2")?
+        .execute(params![InteractionKind::SearchPage, &expired.to_rfc3339()])?;
+
+    let existing = tx
+        .prepare_cached(
+            "SELECT id FROM interaction_custom_id
+                WHERE guild_id = ?1 AND kind = ?2 AND set_name = ?3 AND choice = ?4",
+        )?
+        .query_row(params![guild_id, kind, &set_name, &choice], |r| {
+            r.get::<_, String>(0)
+        })
+        .optional()?;
+
+    let id = if let Some(id) = existing {
+        tx.prepare_cached("UPDATE interaction_custom_id SET extra = ?2 WHERE id = ?1")?
+            .execute(params![&id, extra])?;
+        id
+    } else {
+        let id = new_id();
+
+        tx.prepare_cached(
+            "INSERT INTO interaction_custom_id (id, guild_id, kind, set_name, choice, extra, created)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?
+        .execute(params![
+            &id,
+            guild_id,
+            kind,
+            set_name,
+            choice,
+            extra,
+            &timestamp.to_rfc3339()
+        ])?;
+        id
+    };
+
+    tx.commit()?;
+
+    Ok(id)
+}
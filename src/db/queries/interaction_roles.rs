@@ -1,16 +1,60 @@
-use poise::serenity_prelude::Timestamp;
-use rusqlite::{params, Connection, OptionalExtension};
+use std::str;
+
+use poise::{serenity_prelude::Timestamp, ChoiceParameter};
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, ToSql,
+};
+use tracing::error;
 
 use crate::{ChannelId, GuildId, MessageId, RoleId, Error};
 
+#[derive(Debug, Clone, Copy, PartialEq, ChoiceParameter)]
+pub enum InteractionRoleStyle {
+    Buttons,
+    Dropdown,
+}
+
+impl ToSql for InteractionRoleStyle {
+    fn to_sql(&self) -> Result<ToSqlOutput, rusqlite::Error> {
+        Ok(match self {
+            InteractionRoleStyle::Buttons => ToSqlOutput::Borrowed("BUTTON".into()),
+            InteractionRoleStyle::Dropdown => ToSqlOutput::Borrowed("SELECT".into()),
+        })
+    }
+}
+
+impl FromSql for InteractionRoleStyle {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        if let ValueRef::Text(v) = value {
+            match str::from_utf8(v).map_err(|e| FromSqlError::Other(Box::new(e)))? {
+                "BUTTON" => Ok(InteractionRoleStyle::Buttons),
+                "SELECT" => Ok(InteractionRoleStyle::Dropdown),
+                e => {
+                    error!("Unexpected enum variant {} for InteractionRoleStyle", e);
+                    Err(FromSqlError::InvalidType)
+                }
+            }
+        } else {
+            Err(FromSqlError::InvalidType)
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct InteractionRole {
     pub guild_id: GuildId,
     pub name: String,
     pub description: Option<String>,
     pub channel_id: ChannelId,
-    pub message_id: MessageId,
+    pub message_id: Option<MessageId>,
     pub exclusive: bool,
+    pub style: InteractionRoleStyle,
+    /// Minimum number of options that must be selected (Dropdown style only)
+    pub min_values: Option<u8>,
+    /// Maximum number of options that can be selected (Dropdown style only)
+    pub max_values: Option<u8>,
     pub choices: Vec<InteractionChoice>,
 }
 
@@ -20,6 +64,12 @@ pub struct InteractionChoice {
     pub choice: String,
     pub emoji: Option<String>,
     pub role_id: RoleId,
+    /// Members must hold this role to pick the choice
+    pub required_role_id: Option<RoleId>,
+    /// Members holding this role can't pick the choice
+    pub forbidden_role_id: Option<RoleId>,
+    /// Members must have posted at least this many messages to pick the choice
+    pub min_message_count: Option<usize>,
 }
 
 pub fn get(
@@ -28,7 +78,8 @@ pub fn get(
     name: String,
 ) -> Result<Option<InteractionRole>, Error> {
     let mut stmt = db.prepare_cached(
-        "SELECT name, description, channel_id, message_id, exclusive FROM interaction_role 
+        "SELECT name, description, channel_id, message_id, exclusive, style, min_values, max_values 
+            FROM interaction_role 
             WHERE guild_id = ?1 AND name = ?2",
     )?;
 
@@ -39,15 +90,19 @@ pub fn get(
                 name: r.get(0)?,
                 description: r.get(1)?,
                 channel_id: r.get(2)?,
-                message_id: MessageId::from(r.get::<_, u64>(3)?),
+                message_id: r.get::<_, Option<u64>>(3)?.map(MessageId::from),
                 exclusive: r.get(4)?,
+                style: r.get(5)?,
+                min_values: r.get(6)?,
+                max_values: r.get(7)?,
                 choices: Vec::new(),
             })
         })
         .optional()?
     {
         let mut stmt = db.prepare_cached(
-            "SELECT choice, emoji, role_id FROM interaction_role_choice 
+            "SELECT choice, emoji, role_id, required_role_id, forbidden_role_id, min_message_count
+                FROM interaction_role_choice 
                 WHERE guild_id = ?1 AND set_name = ?2",
         )?;
 
@@ -57,6 +112,9 @@ pub fn get(
                 choice: r.get(0)?,
                 emoji: r.get(1)?,
                 role_id: RoleId::from(r.get::<_, u64>(2)?),
+                required_role_id: r.get(3)?,
+                forbidden_role_id: r.get(4)?,
+                min_message_count: r.get(5)?,
             })
         })? {
             ir.choices.push(choice?);
@@ -68,6 +126,28 @@ pub fn get(
     }
 }
 
+/// Gets every interaction role set in the guild
+pub fn get_all(db: &Connection, guild_id: GuildId) -> Result<Vec<InteractionRole>, Error> {
+    let mut stmt = db.prepare_cached(
+        "SELECT name FROM interaction_role 
+            WHERE guild_id = ?1
+            ORDER BY name",
+    )?;
+
+    let names = stmt
+        .query_map(params![guild_id], |r| r.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut sets = Vec::with_capacity(names.len());
+    for name in names {
+        if let Some(ir) = get(db, guild_id, name)? {
+            sets.push(ir);
+        }
+    }
+
+    Ok(sets)
+}
+
 pub fn update(
     db: &Connection,
     guild_id: GuildId,
@@ -103,6 +183,34 @@ pub fn update(
     Ok(true)
 }
 
+pub fn update_style(
+    db: &Connection,
+    guild_id: GuildId,
+    name: String,
+    style: InteractionRoleStyle,
+    min_values: Option<u8>,
+    max_values: Option<u8>,
+    timestamp: Timestamp,
+) -> Result<bool, Error> {
+    let mut stmt = db.prepare_cached(
+        "UPDATE interaction_role SET
+                style = ?3,
+                min_values = ?4,
+                max_values = ?5,
+                last_updated = ?6
+            WHERE guild_id = ?1 AND name = ?2 AND last_updated < ?6",
+    )?;
+
+    Ok(stmt.execute(params![
+        guild_id,
+        name,
+        style,
+        min_values,
+        max_values,
+        &timestamp.to_rfc3339()
+    ])? > 0)
+}
+
 pub fn update_choice(
     db: &Connection,
     guild_id: GuildId,
@@ -134,3 +242,91 @@ pub fn update_choice(
 
     Ok(true)
 }
+
+pub fn update_choice_requirements(
+    db: &Connection,
+    guild_id: GuildId,
+    set_name: String,
+    choice: String,
+    required_role_id: Option<RoleId>,
+    forbidden_role_id: Option<RoleId>,
+    min_message_count: Option<usize>,
+    timestamp: Timestamp,
+) -> Result<bool, Error> {
+    let mut stmt = db.prepare_cached(
+        "UPDATE interaction_role_choice SET
+                required_role_id = ?4,
+                forbidden_role_id = ?5,
+                min_message_count = ?6,
+                last_updated = ?7
+            WHERE guild_id = ?1 AND set_name = ?2 AND choice = ?3 AND last_updated < ?7",
+    )?;
+
+    Ok(stmt.execute(params![
+        guild_id,
+        set_name,
+        choice,
+        required_role_id,
+        forbidden_role_id,
+        min_message_count,
+        &timestamp.to_rfc3339()
+    ])? > 0)
+}
+
+pub fn delete(
+    db: &mut Connection,
+    guild_id: GuildId,
+    name: String,
+) -> Result<bool, Error> {
+    let tx = db.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "DELETE FROM interaction_custom_id
+                WHERE guild_id = ?1 AND set_name = ?2",
+        )?;
+        stmt.execute(params![guild_id, &name])?;
+    }
+    {
+        let mut stmt = tx.prepare_cached(
+            "DELETE FROM interaction_role_choice
+                WHERE guild_id = ?1 AND set_name = ?2",
+        )?;
+        stmt.execute(params![guild_id, &name])?;
+    }
+    let deleted = {
+        let mut stmt = tx.prepare_cached(
+            "DELETE FROM interaction_role
+                WHERE guild_id = ?1 AND name = ?2",
+        )?;
+        stmt.execute(params![guild_id, &name])?
+    };
+    tx.commit()?;
+
+    Ok(deleted > 0)
+}
+
+pub fn delete_choice(
+    db: &mut Connection,
+    guild_id: GuildId,
+    set_name: String,
+    choice: String,
+) -> Result<bool, Error> {
+    let tx = db.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "DELETE FROM interaction_custom_id
+                WHERE guild_id = ?1 AND set_name = ?2 AND choice = ?3",
+        )?;
+        stmt.execute(params![guild_id, &set_name, &choice])?;
+    }
+    let deleted = {
+        let mut stmt = tx.prepare_cached(
+            "DELETE FROM interaction_role_choice
+                WHERE guild_id = ?1 AND set_name = ?2 AND choice = ?3",
+        )?;
+        stmt.execute(params![guild_id, &set_name, &choice])?
+    };
+    tx.commit()?;
+
+    Ok(deleted > 0)
+}
@@ -44,3 +44,39 @@ pub fn get(
 
     Ok(count)
 }
+
+/// Gets the user's count for each channel in the guild
+pub fn get_by_user(
+    db: &Connection,
+    guild_id: GuildId,
+    user_id: UserId,
+) -> Result<Vec<(ChannelId, u64)>, Error> {
+    let mut stmt = db.prepare_cached(
+        "SELECT channel_id, message_count FROM message_count 
+        WHERE guild_id=?1 AND user_id=?2
+        ORDER BY channel_id",
+    )?;
+
+    let counts = stmt
+        .query_map(params![guild_id, user_id], |r| {
+            Ok((r.get::<_, ChannelId>(0)?, r.get::<_, u64>(1)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(counts)
+}
+
+/// Deletes the user's counts in one guild or all of them. Returns the number of
+/// channel counts deleted
+pub fn delete_user(
+    db: &Connection,
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+) -> Result<u64, Error> {
+    let mut stmt = db.prepare_cached(
+        "DELETE FROM message_count
+        WHERE user_id = ?1 AND (?2 IS NULL OR guild_id = ?2)",
+    )?;
+
+    Ok(stmt.execute(params![user_id, guild_id])? as u64)
+}
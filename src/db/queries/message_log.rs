@@ -1,15 +1,29 @@
-use std::{collections::HashMap, io::{Cursor, Read, Write}, str};
+use std::{collections::{HashMap, HashSet}, io::{Cursor, Read, Write}, str};
 
 use poise::serenity_prelude::{Message, Timestamp};
 use rusqlite::{
     params,
     types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
-    Connection, ToSql,
+    Connection, OptionalExtension, ToSql,
 };
+use serde_json::{json, Value};
 use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 use zstd::{Decoder, Encoder};
 
-use crate::{db::{CompressionState, COMPRESSION_LEVEL, MESSAGE_LOG_CHUNK_SIZE}, ensure, Error, ErrorContext, MessageId};
+use crate::{db::{message_record, decode_frame_offsets, encode_frame_offsets, scan_frame_offsets, BadChunk, CachedChunk, ChunkCache, CompressionState, RepairReceipt, VerifyReport, COLD_COMPRESSION_LEVEL, COMPRESSION_LEVEL, MESSAGE_LOG_CHUNK_SIZE}, ensure, ChannelId, Error, ErrorContext, GuildId, MessageId, UserId};
+
+/// How many message_index rows are removed in one go by `apply_retention` and
+/// `forget_user`. This keeps each transaction short so deleting a lot of the log
+/// doesn't block everything else
+const DELETE_BATCH_SIZE: u64 = 5000;
+/// How many message_index_ids `reassign_index_entries` checks in one go
+const REASSIGN_BATCH_SIZE: u64 = 20_000;
+/// How many message bodies a dictionary is trained on
+const DICTIONARY_SAMPLE_COUNT: u64 = 10_000;
+/// Training on fewer than this doesn't produce a useful dictionary
+const DICTIONARY_MIN_SAMPLE_COUNT: usize = 100;
+/// The zstd docs suggest ~100KiB as a reasonable dictionary size
+const DICTIONARY_MAX_SIZE: usize = 1024 * 110;
 
 #[derive(Debug, PartialEq)]
 pub enum LogType {
@@ -20,15 +34,97 @@ pub enum LogType {
     Purge,
 }
 
+/// What was removed from the message log by `delete_entries`
+#[derive(Debug, Clone, Default)]
+pub struct DeleteReceipt {
+    pub index_entries: u64,
+    pub uncompressed_bodies: u64,
+    pub search_entries: u64,
+    pub chunks_rewritten: u64,
+    pub chunks_dropped: u64,
+}
+
+impl DeleteReceipt {
+    pub fn add(&mut self, other: DeleteReceipt) {
+        self.index_entries += other.index_entries;
+        self.uncompressed_bodies += other.uncompressed_bodies;
+        self.search_entries += other.search_entries;
+        self.chunks_rewritten += other.chunks_rewritten;
+        self.chunks_dropped += other.chunks_dropped;
+    }
+}
+
 #[derive(Debug)]
 pub struct MessageLog {
     pub message_index_id: u64,
     pub message_id: MessageId,
+    pub guild_id: Option<GuildId>,
+    pub channel_id: Option<ChannelId>,
     pub timestamp: Timestamp,
     pub type_: LogType,
     pub message: Option<Message>,
 }
 
+/// Filters for `search`. All the filters that are set must match
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageSearchQuery {
+    pub guild_id: GuildId,
+    /// Words that must all appear in the message
+    pub text: Option<String>,
+    pub user_id: Option<UserId>,
+    pub channel_id: Option<ChannelId>,
+    /// Only messages with an id greater than this, i.e. sent after it
+    pub after_id: Option<MessageId>,
+    /// Only messages with an id less than this, i.e. sent before it
+    pub before_id: Option<MessageId>,
+    /// Only messages in these channels. It's worked out from the permissions of whoever
+    /// is looking at the results, so it isn't stored with the pagination buttons
+    pub channel_ids: Option<Vec<ChannelId>>,
+}
+
+impl MessageSearchQuery {
+    /// Packs the query into JSON so it can be stored with the pagination buttons
+    pub fn to_json(&self) -> Value {
+        json!({
+            "guild_id": self.guild_id.0,
+            "text": self.text,
+            "user_id": self.user_id.map(|v| v.0),
+            "channel_id": self.channel_id.map(|v| v.0),
+            "after_id": self.after_id.map(|v| v.0),
+            "before_id": self.before_id.map(|v| v.0),
+        })
+    }
+
+    /// The inverse of `to_json`
+    pub fn from_json(value: &Value) -> Result<Self, Error> {
+        let id = |key: &str| -> Result<Option<u64>, Error> {
+            match &value[key] {
+                Value::Null => Ok(None),
+                v => Ok(Some(v.as_u64().ok_or_else(|| anyhow::anyhow!("Search query {key} ({v}) isn't an id"))?)),
+            }
+        };
+
+        Ok(Self {
+            guild_id: GuildId::from(id("guild_id")?.ok_or_else(|| anyhow::anyhow!("Search query is missing guild_id"))?),
+            text: value["text"].as_str().map(|v| v.to_string()),
+            user_id: id("user_id")?.map(UserId::from),
+            channel_id: id("channel_id")?.map(ChannelId::from),
+            after_id: id("after_id")?.map(MessageId::from),
+            before_id: id("before_id")?.map(MessageId::from),
+            channel_ids: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageSearchResult {
+    pub message_index_id: u64,
+    pub channel_id: ChannelId,
+    pub user_id: UserId,
+    pub message_id: MessageId,
+    pub content: String,
+}
+
 impl ToSql for LogType {
     fn to_sql(&self) -> Result<ToSqlOutput, rusqlite::Error> {
         Ok(match self {
@@ -92,56 +188,534 @@ pub fn log(
 
     let tx = db.transaction()?;
     {   
+        let (guild_id, channel_id, user_id) = match message.as_ref() {
+            Some(message) => (
+                message.guild_id.map(|v| v.0),
+                Some(message.channel_id.0),
+                Some(message.author.id.0),
+            ),
+            // Deletes and purges don't come with the message so copy them from an earlier entry
+            None => tx
+                .prepare_cached(
+                    "SELECT guild_id, channel_id, user_id FROM message_index
+                    WHERE message_id = ?1 AND guild_id IS NOT NULL
+                    LIMIT 1",
+                )?
+                .query_row(params![message_id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                .optional()?
+                .unwrap_or((None, None, None)),
+        };
+
         let mut stmt = tx.prepare_cached(
-            "INSERT INTO message_index (message_id, timestamp, type)
-            VALUES(?1, ?2, ?3)",
+            "INSERT INTO message_index (message_id, timestamp, type, guild_id, channel_id, user_id)
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
         )?;
         
         let message_index_id = stmt.insert(params![
             message_id,
             &timestamp.to_rfc3339(),
             type_,
+            guild_id,
+            channel_id,
+            user_id,
         ])?;
             
         debug!(message_index_id, "message_index inserted");
 
         if let Some(message) = message {
-            let json = serde_json::to_value(message)?;
+            index_for_search(&tx, message_index_id, &message)?;
+
+            let body = message_record::encode_message(&message)?;
             
             let mut stmt = tx.prepare_cached("
-                INSERT INTO message_chunk_temp (message_index_id, message_json)
+                INSERT INTO message_chunk_temp (message_index_id, message_body)
                 VALUES (?1, ?2)
             ")?;
 
-            stmt.execute(params![message_index_id, json])?;
+            stmt.execute(params![message_index_id, body])?;
         }
     }
     tx.commit()?;   
     Ok(())
 }
         
+/// Adds the text of the message to the full text search index. Messages outside a
+/// guild aren't indexed as they could never be searched for
+fn index_for_search(db: &Connection, message_index_id: i64, message: &Message) -> Result<(), Error> {
+    if message.guild_id.is_none() {
+        return Ok(());
+    }
+
+    let mut stmt = db.prepare_cached(
+        "INSERT INTO message_search (rowid, content) VALUES (?1, ?2)",
+    )?;
+    stmt.execute(params![message_index_id, &message.content])?;
+
+    Ok(())
+}
+
+/// Removes entries added by `index_for_search`. The search table is contentless so
+/// FTS5 needs the exact text that was indexed to find what to remove
+fn remove_from_search(db: &Connection, entries: &[(u64, String)]) -> Result<u64, Error> {
+    let mut stmt = db.prepare_cached(
+        "INSERT INTO message_search (message_search, rowid, content) VALUES ('delete', ?1, ?2)",
+    )?;
+    for (message_index_id, content) in entries.iter() {
+        stmt.execute(params![message_index_id, content])?;
+    }
+    Ok(entries.len() as u64)
+}
+
+/// Quotes each word so punctuation in the search text isn't treated as FTS5 query syntax.
+/// The words are implicitly ANDed together
+fn fts_query(text: &str) -> String {
+    text.split_whitespace()
+        .map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Searches the message log, newest first. Each message is only returned once, as
+/// the newest of its revisions that matched. Returns the total number of matching
+/// messages along with the requested page of them
+pub fn search(
+    db: &Connection,
+    cache: &mut ChunkCache,
+    query: MessageSearchQuery,
+    limit: u64,
+    offset: u64,
+) -> Result<(u64, Vec<MessageSearchResult>), Error> {
+    let text = query
+        .text
+        .as_deref()
+        .map(fts_query)
+        .filter(|v| v.len() > 0);
+
+    // The filters are on message_index so they can use its indexes. FTS5 doesn't
+    // allow MATCH to be ORed with anything so it can't use the same "?N IS NULL OR"
+    // trick as the other filters
+    let from_clause = if text.is_some() {
+        "message_search
+        INNER JOIN message_index ON message_index.message_index_id = message_search.rowid
+        WHERE message_search MATCH ?6"
+    } else {
+        "message_index
+        WHERE ?6 IS NULL AND type IN ('CREATE', 'EDIT')"
+    };
+    let filter_clause = format!(
+        "{from_clause}
+        AND guild_id = ?1
+        AND (?2 IS NULL OR channel_id = ?2)
+        AND (?3 IS NULL OR user_id = ?3)
+        AND (?4 IS NULL OR message_id > ?4)
+        AND (?5 IS NULL OR message_id < ?5)
+        AND (?9 IS NULL OR channel_id IN (SELECT value FROM json_each(?9)))"
+    );
+    let channel_ids = query
+        .channel_ids
+        .as_ref()
+        .map(|ids| serde_json::to_string(&ids.iter().map(|v| v.into()).collect::<Vec<u64>>()))
+        .transpose()?;
+
+    let total = db
+        .prepare_cached(&format!("SELECT count(DISTINCT message_id) FROM {filter_clause}"))?
+        .query_row(
+            params![
+                query.guild_id,
+                query.channel_id,
+                query.user_id,
+                query.after_id,
+                query.before_id,
+                &text,
+                None::<u64>,
+                None::<u64>,
+                &channel_ids,
+            ],
+            |r| r.get::<_, u64>(0),
+        )?;
+
+    // SQLite takes the other columns from the row max() picked, i.e. the newest revision
+    let mut stmt = db.prepare_cached(&format!(
+        "SELECT {LOG_COLUMNS}, user_id, max(message_index_id)
+        FROM {filter_clause}
+        GROUP BY message_id
+        ORDER BY message_id DESC
+        LIMIT ?7 OFFSET ?8"
+    ))?;
+
+    let rows = stmt
+        .query_map(
+            params![
+                query.guild_id,
+                query.channel_id,
+                query.user_id,
+                query.after_id,
+                query.before_id,
+                &text,
+                limit,
+                offset,
+                &channel_ids,
+            ],
+            |r| Ok((log_from_row(r)?, r.get::<_, u64>(7)?)),
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (logs, user_ids): (Vec<_>, Vec<_>) = rows.into_iter().unzip();
+    let results = load_message_bodies(db, cache, logs)?
+        .into_iter()
+        .zip(user_ids.into_iter())
+        .map(|(log, user_id)| Ok(MessageSearchResult {
+            message_index_id: log.message_index_id,
+            // Always set alongside the guild_id that was filtered on
+            channel_id: log.channel_id.ok_or_else(|| anyhow::anyhow!(
+                "message_index ({}) has a guild_id but no channel_id",
+                log.message_index_id
+            ))?,
+            user_id: UserId::from(user_id),
+            message_id: log.message_id,
+            // Missing if the chunk it was in was quarantined
+            content: log.message.map(|m| m.content).unwrap_or_default(),
+        }))
+        .collect::<Result<_, Error>>()?;
+
+    Ok((total, results))
+}
+
+/// Fills in the search index and the message_index guild, channel and user for one
+/// of the chunks compressed before they existed.
+///
+/// Returns true if there are more chunks waiting to be backfilled
+#[instrument(skip(db))]
+pub fn backfill_chunk(db: &mut Connection) -> Result<bool, Error> {
+    let next = db
+        .prepare_cached(
+            "SELECT message_chunk.chunk_id, start_message_index_id, end_message_index_id
+            FROM message_search_backfill
+            INNER JOIN message_chunk ON message_chunk.chunk_id = message_search_backfill.chunk_id
+            LIMIT 1",
+        )?
+        .query_row((), |r| Ok((
+            r.get::<_, u64>(0)?,
+            r.get::<_, u64>(1)?,
+            r.get::<_, u64>(2)?,
+        )))
+        .optional()?;
+
+    let (chunk_id, start_id, end_id) = match next {
+        Some(v) => v,
+        None => return Ok(false),
+    };
+
+    // Every entry is needed so there's no point going through the cache
+    let messages = read_chunk_messages(chunk_id, &load_chunk(db, chunk_id)?, (start_id..=end_id).collect())?;
+
+    let tx = db.transaction()?;
+    {
+        let mut update_stmt = tx.prepare_cached(
+            "UPDATE message_index
+            SET guild_id = ?2, channel_id = ?3, user_id = ?4
+            WHERE message_index_id = ?1",
+        )?;
+
+        let mut count = 0;
+        for (message_index_id, message) in messages.iter() {
+            if let Some(message) = message {
+                index_for_search(&tx, *message_index_id as i64, message)?;
+                update_stmt.execute(params![
+                    message_index_id,
+                    message.guild_id.map(|v| v.0),
+                    message.channel_id.0,
+                    message.author.id.0,
+                ])?;
+                count += 1;
+            }
+        }
+        debug!(chunk_id, count, "Chunk backfilled");
+
+        // Deletes and purges of the messages in the chunk don't have a body to get
+        // the ids from
+        tx.prepare_cached(
+            "UPDATE message_index
+            SET
+                guild_id = known.guild_id,
+                channel_id = known.channel_id,
+                user_id = known.user_id
+            FROM (
+                SELECT message_id, guild_id, channel_id, user_id
+                FROM message_index
+                WHERE message_index_id BETWEEN ?1 AND ?2 AND guild_id IS NOT NULL
+                GROUP BY message_id
+            ) AS known
+            WHERE
+                known.message_id = message_index.message_id
+                AND message_index.guild_id IS NULL",
+        )?
+        .execute(params![start_id, end_id])?;
+
+        tx.prepare_cached("DELETE FROM message_search_backfill WHERE chunk_id = ?1")?
+            .execute(params![chunk_id])?;
+    }
+    tx.commit()?;
+
+    Ok(db
+        .prepare_cached("SELECT 1 FROM message_search_backfill LIMIT 1")?
+        .exists(())?)
+}
+
+/// A chunk rebuilt by `prepare_record_conversion` or `prepare_cold_chunk` that's
+/// waiting to be committed by `commit_rewritten_chunk`
+#[derive(Debug)]
+pub struct RewrittenChunk {
+    chunk_id: u64,
+    /// The data `chunk_id` and each of the chunks merged into it had when they were read.
+    /// Retention, forget_user and repairs rewrite or drop chunks, so the commit is thrown
+    /// away if any of them changed
+    sources: Vec<(u64, Vec<u8>)>,
+    end_message_index_id: u64,
+    data: Vec<u8>,
+    dictionary_id: Option<u64>,
+    compression_level: Option<i32>,
+    frame_offsets: Vec<u8>,
+    /// Set if this converts the chunk's bodies to message records
+    records_converted: bool,
+}
+
+/// Converts the bodies in one of the chunks compressed before message records existed
+/// from serenity's JSON to records. It keeps the dictionary and level the chunk was
+/// compressed with.
+///
+/// Returns true if there are more chunks waiting to be converted
+#[instrument(skip(db))]
+pub fn convert_chunk_to_records(db: &mut Connection) -> Result<bool, Error> {
+    match prepare_record_conversion(db)? {
+        Some(chunk) => commit_rewritten_chunk(db, chunk)?,
+        None => return Ok(false),
+    };
+
+    Ok(db
+        .prepare_cached("SELECT 1 FROM message_record_backfill LIMIT 1")?
+        .exists(())?)
+}
+
+/// Builds the message record version of the next chunk waiting to be converted. This
+/// only reads from the database so it can run on the compression worker's connection
+#[instrument(skip(db))]
+pub fn prepare_record_conversion(db: &Connection) -> Result<Option<RewrittenChunk>, Error> {
+    let next = db
+        .prepare_cached(
+            "SELECT message_chunk.chunk_id, end_message_index_id, data, dictionary_id, compression_level
+            FROM message_record_backfill
+            INNER JOIN message_chunk ON message_chunk.chunk_id = message_record_backfill.chunk_id
+            LIMIT 1",
+        )?
+        .query_row((), |r| Ok((
+            r.get::<_, u64>(0)?,
+            r.get::<_, u64>(1)?,
+            r.get::<_, Vec<u8>>(2)?,
+            r.get::<_, Option<u64>>(3)?,
+            r.get::<_, Option<i32>>(4)?,
+        )))
+        .optional()?;
+
+    let (chunk_id, end_id, data, dictionary_id, level) = match next {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let dictionary = get_dictionary(db, dictionary_id)?;
+    let entries = decode_chunk(data.clone(), dictionary.as_deref())?
+        .into_iter()
+        // Empty entries are messages without a body
+        .map(|entry| if entry.len() == 0 { Ok(entry) } else { message_record::upgrade(entry) })
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to convert chunk ({chunk_id}) to message records"))?;
+    let (compressed, frame_offsets) = encode_chunk(&entries, dictionary.as_deref(), level.unwrap_or(COMPRESSION_LEVEL))?;
+
+    Ok(Some(RewrittenChunk {
+        chunk_id,
+        sources: vec![(chunk_id, data)],
+        end_message_index_id: end_id,
+        data: compressed,
+        dictionary_id,
+        compression_level: level,
+        frame_offsets,
+        records_converted: true,
+    }))
+}
+
+/// Replaces the chunk with the rewritten version, moving the entries of any chunks that
+/// were merged into it. Nothing is written if any of the chunks it was built from have
+/// changed since, the next attempt rebuilds it from what's there now.
+///
+/// Returns true if the chunk was committed
+#[instrument(skip(db, chunk), fields(chunk_id = chunk.chunk_id))]
+pub fn commit_rewritten_chunk(db: &mut Connection, chunk: RewrittenChunk) -> Result<bool, Error> {
+    let tx = db.transaction()?;
+    {
+        let mut unchanged_stmt = tx.prepare_cached(
+            "SELECT 1 FROM message_chunk WHERE chunk_id = ?1 AND data = ?2",
+        )?;
+        for (chunk_id, data) in chunk.sources.iter() {
+            if !unchanged_stmt.exists(params![chunk_id, data])? {
+                warn!(chunk_id, "Chunk changed while it was being rewritten, discarding the rewrite");
+                return Ok(false);
+            }
+        }
+
+        tx.prepare_cached(
+            "UPDATE message_chunk
+            SET data = ?2, dictionary_id = ?3, compression_level = ?4, end_message_index_id = ?5, frame_offsets = ?6
+            WHERE chunk_id = ?1",
+        )?
+        .execute(params![
+            chunk.chunk_id,
+            chunk.data,
+            chunk.dictionary_id,
+            chunk.compression_level,
+            chunk.end_message_index_id,
+            chunk.frame_offsets,
+        ])?;
+
+        let mut move_stmt = tx.prepare_cached(
+            "UPDATE message_index SET chunk_id = ?1 WHERE chunk_id = ?2",
+        )?;
+        let mut delete_stmt = tx.prepare_cached(
+            "DELETE FROM message_chunk WHERE chunk_id = ?1",
+        )?;
+        for (merged_id, _) in chunk.sources.iter().skip(1) {
+            move_stmt.execute(params![chunk.chunk_id, merged_id])?;
+            delete_stmt.execute(params![merged_id])?;
+        }
+
+        if chunk.records_converted {
+            tx.prepare_cached("DELETE FROM message_record_backfill WHERE chunk_id = ?1")?
+                .execute(params![chunk.chunk_id])?;
+            debug!(chunk.chunk_id, "Chunk converted to message records");
+        }
+    }
+    tx.commit()?;
+
+    Ok(true)
+}
+
+/// Looks up who sent the message
+pub fn get_user(
+    db: &Connection,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    message_id: MessageId,
+) -> Result<Option<UserId>, Error> {
+    let mut stmt = db.prepare_cached(
+        "SELECT user_id FROM message_index
+        WHERE guild_id = ?1 AND channel_id = ?2 AND message_id = ?3 AND user_id IS NOT NULL
+        LIMIT 1",
+    )?;
+
+    Ok(stmt
+        .query_row(params![guild_id, channel_id, message_id], |r| r.get::<_, u64>(0))
+        .optional()?
+        .map(UserId::from))
+}
+
+/// Maps a row selected with `LOG_COLUMNS` to the log entry and its chunk_id
+fn log_from_row(r: &rusqlite::Row) -> rusqlite::Result<(MessageLog, Option<u64>)> {
+    Ok((MessageLog {
+        message_index_id: r.get::<_, u64>(0)?,
+        message_id: MessageId::from(r.get::<_, u64>(1)?),
+        guild_id: r.get::<_, Option<u64>>(2)?.map(GuildId::from),
+        channel_id: r.get(3)?,
+        timestamp: Timestamp::from(r.get::<_, String>(4)?),
+        type_: r.get(5)?,
+        message: None, 
+    }, r.get::<_, Option<u64>>(6)?))
+}
+
+const LOG_COLUMNS: &str = "message_index_id, message_id, guild_id, channel_id, timestamp, type, chunk_id";
+
 pub fn get(
     db: &Connection,
+    cache: &mut ChunkCache,
     message_id: MessageId,
 ) -> Result<Vec<MessageLog>, Error> {
-    let mut stmt = db.prepare_cached(
-        "SELECT message_index_id, message_id, timestamp, type, chunk_id FROM message_index
+    let mut stmt = db.prepare_cached(&format!(
+        "SELECT {LOG_COLUMNS} FROM message_index
         WHERE message_id = ?1
         ORDER BY timestamp DESC",
-    )?;
+    ))?;
 
-    let mut messages_result: Vec<(MessageLog, Option<u64>)> = stmt
-        .query_map(params![message_id], |r| {
-            Ok((MessageLog {
-                message_index_id: r.get::<_, u64>(0)?,
-                message_id: MessageId::from(r.get::<_, u64>(1)?),
-                timestamp: Timestamp::from(r.get::<_, String>(2)?),
-                type_: r.get(3)?,
-                message: None, 
-            }, r.get::<_, Option<u64>>(4)?))
-        })?
+    let messages_result = stmt
+        .query_map(params![message_id], log_from_row)?
+        .collect::<Result<_, _>>()?;
+
+    load_message_bodies(db, cache, messages_result)
+}
+
+/// Gets a page of the log entries for messages the user sent in the guild, oldest
+/// first. `after` is the message_id and message_index_id of the last entry of the
+/// previous page
+pub fn get_by_user(
+    db: &Connection,
+    cache: &mut ChunkCache,
+    guild_id: GuildId,
+    user_id: UserId,
+    after: Option<(MessageId, u64)>,
+    limit: u64,
+) -> Result<Vec<MessageLog>, Error> {
+    let mut stmt = db.prepare_cached(&format!(
+        "SELECT {LOG_COLUMNS} FROM message_index
+        WHERE guild_id = ?1 AND user_id = ?2
+            AND (?3 IS NULL OR (message_id, message_index_id) > (?3, ?4))
+        ORDER BY message_id, message_index_id
+        LIMIT ?5",
+    ))?;
+
+    let messages_result = stmt
+        .query_map(
+            params![guild_id, user_id, after.map(|v| v.0), after.map(|v| v.1), limit],
+            log_from_row,
+        )?
         .collect::<Result<_, _>>()?;
 
+    load_message_bodies(db, cache, messages_result)
+}
+
+/// Gets the log entries for up to `limit` messages sent in the channel from `after_id`
+/// up to (but not including) `before_id`, oldest first. Every entry of each message is
+/// included so its history is never cut off part way through
+pub fn get_by_channel(
+    db: &Connection,
+    cache: &mut ChunkCache,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    after_id: MessageId,
+    before_id: MessageId,
+    limit: u64,
+) -> Result<Vec<MessageLog>, Error> {
+    let mut stmt = db.prepare_cached(&format!(
+        "SELECT {LOG_COLUMNS} FROM message_index
+        WHERE guild_id = ?1 AND channel_id = ?2 AND message_id IN (
+            SELECT DISTINCT message_id FROM message_index
+            WHERE guild_id = ?1 AND channel_id = ?2 AND message_id >= ?3 AND message_id < ?4
+            ORDER BY message_id
+            LIMIT ?5
+        )
+        ORDER BY message_id, message_index_id",
+    ))?;
+
+    let messages_result = stmt
+        .query_map(params![guild_id, channel_id, after_id, before_id, limit], log_from_row)?
+        .collect::<Result<_, _>>()?;
+
+    load_message_bodies(db, cache, messages_result)
+}
+
+/// Fills in the message of each Create and Edit entry from message_chunk_temp or
+/// the chunk it was compressed into
+fn load_message_bodies(
+    db: &Connection,
+    cache: &mut ChunkCache,
+    mut messages_result: Vec<(MessageLog, Option<u64>)>,
+) -> Result<Vec<MessageLog>, Error> {
     let mut needs_chunk: HashMap<u64, Vec<(u64, usize)>> = HashMap::new();
     for (i, (m, chunk_id)) in messages_result
         .iter_mut()
@@ -160,8 +734,10 @@ pub fn get(
                 .unwrap()
                 .push((m.message_index_id, i));
         } else {
-            m.message = get_message_body(db, m.message_index_id, None)?;
-            ensure!(m.message.is_some(), "Failed to get message body for a Create or Edit log entry: message_index_id: {}, chunk_id: {:?}", m.message_index_id, chunk_id);
+            m.message = get_message_body(db, cache, m.message_index_id, None)?;
+            if m.message.is_none() {
+                warn!(message_index_id = m.message_index_id, "Create or Edit log entry has no body, its chunk may have been quarantined");
+            }
         }
     }
 
@@ -171,7 +747,7 @@ pub fn get(
             .map(|(id, _)| *id)
             .collect::<Vec<_>>();
 
-        let results = decompress_message_body(db, message_index_ids, chunk_id)?;
+        let results = decompress_message_body(db, cache, message_index_ids, chunk_id)?;
         ensure!(results.len() == messages.len(), "Expected {} message bodies from decompress_message_body but got {}", messages.len(), results.len());
 
         for (message_index_id, body) in results.into_iter() {
@@ -189,178 +765,374 @@ pub fn get(
         .collect())
 }
 
-#[instrument(skip(db))]
-fn decompress_message_body(db: &Connection, mut message_index_ids: Vec<u64>, chunk_id: u64) -> Result<Vec<(u64, Option<Message>)>, Error> {
-    ensure!(message_index_ids.len() > 0, "0 message_index_ids passed into decompress_message_body");
-
-    let mut stmt = db.prepare_cached("
-        SELECT chunk_id, start_message_index_id, end_message_index_id, data 
-        FROM message_chunk
-        WHERE chunk_id = ?1
-        LIMIT 1
-    ")?;
-    let (chunk_id, start_id, end_id, data) = stmt.query_row(params![chunk_id], |r| {
-        let chunk_id: u64 = r.get(0)?;
-        let start_id: u64 = r.get(1)?;
-        let end_id: u64 = r.get(2)?;
-        let data: Vec<u8> = r.get(3)?;
-        Ok((chunk_id, start_id, end_id, data))
-    })?;
-
-    for message_index_id in message_index_ids.iter() {
-        ensure!(*message_index_id >= start_id && *message_index_id <= end_id, 
-            "message_index_id ({message_index_id}) outside bounds of chunk ({chunk_id}, {start_id} -> {end_id})");
-    }
-
-    message_index_ids.sort();
+/// Loads and decompresses the chunk, using the stored frame offsets if it has them
+fn load_chunk(db: &Connection, chunk_id: u64) -> Result<CachedChunk, Error> {
+    let (start_id, end_id, data, dictionary_id, frame_offsets) = db
+        .prepare_cached(
+            "SELECT start_message_index_id, end_message_index_id, data, dictionary_id, frame_offsets
+            FROM message_chunk
+            WHERE chunk_id = ?1
+            LIMIT 1",
+        )?
+        .query_row(params![chunk_id], |r| Ok((
+            r.get::<_, u64>(0)?,
+            r.get::<_, u64>(1)?,
+            r.get::<_, Vec<u8>>(2)?,
+            r.get::<_, Option<u64>>(3)?,
+            r.get::<_, Option<Vec<u8>>>(4)?,
+        )))?;
 
     let mut zstd_buffer = Vec::with_capacity(MESSAGE_LOG_CHUNK_SIZE as usize);
-    
-    let mut zdec = Decoder::new(Cursor::new(data))?;
-    zdec.read_to_end(&mut zstd_buffer)?;
-    
-    let mut cobs_buffer = Vec::new();
-    let mut count = 0;
-    let mut start_i = 0;
+    let dictionary = get_dictionary(db, dictionary_id)?;
+    decompress_chunk(data, dictionary.as_deref(), &mut zstd_buffer)?;
 
-    let mut results = Vec::new();
+    let offsets = match frame_offsets {
+        Some(v) => decode_frame_offsets(&v)?,
+        None => scan_frame_offsets(&zstd_buffer),
+    };
 
-    for (end_i, _) in zstd_buffer
-        .iter()
-        .enumerate()
-        .filter(|(_, byte)| **byte == corncobs::ZERO)
-    {
-        let index_id = start_id + count;
+    CachedChunk::new(start_id, end_id, zstd_buffer, offsets)
+        .with_context(|| format!("Failed to load chunk ({chunk_id})"))
+}
 
-        // Look for the ids we care about
-        if index_id == message_index_ids[0] {
-            // Cobs decode the correct range of bytes
-            corncobs::decode(&zstd_buffer[start_i..=end_i], &mut cobs_buffer)?;
-            
-            let message = if cobs_buffer.len() == 0 {
-                None
-            } else { 
-                serde_json::from_slice::<Message>(&cobs_buffer)
-                    .context("Decoding compressed chunk into Message")
-                    .map(|m| Some(m))?
-            };
-            let message_index_id = message_index_ids.remove(0);
-            results.push((message_index_id, message));
+/// Decodes the message bodies for the ids out of the chunk
+fn read_chunk_messages(
+    chunk_id: u64,
+    chunk: &CachedChunk,
+    message_index_ids: Vec<u64>,
+) -> Result<Vec<(u64, Option<Message>)>, Error> {
+    let mut cobs_buffer = Vec::new();
+    let mut results = Vec::with_capacity(message_index_ids.len());
 
-            if message_index_ids.len() == 0 {
-                break;
-            }
-            // Clear the cobs_buffer for reuse
-            cobs_buffer.clear();
-        }
+    for message_index_id in message_index_ids.into_iter() {
+        let frame = chunk.frame(message_index_id).ok_or_else(|| anyhow::anyhow!(
+            "message_index_id ({message_index_id}) outside bounds of chunk ({chunk_id}, {} -> {})",
+            chunk.start_id,
+            chunk.end_id
+        ))?;
 
-        count += 1;
-        start_i = end_i + 1;
-    }
+        cobs_buffer.clear();
+        corncobs::decode(frame, &mut cobs_buffer)?;
 
-    if message_index_ids.len() > 0 {
-        Err(anyhow::anyhow!("Failed to find message_index_id ({:?}) in chunk ({chunk_id}) despite it being within the declared chunk bounds ({start_id} -> {end_id})", message_index_ids))?
-    } else {
-        Ok(results)
+        let message = if cobs_buffer.len() == 0 {
+            None
+        } else {
+            message_record::decode_message(&cobs_buffer)
+                .context("Decoding compressed chunk into Message")
+                .map(|m| Some(m))?
+        };
+        results.push((message_index_id, message));
     }
 
+    Ok(results)
 }
 
-fn get_message_body(db: &Connection, message_index_id: u64, chunk_id: Option<u64>) -> Result<Option<Message>, Error> {
+#[instrument(skip(db, cache))]
+fn decompress_message_body(db: &Connection, cache: &mut ChunkCache, mut message_index_ids: Vec<u64>, chunk_id: u64) -> Result<Vec<(u64, Option<Message>)>, Error> {
+    ensure!(message_index_ids.len() > 0, "0 message_index_ids passed into decompress_message_body");
+    message_index_ids.sort();
+
+    let chunk = cache.get_or_load(db, chunk_id, || load_chunk(db, chunk_id))?;
+    read_chunk_messages(chunk_id, chunk, message_index_ids)
+}
+
+fn get_message_body(db: &Connection, cache: &mut ChunkCache, message_index_id: u64, chunk_id: Option<u64>) -> Result<Option<Message>, Error> {
     let message = if let Some(chunk_id) = chunk_id {
-        let mut r = decompress_message_body(db, vec![message_index_id], chunk_id)?;
+        let mut r = decompress_message_body(db, cache, vec![message_index_id], chunk_id)?;
         ensure!(r.len() == 1, "Expected 1 message body from decompress_message_body but got {}", r.len());
         r.remove(0).1
     } else {
         let mut stmt = db.prepare_cached("
-            SELECT message_json 
+            SELECT message_body 
             FROM message_chunk_temp 
             WHERE message_index_id = ?1
             LIMIT 1
         ")?;
+        // Missing if the chunk it was compressed into was quarantined by a repair
         stmt.query_row(params![message_index_id], |r| 
-            r.get::<_, Option<serde_json::Value>>(0))?
-            .map(|v| serde_json::from_value(v))
-            .transpose()
-            .map_err(|e| {
-                rusqlite::Error::FromSqlConversionFailure(
-                    0,
-                    rusqlite::types::Type::Text,
-                    Box::new(e),
-                )
-            })?
+            r.get::<_, Vec<u8>>(0))
+            .optional()?
+            .map(|v| message_record::decode_message(&v))
+            .transpose()?
     };
     Ok(message)
 }
  
-pub fn verify_compressed_chunks(db: &Connection) -> Result<(), Error> {
-    let mut stmt = db.prepare("SELECT message_index_id, message_id, type FROM message_index")?;
-    let index_map = stmt.query_map((), |r| Ok((
-        r.get::<_, u64>(0)?,
-        (
+/// Checks a compressed chunk can be read back and lines up with message_index.
+///
+/// Returns why the chunk is bad or None if it's fine. Errors are only returned if the
+/// database itself couldn't be read
+fn verify_chunk(db: &Connection, chunk_id: u64) -> Result<Option<String>, Error> {
+    // The chunk and its message_index rows have to be read from the same snapshot or
+    // a chunk rewritten in between (e.g. by a delete) looks corrupt
+    let tx = db.unchecked_transaction()?;
+    let reason = check_chunk(&tx, chunk_id)?;
+    tx.commit()?;
+    Ok(reason)
+}
+
+/// `verify_chunk` without the transaction, for callers that already have one
+fn check_chunk(db: &Connection, chunk_id: u64) -> Result<Option<String>, Error> {
+    let chunk = db
+        .prepare_cached(
+            "SELECT start_message_index_id, end_message_index_id, data, dictionary_id, frame_offsets
+            FROM message_chunk
+            WHERE chunk_id = ?1
+            LIMIT 1",
+        )?
+        .query_row(params![chunk_id], |r| Ok((
+            r.get::<_, u64>(0)?,
             r.get::<_, u64>(1)?,
-            r.get::<_, String>(2)?,
-        ),
-    )))?
-    .collect::<Result<HashMap<_, _>, _>>()?;
+            r.get::<_, Vec<u8>>(2)?,
+            r.get::<_, Option<u64>>(3)?,
+            r.get::<_, Option<Vec<u8>>>(4)?,
+        )))
+        .optional()?;
+
+    // Deleted since the chunk ids were listed
+    let (start_id, end_id, data, dictionary_id, frame_offsets) = match chunk {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    if end_id < start_id {
+        return Ok(Some(format!("Bounds are reversed ({start_id} -> {end_id})")));
+    }
+
+    let dictionary = match get_dictionary(db, dictionary_id) {
+        Ok(v) => v,
+        Err(e) => return Ok(Some(format!("Failed to load its dictionary: {e}"))),
+    };
 
-    let mut cobs_buffer = Vec::new();
     let mut zstd_buffer = Vec::with_capacity(MESSAGE_LOG_CHUNK_SIZE as usize);
-    let mut stmt = db.prepare("SELECT chunk_id, start_message_index_id, end_message_index_id, data FROM message_chunk")?;
-    let mut rows = stmt.query(())?;
-    
-    while let Some(r) = rows.next()? {
-        let chunk_id: u64 = r.get(0)?;
-        let start_id: u64 = r.get(1)?;
-        let end_id: u64 = r.get(2)?;
-        let data: Vec<u8> = r.get(3)?;
-        
-        let mut zdec = Decoder::new(Cursor::new(data))?;
-        zstd_buffer.clear();
-        let z_len = zdec.read_to_end(&mut zstd_buffer)?;
-        assert_eq!(z_len, zstd_buffer.len());
+    if let Err(e) = decompress_chunk(data, dictionary.as_deref(), &mut zstd_buffer) {
+        return Ok(Some(format!("Failed to decompress: {e}")));
+    }
+    if zstd_buffer.last().map_or(false, |v| *v != corncobs::ZERO) {
+        return Ok(Some("Has data after the last frame".to_string()));
+    }
 
-        let mut count = 0;
-        let mut start_i = 0;
+    let offsets = scan_frame_offsets(&zstd_buffer);
+    let count_target = end_id - start_id + 1;
+    if offsets.len() as u64 != count_target {
+        return Ok(Some(format!(
+            "Has {} frames but its bounds ({start_id} -> {end_id}) need {count_target}",
+            offsets.len()
+        )));
+    }
+    if let Some(frame_offsets) = frame_offsets {
+        match decode_frame_offsets(&frame_offsets) {
+            Ok(stored) if stored == offsets => {},
+            Ok(_) => return Ok(Some("Stored frame offsets don't match the data".to_string())),
+            Err(e) => return Ok(Some(format!("Stored frame offsets are invalid: {e}"))),
+        }
+    }
+    let chunk = CachedChunk::new(start_id, end_id, zstd_buffer, offsets)?;
 
-        for (end_i, _) in zstd_buffer
-            .iter()
-            .enumerate()
-            .filter(|(_, byte)| **byte == corncobs::ZERO)
-        {
-            assert_ne!(zstd_buffer[start_i], corncobs::ZERO);
-            assert_eq!(zstd_buffer[end_i], corncobs::ZERO);
-            cobs_buffer.clear();
-            corncobs::decode(&zstd_buffer[start_i..=end_i], &mut cobs_buffer)?;
-            start_i = end_i + 1;
-            
-            if cobs_buffer.len() > 0 {
-                let index_id = start_id + count;
-                let (message_id, type_) = if let Some(mid) = index_map.get(&index_id) {
-                    mid.clone()
-                } else {
-                    error!("Failed to get message_id for message_index_id {}", index_id);
-                    (0, "unknown".to_string())
-                };
-                match serde_json::from_slice::<Message>(&cobs_buffer) {
-                    Ok(m) => if m.id != message_id {
-                        error!(chunk_id, index_id, message_id, decoded_id = %m.id, type_, "Decoded message_id doesn't match");
-                    },
-                    Err(e) => error!("Failed to get message from message_index_id {}: {}", index_id, e),
-                }
-            }
+    let index = db
+        .prepare_cached(
+            "SELECT message_index_id, message_id FROM message_index
+            WHERE message_index_id BETWEEN ?1 AND ?2",
+        )?
+        .query_map(params![start_id, end_id], |r| Ok((
+            r.get::<_, u64>(0)?,
+            r.get::<_, u64>(1)?,
+        )))?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    let mut cobs_buffer = Vec::new();
+    for message_index_id in start_id..=end_id {
+        let frame = chunk
+            .frame(message_index_id)
+            .ok_or_else(|| anyhow::anyhow!("Missing frame for message_index_id ({message_index_id}) in chunk ({chunk_id})"))?;
 
-            count += 1;
+        cobs_buffer.clear();
+        if let Err(e) = corncobs::decode(frame, &mut cobs_buffer) {
+            return Ok(Some(format!("Entry for message_index_id {message_index_id} isn't valid COBS: {e:?}")));
+        }
+        // Deletes and purges don't have a body and deleted entries are blanked
+        if cobs_buffer.len() == 0 {
+            continue;
         }
 
-        let count_target = end_id - start_id + 1;
-        if count != count_target {
-            error!(chunk_id, count, end_id, start_id, count_target, "Incorrect count in chunk")
-        } else {
-            // trace!(chunk_id, count, end_id, start_id, count_target, "Chunk OK")
+        let message_id = match index.get(&message_index_id) {
+            Some(v) => *v,
+            None => return Ok(Some(format!("Has a body for message_index_id {message_index_id} which isn't in message_index"))),
+        };
+        match message_record::decode_message(&cobs_buffer) {
+            Ok(m) if m.id.0 == message_id => {},
+            Ok(m) => return Ok(Some(format!(
+                "Entry for message_index_id {message_index_id} is message {} but message_index has {message_id}",
+                m.id.0
+            ))),
+            Err(e) => return Ok(Some(format!("Failed to decode message_index_id {message_index_id}: {e}"))),
         }
     }
-    Ok(())
+
+    Ok(None)
+}
+
+/// Checks every compressed chunk and that message_index points at chunks that cover
+/// each entry. Problems are collected in the report rather than returned as errors.
+///
+/// `integrity_check` and `repair` are left empty, see `db::verify_database`
+#[instrument(skip(db))]
+pub fn verify_compressed_chunks(db: &Connection) -> Result<VerifyReport, Error> {
+    let mut report = VerifyReport::default();
+
+    let chunk_ids = db
+        .prepare("SELECT chunk_id FROM message_chunk ORDER BY chunk_id")?
+        .query_map((), |r| r.get::<_, u64>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for chunk_id in chunk_ids.into_iter() {
+        report.chunks_checked += 1;
+        if let Some(reason) = verify_chunk(db, chunk_id)? {
+            error!(chunk_id, %reason, "Compressed chunk failed verification");
+            report.bad_chunks.push(BadChunk { chunk_id, reason });
+        }
+    }
+
+    report.misplaced_index_entries = db
+        .prepare(
+            "SELECT count(1) FROM message_index
+            LEFT JOIN message_chunk ON message_chunk.chunk_id = message_index.chunk_id
+            WHERE message_index.chunk_id IS NOT NULL
+                AND (
+                    message_chunk.chunk_id IS NULL
+                    OR message_index.message_index_id NOT BETWEEN
+                        message_chunk.start_message_index_id AND message_chunk.end_message_index_id
+                )",
+        )?
+        .query_row((), |r| r.get(0))?;
+    if report.misplaced_index_entries > 0 {
+        error!(misplaced_index_entries = report.misplaced_index_entries, "message_index entries point at chunks that don't cover them");
+    }
+
+    Ok(report)
+}
+
+/// Moves the chunks into message_chunk_quarantine if they still fail verification
+/// and points the message_index entries that were in them at whichever other chunk
+/// covers them. Entries that aren't covered by any chunk are left with a NULL chunk_id
+/// and no body.
+///
+/// It's all one transaction so callers should only pass a few chunks at a time
+#[instrument(skip(db))]
+pub fn repair_compressed_chunks(db: &mut Connection, chunk_ids: &[u64]) -> Result<RepairReceipt, Error> {
+    let mut receipt = RepairReceipt::default();
+    let quarantined = Timestamp::now().to_rfc3339();
+
+    let tx = db.transaction()?;
+    for chunk_id in chunk_ids.iter() {
+        // The chunks were verified on another connection so check they're still bad
+        // before moving them out of the way
+        let reason = match check_chunk(&tx, *chunk_id)? {
+            Some(v) => v,
+            None => continue,
+        };
+
+        tx.prepare_cached(
+            "INSERT INTO message_chunk_quarantine (
+                chunk_id, start_message_index_id, end_message_index_id, data,
+                dictionary_id, compression_level, frame_offsets, reason, quarantined)
+            SELECT
+                chunk_id, start_message_index_id, end_message_index_id, data,
+                dictionary_id, compression_level, frame_offsets, ?2, ?3
+            FROM message_chunk
+            WHERE chunk_id = ?1",
+        )?
+        .execute(params![chunk_id, reason, quarantined])?;
+
+        // Another chunk may still cover some of the entries (e.g. a merge that didn't
+        // finish) so they only lose their body if there isn't one
+        let reassigned: Vec<(u64, bool)> = tx
+            .prepare_cached(
+                "UPDATE message_index
+                SET chunk_id = (
+                    SELECT chunk_id FROM message_chunk
+                    WHERE message_index.message_index_id BETWEEN start_message_index_id AND end_message_index_id
+                        AND chunk_id != ?1
+                    ORDER BY chunk_id DESC
+                    LIMIT 1
+                )
+                WHERE chunk_id = ?1
+                RETURNING message_index_id, chunk_id IS NULL AND type IN ('CREATE', 'EDIT')",
+            )?
+            .query_map(params![chunk_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        receipt.index_entries_reassigned += reassigned.len() as u64;
+        receipt.bodies_lost += count_lost_bodies(&tx, &reassigned)?;
+
+        tx.prepare_cached("DELETE FROM message_search_backfill WHERE chunk_id = ?1")?
+            .execute(params![chunk_id])?;
+        tx.prepare_cached("DELETE FROM message_record_backfill WHERE chunk_id = ?1")?
+            .execute(params![chunk_id])?;
+        tx.prepare_cached("DELETE FROM message_chunk WHERE chunk_id = ?1")?
+            .execute(params![chunk_id])?;
+        warn!(chunk_id, %reason, "Chunk quarantined");
+        receipt.chunks_quarantined += 1;
+    }
+    tx.commit()?;
+
+    Ok(receipt)
+}
+
+/// Points message_index entries that have a chunk which doesn't cover them at the one
+/// that does, or NULL if none do. It checks `REASSIGN_BATCH_SIZE` ids after `after`
+/// each call.
+///
+/// Returns the id to carry on after, None once it's reached the end
+#[instrument(skip(db))]
+pub fn reassign_index_entries(db: &mut Connection, after: u64) -> Result<(RepairReceipt, Option<u64>), Error> {
+    let end = after + REASSIGN_BATCH_SIZE;
+    let tx = db.transaction()?;
+    let reassigned: Vec<(u64, bool)> = tx
+        .prepare_cached(
+            "UPDATE message_index
+            SET chunk_id = (
+                SELECT chunk_id FROM message_chunk
+                WHERE message_index.message_index_id BETWEEN start_message_index_id AND end_message_index_id
+                ORDER BY chunk_id DESC
+                LIMIT 1
+            )
+            WHERE message_index_id > ?1 AND message_index_id <= ?2
+                AND chunk_id IS NOT NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM message_chunk
+                    WHERE message_chunk.chunk_id = message_index.chunk_id
+                        AND message_index.message_index_id BETWEEN start_message_index_id AND end_message_index_id
+                )
+            RETURNING message_index_id, chunk_id IS NULL AND type IN ('CREATE', 'EDIT')",
+        )?
+        .query_map(params![after, end], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let bodies_lost = count_lost_bodies(&tx, &reassigned)?;
+    let more = tx
+        .prepare_cached("SELECT 1 FROM message_index WHERE message_index_id > ?1 LIMIT 1")?
+        .exists(params![end])?;
+    tx.commit()?;
+
+    let receipt = RepairReceipt {
+        chunks_quarantined: 0,
+        index_entries_reassigned: reassigned.len() as u64,
+        bodies_lost,
+    };
+    Ok((receipt, more.then_some(end)))
+}
+
+/// Counts the reassigned entries that should have a body but are no longer in a chunk
+/// and don't have one in message_chunk_temp either. Takes the (message_index_id, needs
+/// a body and lost its chunk) pairs returned by the reassigning UPDATEs
+fn count_lost_bodies(db: &Connection, reassigned: &[(u64, bool)]) -> Result<u64, Error> {
+    let mut stmt = db.prepare_cached("SELECT 1 FROM message_chunk_temp WHERE message_index_id = ?1")?;
+    let mut lost = 0;
+    for (message_index_id, _) in reassigned.iter().filter(|(_, unchunked)| *unchunked) {
+        if !stmt.exists(params![message_index_id])? {
+            lost += 1;
+        }
+    }
+    Ok(lost)
 }
 
 #[instrument(skip(db))]
@@ -369,7 +1141,7 @@ pub fn get_compression_state(
 ) -> Result<CompressionState, Error> {
     let (uncompressed_messages, uncompressed_bytes): (u64, u64)  = {
         let mut count_stmt = db.prepare(
-            "SELECT count(1), COALESCE(sum(length(message_json)), 0) FROM message_chunk_temp",
+            "SELECT count(1), COALESCE(sum(length(message_body)), 0) FROM message_chunk_temp",
         )?;
         count_stmt.query_row((), 
         |r| Ok((
@@ -401,9 +1173,27 @@ pub fn get_compression_state(
         compressed_messages,
         compressed_bytes,
         chunks,
+        // Only the DB task has a cache, it fills these in
+        chunk_cache_hits: 0,
+        chunk_cache_misses: 0,
     })
 }
 
+/// A compressed chunk built by `prepare_chunk` that's waiting to be committed
+#[derive(Debug)]
+pub struct PreparedChunk {
+    start_message_index_id: u64,
+    end_message_index_id: u64,
+    data: Vec<u8>,
+    dictionary_id: Option<u64>,
+    frame_offsets: Vec<u8>,
+    /// How many of the entries came from message_chunk_temp rather than being filled
+    /// in for a missing id
+    body_count: u64,
+    /// If there are enough uncompressed messages left over for another chunk
+    more: bool,
+}
+
 /// Check if there are enough uncompressed messages to fill a compressed chunk. If so,
 /// compress them and insert the chunk.
 /// 
@@ -412,9 +1202,22 @@ pub fn get_compression_state(
 pub fn compress(
     db: &mut Connection,
 ) -> Result<bool, Error> {
+    match prepare_chunk(db)? {
+        Some(chunk) => commit_chunk(db, chunk),
+        None => Ok(false),
+    }
+}
+
+/// Builds the next chunk if there are enough uncompressed messages to fill one. This
+/// only reads from the database so it can be run on a separate connection to keep the
+/// CPU heavy part away from the DB task
+#[instrument(skip(db))]
+pub fn prepare_chunk(
+    db: &Connection,
+) -> Result<Option<PreparedChunk>, Error> {
     let (uncompressed_size, start_message_index_id): (u64, u64)  = {
         let mut count_stmt = db.prepare_cached(
-            "SELECT COALESCE(sum(length(message_json)), 0), COALESCE(min(message_index_id), 0) FROM message_chunk_temp",
+            "SELECT COALESCE(sum(length(message_body)), 0), COALESCE(min(message_index_id), 0) FROM message_chunk_temp",
         )?;
         count_stmt.query_row((), 
         |r| Ok((
@@ -432,7 +1235,15 @@ pub fn compress(
         let mut compressed = Vec::<u8>::with_capacity(MESSAGE_LOG_CHUNK_SIZE as usize);
         let mut next_message_index_id = start_message_index_id;
         let mut cobs_buffer = Vec::new();
-        let mut encoder = Encoder::new(&mut compressed, COMPRESSION_LEVEL)?;
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        let dictionary = latest_dictionary(db)?;
+        let dictionary_id = dictionary.as_ref().map(|(id, _)| *id);
+        let mut encoder = chunk_encoder(
+            &mut compressed,
+            dictionary.as_ref().map(|(_, d)| d.as_slice()),
+            COMPRESSION_LEVEL,
+        )?;
         
         let _span = span!(Level::DEBUG, "Compressing message chunk").entered();
         
@@ -445,11 +1256,14 @@ pub fn compress(
                 let cobs_len = corncobs::encode_buf(&data, &mut cobs_buffer);
                 assert!(cobs_len > 0);
 
+                offsets.push(offset);
+                offset += cobs_len as u32;
+
                 encoder.write_all(&cobs_buffer[..cobs_len])
             };
 
             let mut fetch_stmt = db.prepare_cached(
-                "SELECT message_index_id, CAST(message_json AS BLOB)
+                "SELECT message_index_id, message_body
                 FROM message_chunk_temp
                 WHERE message_index_id >= ?1"
             )?;
@@ -478,7 +1292,8 @@ pub fn compress(
                 {
                     trace!("Adding {id} with length {}", data.len());
                     remaining_bytes -= data.len();
-                    push(data)?;
+                    // Rows logged before message records existed are converted on the way in
+                    push(message_record::upgrade(data)?)?;
                 } else {
                     break;
                 }
@@ -504,47 +1319,885 @@ pub fn compress(
             end_message_index_id,
         );
 
-        let tx = db.transaction()?;
+        Ok(Some(PreparedChunk {
+            start_message_index_id,
+            end_message_index_id,
+            data: compressed,
+            dictionary_id,
+            frame_offsets: encode_frame_offsets(&offsets),
+            body_count: message_count - dummy_count,
+            more: uncompressed_size - remaining_bytes as u64 > MESSAGE_LOG_CHUNK_SIZE,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Inserts the chunk and removes the messages it contains from message_chunk_temp.
+///
+/// Returns true if there are enough uncompressed messages to create more chunks
+#[instrument(skip(db, chunk), fields(start_message_index_id = chunk.start_message_index_id, end_message_index_id = chunk.end_message_index_id))]
+pub fn commit_chunk(
+    db: &mut Connection,
+    chunk: PreparedChunk,
+) -> Result<bool, Error> {
+    let PreparedChunk {
+        start_message_index_id,
+        end_message_index_id,
+        data,
+        dictionary_id,
+        frame_offsets,
+        body_count,
+        more,
+    } = chunk;
+
+    let tx = db.transaction()?;
+
+    {
+        // Entries can be deleted (by retention or forget_user) while the chunk is being
+        // built. Committing it would bring their bodies back so it's thrown away instead
+        // and rebuilt on the next attempt
+        let current_count: u64 = tx
+            .prepare_cached(
+                "SELECT count(1) FROM message_chunk_temp
+                WHERE message_index_id BETWEEN ?1 AND ?2",
+            )?
+            .query_row(params![start_message_index_id, end_message_index_id], |r| r.get(0))?;
+        if current_count != body_count {
+            warn!(current_count, body_count, "message_chunk_temp changed while the chunk was being built, discarding it");
+            return Ok(true);
+        }
+
+        let mut insert_stmt = tx.prepare_cached(
+            "INSERT INTO message_chunk (start_message_index_id, end_message_index_id, data, dictionary_id, frame_offsets)
+            VALUES (?1, ?2, ?3, ?4, ?5)"
+        )?;
+        let chunk_id = insert_stmt.insert(params![
+            start_message_index_id,
+            end_message_index_id,
+            data,
+            dictionary_id,
+            frame_offsets,
+        ])?;
+        debug!(chunk_id, "Chunk inserted");
+        
+        let mut update_stmt = tx.prepare_cached(
+            "UPDATE message_index
+            SET chunk_id = ?1
+            WHERE message_index_id BETWEEN ?2 AND ?3"
+        )?;
+        update_stmt.execute(params![
+            chunk_id,
+            start_message_index_id,
+            end_message_index_id,
+        ])?;
+
+        let mut delete_stmt = tx.prepare_cached(
+            "DELETE FROM message_chunk_temp
+            WHERE message_index_id BETWEEN ?1 AND ?2"
+        )?;
+        delete_stmt.execute(params![
+            start_message_index_id,
+            end_message_index_id,
+        ])?;
+    }
+
+    tx.commit()?;
+
+    Ok(more)
+}
+
+/// Trains a new zstd dictionary from a sample of the logged message bodies and stores
+/// it as the next version. Chunks compressed from then on use the new dictionary.
+///
+/// Returns the id of the new dictionary or None if there weren't enough messages to
+/// sample
+#[instrument(skip(db))]
+pub fn train_dictionary(db: &Connection) -> Result<Option<u64>, Error> {
+    let mut samples: Vec<Vec<u8>> = db
+        .prepare(
+            "SELECT message_body
+            FROM message_chunk_temp
+            ORDER BY random()
+            LIMIT ?1",
+        )?
+        .query_map(params![DICTIONARY_SAMPLE_COUNT], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    // Top up from the newest chunks if there aren't enough uncompressed messages
+    if (samples.len() as u64) < DICTIONARY_SAMPLE_COUNT {
+        let mut stmt = db.prepare(
+            "SELECT data, dictionary_id FROM message_chunk ORDER BY chunk_id DESC",
+        )?;
+        let mut rows = stmt.query(())?;
+        while let Some(r) = rows.next()? {
+            let dictionary = get_dictionary(db, r.get(1)?)?;
+            samples.extend(
+                decode_chunk(r.get(0)?, dictionary.as_deref())?
+                    .into_iter()
+                    .filter(|entry| entry.len() > 0),
+            );
+            if samples.len() as u64 >= DICTIONARY_SAMPLE_COUNT {
+                break;
+            }
+        }
+    }
+    samples.truncate(DICTIONARY_SAMPLE_COUNT as usize);
+    // The dictionary should match what's being compressed now
+    let samples = samples
+        .into_iter()
+        .map(message_record::upgrade)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if samples.len() < DICTIONARY_MIN_SAMPLE_COUNT {
+        warn!("Only {} messages to sample, not training a dictionary", samples.len());
+        return Ok(None);
+    }
+
+    let dictionary = zstd::dict::from_samples(&samples, DICTIONARY_MAX_SIZE)?;
+    let dictionary_id = db
+        .prepare(
+            "INSERT INTO message_dictionary (created, sample_count, data)
+            VALUES (?1, ?2, ?3)",
+        )?
+        .insert(params![
+            &Timestamp::now().to_rfc3339(),
+            samples.len() as u64,
+            dictionary,
+        ])? as u64;
+
+    info!(dictionary_id, sample_count = samples.len(), size = dictionary.len(), "Dictionary trained");
+
+    Ok(Some(dictionary_id))
+}
+
+/// Re-compresses one of the chunks that isn't using the latest dictionary with it.
+///
+/// Returns true if there are more chunks waiting to be recompressed
+#[instrument(skip(db))]
+pub fn recompress_chunk(db: &Connection) -> Result<bool, Error> {
+    let (dictionary_id, dictionary) = match latest_dictionary(db)? {
+        Some(v) => v,
+        None => return Ok(false),
+    };
+
+    let next = db
+        .prepare_cached(
+            "SELECT chunk_id, data, dictionary_id, compression_level
+            FROM message_chunk
+            WHERE dictionary_id IS NULL OR dictionary_id != ?1
+            LIMIT 1",
+        )?
+        .query_row(params![dictionary_id], |r| Ok((
+            r.get::<_, u64>(0)?,
+            r.get::<_, Vec<u8>>(1)?,
+            r.get::<_, Option<u64>>(2)?,
+            r.get::<_, Option<i32>>(3)?,
+        )))
+        .optional()?;
+
+    let (chunk_id, data, old_dictionary_id, level) = match next {
+        Some(v) => v,
+        None => return Ok(false),
+    };
+
+    let old_size = data.len();
+    let old_dictionary = get_dictionary(db, old_dictionary_id)?;
+    let entries = decode_chunk(data, old_dictionary.as_deref())?;
+    let (compressed, frame_offsets) = encode_chunk(&entries, Some(&dictionary), level.unwrap_or(COMPRESSION_LEVEL))?;
+    debug!(chunk_id, old_size, new_size = compressed.len(), "Chunk recompressed");
+
+    db.prepare_cached(
+        "UPDATE message_chunk
+        SET data = ?2, dictionary_id = ?3, frame_offsets = ?4
+        WHERE chunk_id = ?1",
+    )?
+    .execute(params![chunk_id, compressed, dictionary_id, frame_offsets])?;
+
+    Ok(db
+        .prepare_cached(
+            "SELECT 1 FROM message_chunk
+            WHERE dictionary_id IS NULL OR dictionary_id != ?1
+            LIMIT 1",
+        )?
+        .exists(params![dictionary_id])?)
+}
+
+/// Chunks that are only made up of messages from before the cutoff and haven't been
+/// recompressed yet. Chunks still waiting on a backfill are left alone so their chunk_id
+/// stays valid
+const COLD_CHUNK_SQL: &str = "
+    SELECT chunk_id, start_message_index_id, end_message_index_id, data, dictionary_id
+    FROM message_chunk
+    WHERE
+        (compression_level IS NULL OR compression_level < ?1)
+        AND (?2 IS NULL OR end_message_index_id < ?2)
+        AND chunk_id NOT IN (SELECT chunk_id FROM message_search_backfill)
+        AND chunk_id NOT IN (SELECT chunk_id FROM message_record_backfill)";
+
+/// Entries are logged in order so everything before the first entry for a message sent
+/// after the cutoff is cold. None means there's nothing that recent
+fn cold_cutoff_index_id(db: &Connection, cutoff: MessageId) -> Result<Option<u64>, Error> {
+    Ok(db
+        .prepare_cached("SELECT min(message_index_id) FROM message_index WHERE message_id >= ?1")?
+        .query_row(params![cutoff], |r| r.get(0))?)
+}
+
+/// Recompresses the oldest chunk that only has messages from before the cutoff at
+/// `COLD_COMPRESSION_LEVEL`, see `prepare_cold_chunk`.
+///
+/// Returns true if there are more chunks waiting to be recompressed
+#[instrument(skip(db))]
+pub fn recompress_cold_chunk(db: &mut Connection, cutoff: MessageId, merge: bool) -> Result<bool, Error> {
+    match prepare_cold_chunk(db, cutoff, merge)? {
+        Some(chunk) => commit_rewritten_chunk(db, chunk)?,
+        None => return Ok(false),
+    };
 
+    Ok(db
+        .prepare_cached(&format!("{COLD_CHUNK_SQL} LIMIT 1"))?
+        .exists(params![COLD_COMPRESSION_LEVEL, cold_cutoff_index_id(db, cutoff)?])?)
+}
+
+/// Builds the `COLD_COMPRESSION_LEVEL` version of the oldest chunk that only has messages
+/// from before the cutoff. If `merge` is set, the chunks directly after it are folded in
+/// while the combined messages still fit in `MESSAGE_LOG_CHUNK_SIZE`. That includes
+/// chunks that are already cold, which will have shrunk if retention or forget_user
+/// blanked some of their entries. This only reads from the database so the slow, high
+/// level compression can run on the compression worker's connection
+#[instrument(skip(db))]
+pub fn prepare_cold_chunk(db: &Connection, cutoff: MessageId, merge: bool) -> Result<Option<RewrittenChunk>, Error> {
+    let cutoff_index_id = cold_cutoff_index_id(db, cutoff)?;
+
+    fn read_chunk(r: &rusqlite::Row) -> rusqlite::Result<(u64, u64, u64, Vec<u8>, Option<u64>)> {
+        Ok((
+            r.get(0)?,
+            r.get(1)?,
+            r.get(2)?,
+            r.get(3)?,
+            r.get(4)?,
+        ))
+    }
+
+    let next = db
+        .prepare_cached(&format!("{COLD_CHUNK_SQL} ORDER BY chunk_id LIMIT 1"))?
+        .query_row(params![COLD_COMPRESSION_LEVEL, cutoff_index_id], read_chunk)
+        .optional()?;
+
+    let (chunk_id, start_id, mut end_id, data, dictionary_id) = match next {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let mut entries = decode_chunk(data.clone(), get_dictionary(db, dictionary_id)?.as_deref())?;
+    ensure!(
+        entries.len() as u64 == end_id - start_id + 1,
+        "Chunk ({chunk_id}) has {} entries but its bounds ({start_id} -> {end_id}) need {}",
+        entries.len(),
+        end_id - start_id + 1
+    );
+    let mut sources = vec![(chunk_id, data)];
+
+    if merge {
+        let mut size: usize = entries.iter().map(|e| e.len()).sum();
+        // The compressed size is never meaningfully bigger than the messages in it so
+        // chunks that are clearly too big can be skipped without decompressing them
+        let mut next_stmt = db.prepare_cached(
+            "SELECT chunk_id, start_message_index_id, end_message_index_id, data, dictionary_id
+            FROM message_chunk
+            WHERE
+                start_message_index_id = ?1
+                AND (?2 IS NULL OR end_message_index_id < ?2)
+                AND length(data) <= ?3
+                AND chunk_id NOT IN (SELECT chunk_id FROM message_search_backfill)
+                AND chunk_id NOT IN (SELECT chunk_id FROM message_record_backfill)
+            LIMIT 1",
+        )?;
+        while let Some((next_id, next_start_id, next_end_id, data, dictionary_id)) = next_stmt
+            .query_row(
+                params![end_id + 1, cutoff_index_id, (MESSAGE_LOG_CHUNK_SIZE as usize).saturating_sub(size) as u64],
+                read_chunk,
+            )
+            .optional()?
         {
-            let mut insert_stmt = tx.prepare_cached(
-                "INSERT INTO message_chunk (start_message_index_id, end_message_index_id, data)
-                VALUES (?1, ?2, ?3)"
-            )?;
-            let chunk_id = insert_stmt.insert(params![
-                start_message_index_id,
-                end_message_index_id,
-                compressed,
-            ])?;
-            debug!(chunk_id, "Chunk inserted");
-            
-            let mut update_stmt = tx.prepare_cached(
-                "UPDATE message_index
-                SET chunk_id = ?1
-                WHERE message_index_id BETWEEN ?2 AND ?3"
-            )?;
-            update_stmt.execute(params![
-                chunk_id,
-                start_message_index_id,
-                end_message_index_id,
-            ])?;
-
-            let mut delete_stmt = tx.prepare_cached(
-                "DELETE FROM message_chunk_temp
-                WHERE message_index_id BETWEEN ?1 AND ?2"
-            )?;
-            delete_stmt.execute(params![
-                start_message_index_id,
-                end_message_index_id,
-            ])?;
+            let next_entries = decode_chunk(data.clone(), get_dictionary(db, dictionary_id)?.as_deref())?;
+            let next_size: usize = next_entries.iter().map(|e| e.len()).sum();
+            if size + next_size > MESSAGE_LOG_CHUNK_SIZE as usize
+                || next_entries.len() as u64 != next_end_id - next_start_id + 1
+            {
+                break;
+            }
 
+            size += next_size;
+            entries.extend(next_entries);
+            end_id = next_end_id;
+            sources.push((next_id, data));
         }
+    }
 
-        tx.commit()?;
+    let dictionary = latest_dictionary(db)?;
+    let (compressed, frame_offsets) = encode_chunk(
+        &entries,
+        dictionary.as_ref().map(|(_, d)| d.as_slice()),
+        COLD_COMPRESSION_LEVEL,
+    )?;
+    debug!(
+        chunk_id,
+        merged = ?sources.iter().skip(1).map(|(id, _)| *id).collect::<Vec<_>>(),
+        entries = entries.len(),
+        compressed_size = compressed.len(),
+        "Cold chunk recompressed"
+    );
+
+    Ok(Some(RewrittenChunk {
+        chunk_id,
+        sources,
+        end_message_index_id: end_id,
+        data: compressed,
+        dictionary_id: dictionary.map(|(id, _)| id),
+        compression_level: Some(COLD_COMPRESSION_LEVEL),
+        frame_offsets,
+        records_converted: false,
+    }))
+}
 
-        let more = uncompressed_size - remaining_bytes as u64 > MESSAGE_LOG_CHUNK_SIZE;
-        Ok(more)
+/// Loads the dictionary the chunk was compressed with
+fn get_dictionary(db: &Connection, dictionary_id: Option<u64>) -> Result<Option<Vec<u8>>, Error> {
+    if let Some(dictionary_id) = dictionary_id {
+        Ok(Some(db
+            .prepare_cached("SELECT data FROM message_dictionary WHERE dictionary_id = ?1")?
+            .query_row(params![dictionary_id], |r| r.get(0))
+            .with_context(|| format!("Failed to load message_dictionary ({dictionary_id})"))?))
     } else {
-        Ok(false)
+        Ok(None)
+    }
+}
+
+/// The newest dictionary, which is the one new chunks are compressed with
+fn latest_dictionary(db: &Connection) -> Result<Option<(u64, Vec<u8>)>, Error> {
+    Ok(db
+        .prepare_cached(
+            "SELECT dictionary_id, data FROM message_dictionary
+            ORDER BY dictionary_id DESC
+            LIMIT 1",
+        )?
+        .query_row((), |r| Ok((r.get(0)?, r.get(1)?)))
+        .optional()?)
+}
+
+fn chunk_encoder<W: Write>(writer: W, dictionary: Option<&[u8]>, level: i32) -> Result<Encoder<'static, W>, Error> {
+    Ok(match dictionary {
+        Some(dictionary) => Encoder::with_dictionary(writer, level, dictionary)?,
+        None => Encoder::new(writer, level)?,
+    })
+}
+
+/// Decompresses the chunk data into `out`, returning the number of bytes read
+fn decompress_chunk(data: Vec<u8>, dictionary: Option<&[u8]>, out: &mut Vec<u8>) -> Result<usize, Error> {
+    Ok(match dictionary {
+        Some(dictionary) => Decoder::with_dictionary(Cursor::new(data), dictionary)?.read_to_end(out)?,
+        None => Decoder::new(Cursor::new(data))?.read_to_end(out)?,
+    })
+}
+
+/// Splits a chunk back into its entries. An empty entry is a message without a body
+fn decode_chunk(data: Vec<u8>, dictionary: Option<&[u8]>) -> Result<Vec<Vec<u8>>, Error> {
+    let mut zstd_buffer = Vec::with_capacity(MESSAGE_LOG_CHUNK_SIZE as usize);
+    decompress_chunk(data, dictionary, &mut zstd_buffer)?;
+
+    let mut entries = Vec::new();
+    let mut start_i = 0;
+    for (end_i, _) in zstd_buffer
+        .iter()
+        .enumerate()
+        .filter(|(_, byte)| **byte == corncobs::ZERO)
+    {
+        let mut entry = Vec::new();
+        corncobs::decode(&zstd_buffer[start_i..=end_i], &mut entry)?;
+        entries.push(entry);
+        start_i = end_i + 1;
+    }
+
+    Ok(entries)
+}
+
+/// The inverse of `decode_chunk`. Returns the compressed data and the packed frame
+/// offsets
+fn encode_chunk(entries: &[Vec<u8>], dictionary: Option<&[u8]>, level: i32) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut compressed = Vec::<u8>::with_capacity(MESSAGE_LOG_CHUNK_SIZE as usize);
+    let mut cobs_buffer = Vec::new();
+    let mut offsets = Vec::with_capacity(entries.len());
+    let mut offset = 0;
+    let mut encoder = chunk_encoder(&mut compressed, dictionary, level)?;
+
+    for data in entries.iter() {
+        cobs_buffer.resize_with(corncobs::max_encoded_len(data.len()), Default::default);
+        let cobs_len = corncobs::encode_buf(data, &mut cobs_buffer);
+        offsets.push(offset);
+        offset += cobs_len as u32;
+        encoder.write_all(&cobs_buffer[..cobs_len])?;
+    }
+    encoder.finish()?;
+
+    Ok((compressed, encode_frame_offsets(&offsets)))
+}
+
+/// Re-compresses the chunk with the entries for `message_index_ids` emptied. The
+/// entries are blanked rather than removed so every id from the start to the end of
+/// the chunk still has an entry at the expected position.
+///
+/// Returns the new data and frame offsets along with the start and end ids of the chunk
+/// and the search index entries of the blanked messages
+fn blank_chunk_entries(
+    db: &Connection,
+    chunk_id: u64,
+    message_index_ids: &HashSet<u64>,
+) -> Result<((Vec<u8>, Vec<u8>), u64, u64, Vec<(u64, String)>), Error> {
+    let (start_id, end_id, data, dictionary_id, level) = db
+        .prepare_cached(
+            "SELECT start_message_index_id, end_message_index_id, data, dictionary_id, compression_level
+            FROM message_chunk
+            WHERE chunk_id = ?1",
+        )?
+        .query_row(params![chunk_id], |r| Ok((
+            r.get::<_, u64>(0)?,
+            r.get::<_, u64>(1)?,
+            r.get::<_, Vec<u8>>(2)?,
+            r.get::<_, Option<u64>>(3)?,
+            r.get::<_, Option<i32>>(4)?,
+        )))?;
+
+    // The chunk keeps the dictionary and level it was compressed with
+    let dictionary = get_dictionary(db, dictionary_id)?;
+    let mut entries = decode_chunk(data, dictionary.as_deref())?;
+    let count_target = end_id - start_id + 1;
+    ensure!(
+        entries.len() as u64 == count_target,
+        "Chunk ({chunk_id}) has {} entries but its bounds ({start_id} -> {end_id}) need {count_target}",
+        entries.len()
+    );
+
+    // Chunks waiting to be backfilled haven't been added to the search index yet
+    let indexed = !db
+        .prepare_cached("SELECT 1 FROM message_search_backfill WHERE chunk_id = ?1")?
+        .exists(params![chunk_id])?;
+
+    let mut search_entries = Vec::new();
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let message_index_id = start_id + i as u64;
+        if message_index_ids.contains(&message_index_id) {
+            if indexed && entry.len() > 0 {
+                search_entries.extend(search_entry(message_index_id, entry)?);
+            }
+            entry.clear();
+        }
+    }
+
+    let level = level.unwrap_or(COMPRESSION_LEVEL);
+    Ok((encode_chunk(&entries, dictionary.as_deref(), level)?, start_id, end_id, search_entries))
+}
+
+/// The search index entry `index_for_search` would have added for the body, if any
+fn search_entry(message_index_id: u64, body: &[u8]) -> Result<Option<(u64, String)>, Error> {
+    let message = message_record::decode_message(body)
+        .with_context(|| format!("Failed to decode message_index ({message_index_id}) to remove it from the search index"))?;
+    Ok(message.guild_id.map(|_| (message_index_id, message.content)))
+}
+
+/// Deletes the message_index rows along with their bodies and search entries.
+/// Compressed chunks have the deleted entries blanked out and are dropped completely
+/// once nothing refers to them any more
+fn delete_entries(
+    db: &mut Connection,
+    entries: &[(u64, Option<u64>)],
+) -> Result<DeleteReceipt, Error> {
+    let mut receipt = DeleteReceipt::default();
+
+    let mut by_chunk: HashMap<u64, HashSet<u64>> = HashMap::new();
+    for (message_index_id, chunk_id) in entries.iter() {
+        if let Some(chunk_id) = chunk_id {
+            by_chunk.entry(*chunk_id).or_default().insert(*message_index_id);
+        }
+    }
+
+    // The re-compression is CPU bound so it's done before the transaction is started
+    let mut search_entries = Vec::new();
+    let rewritten = by_chunk
+        .iter()
+        .map(|(chunk_id, ids)| {
+            blank_chunk_entries(db, *chunk_id, ids)
+                .map(|((data, frame_offsets), start_id, end_id, indexed)| {
+                    search_entries.extend(indexed);
+                    (*chunk_id, data, frame_offsets, start_id, end_id)
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    {
+        let mut temp_body_stmt = db.prepare_cached(
+            "SELECT message_body FROM message_chunk_temp WHERE message_index_id = ?1",
+        )?;
+        for (message_index_id, _) in entries.iter().filter(|(_, chunk_id)| chunk_id.is_none()) {
+            let body = temp_body_stmt
+                .query_row(params![message_index_id], |r| r.get::<_, Vec<u8>>(0))
+                .optional()?;
+            if let Some(body) = body {
+                search_entries.extend(search_entry(*message_index_id, &body)?);
+            }
+        }
+    }
+
+    let tx = db.transaction()?;
+    {
+        let mut delete_temp_stmt = tx.prepare_cached(
+            "DELETE FROM message_chunk_temp WHERE message_index_id = ?1",
+        )?;
+        let mut delete_index_stmt = tx.prepare_cached(
+            "DELETE FROM message_index WHERE message_index_id = ?1",
+        )?;
+        for (message_index_id, _) in entries.iter() {
+            receipt.uncompressed_bodies += delete_temp_stmt.execute(params![message_index_id])? as u64;
+            receipt.index_entries += delete_index_stmt.execute(params![message_index_id])? as u64;
+        }
+        receipt.search_entries = remove_from_search(&tx, &search_entries)?;
+
+        for (chunk_id, data, frame_offsets, start_id, end_id) in rewritten.into_iter() {
+            let in_use = tx
+                .prepare_cached(
+                    "SELECT 1 FROM message_index
+                    WHERE message_index_id BETWEEN ?1 AND ?2
+                    LIMIT 1",
+                )?
+                .exists(params![start_id, end_id])?;
+
+            if in_use {
+                tx.prepare_cached("UPDATE message_chunk SET data = ?2, frame_offsets = ?3 WHERE chunk_id = ?1")?
+                    .execute(params![chunk_id, data, frame_offsets])?;
+                debug!(chunk_id, "Chunk rewritten");
+                receipt.chunks_rewritten += 1;
+            } else {
+                tx.prepare_cached("DELETE FROM message_search_backfill WHERE chunk_id = ?1")?
+                    .execute(params![chunk_id])?;
+                tx.prepare_cached("DELETE FROM message_record_backfill WHERE chunk_id = ?1")?
+                    .execute(params![chunk_id])?;
+                tx.prepare_cached("DELETE FROM message_chunk WHERE chunk_id = ?1")?
+                    .execute(params![chunk_id])?;
+                debug!(chunk_id, "Chunk dropped");
+                receipt.chunks_dropped += 1;
+            }
+        }
+    }
+    tx.commit()?;
+
+    Ok(receipt)
+}
+
+/// Deletes the log entries for messages in the guild sent before `cutoff`.
+///
+/// Returns true if there are more expired entries left to delete
+#[instrument(skip(db))]
+pub fn apply_retention(
+    db: &mut Connection,
+    guild_id: GuildId,
+    cutoff: MessageId,
+) -> Result<bool, Error> {
+    let expired: Vec<(u64, Option<u64>)> = db
+        .prepare_cached(
+            "SELECT message_index_id, chunk_id FROM message_index
+            WHERE guild_id = ?1 AND message_id < ?2
+            ORDER BY message_index_id
+            LIMIT ?3",
+        )?
+        .query_map(params![guild_id, cutoff, DELETE_BATCH_SIZE], |r| {
+            Ok((r.get(0)?, r.get(1)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    if expired.len() == 0 {
+        return Ok(false);
+    }
+
+    let receipt = delete_entries(db, &expired)?;
+    info!(?receipt, "Expired message log entries deleted");
+
+    Ok(expired.len() as u64 == DELETE_BATCH_SIZE)
+}
+
+/// Deletes a batch of the log entries for messages sent by the user, in one guild
+/// or all of them. Chunks still waiting to be backfilled are done first, one per call,
+/// as the user's entries in them can't be found until they are.
+///
+/// Returns true if there's more left to do
+#[instrument(skip(db))]
+pub fn forget_user(
+    db: &mut Connection,
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+) -> Result<(DeleteReceipt, bool), Error> {
+    if db
+        .prepare_cached("SELECT 1 FROM message_search_backfill LIMIT 1")?
+        .exists(())?
+    {
+        backfill_chunk(db)?;
+        return Ok((DeleteReceipt::default(), true));
+    }
+
+    let entries: Vec<(u64, Option<u64>)> = db
+        .prepare_cached(
+            "SELECT message_index_id, chunk_id FROM message_index
+            WHERE user_id = ?1 AND (?2 IS NULL OR guild_id = ?2)
+            ORDER BY message_index_id
+            LIMIT ?3",
+        )?
+        .query_map(params![user_id, guild_id, DELETE_BATCH_SIZE], |r| {
+            Ok((r.get(0)?, r.get(1)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    if entries.len() == 0 {
+        return Ok((DeleteReceipt::default(), false));
+    }
+
+    let receipt = delete_entries(db, &entries)?;
+    Ok((receipt, entries.len() as u64 == DELETE_BATCH_SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_utils::{test_database, test_message};
+
+    const GUILD: u64 = 1;
+    const CHANNEL: u64 = 2;
+    const FORGOTTEN: u64 = 3;
+    const KEPT: u64 = 4;
+    // Big enough that the messages below fill a chunk with some left over
+    const MESSAGE_COUNT: u64 = 60;
+    const PADDING: usize = 2000;
+
+    fn log_messages(db: &mut Connection) {
+        for i in 0..MESSAGE_COUNT {
+            let (user_id, word) = if i % 2 == 0 { (FORGOTTEN, "apple") } else { (KEPT, "banana") };
+            let content = format!("{word} {}", "x".repeat(PADDING));
+            let message = test_message(1000 + i, GUILD, CHANNEL, user_id, &content);
+            log(db, message.id.into(), message.timestamp, LogType::Create, Some(message)).unwrap();
+        }
+    }
+
+    fn search_count(db: &Connection, cache: &mut ChunkCache, text: &str) -> u64 {
+        let query = MessageSearchQuery {
+            guild_id: GuildId::from(GUILD),
+            text: Some(text.to_string()),
+            user_id: None,
+            channel_id: None,
+            after_id: None,
+            before_id: None,
+            channel_ids: None,
+        };
+        search(db, cache, query, 100, 0).unwrap().0
+    }
+
+    fn first_chunk(db: &Connection) -> (u64, u64, u64) {
+        db.query_row(
+            "SELECT chunk_id, start_message_index_id, end_message_index_id
+            FROM message_chunk
+            ORDER BY chunk_id
+            LIMIT 1",
+            (),
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn repair_quarantines_corrupt_chunks() {
+        let mut db = test_database();
+        log_messages(&mut db);
+        compress(&mut db).unwrap();
+        assert!(verify_compressed_chunks(&db).unwrap().is_ok());
+
+        let (chunk_id, start_id, end_id) = first_chunk(&db);
+        db.execute("UPDATE message_chunk SET data = x'00' WHERE chunk_id = ?1", [chunk_id]).unwrap();
+        let report = verify_compressed_chunks(&db).unwrap();
+        assert_eq!(report.bad_chunks.len(), 1);
+        assert_eq!(report.bad_chunks[0].chunk_id, chunk_id);
+
+        let receipt = repair_compressed_chunks(&mut db, &[chunk_id]).unwrap();
+        assert_eq!(receipt.chunks_quarantined, 1);
+        assert_eq!(receipt.index_entries_reassigned, end_id - start_id + 1);
+        assert_eq!(receipt.bodies_lost, end_id - start_id + 1);
+        assert!(verify_compressed_chunks(&db).unwrap().is_ok());
+        let quarantined: u64 = db
+            .query_row("SELECT count(1) FROM message_chunk_quarantine WHERE chunk_id = ?1", [chunk_id], |r| r.get(0))
+            .unwrap();
+        assert_eq!(quarantined, 1);
+
+        // It's gone so there's nothing left to do
+        let receipt = repair_compressed_chunks(&mut db, &[chunk_id]).unwrap();
+        assert_eq!(receipt.chunks_quarantined, 0);
+    }
+
+    #[test]
+    fn reassign_fixes_entries_pointing_at_the_wrong_chunk() {
+        let mut db = test_database();
+        log_messages(&mut db);
+        compress(&mut db).unwrap();
+
+        // The first of the messages that are still waiting to be compressed
+        let (chunk_id, _, end_id) = first_chunk(&db);
+        db.execute(
+            "UPDATE message_index SET chunk_id = ?1 WHERE message_index_id = ?2",
+            [chunk_id, end_id + 1],
+        )
+        .unwrap();
+        assert_eq!(verify_compressed_chunks(&db).unwrap().misplaced_index_entries, 1);
+
+        let mut receipt = RepairReceipt::default();
+        let mut after = Some(0);
+        while let Some(a) = after {
+            let (batch, next) = reassign_index_entries(&mut db, a).unwrap();
+            receipt.add(batch);
+            after = next;
+        }
+        assert_eq!(receipt.index_entries_reassigned, 1);
+        // Its body is still in message_chunk_temp
+        assert_eq!(receipt.bodies_lost, 0);
+        assert!(verify_compressed_chunks(&db).unwrap().is_ok());
+    }
+
+    #[test]
+    fn chunk_frames_round_trip_with_a_dictionary() {
+        // Raw content dictionaries are accepted by zstd so this doesn't need training
+        let dictionary = "a message that's logged ".repeat(64).into_bytes();
+        let entries = vec![
+            b"a message that's logged".to_vec(),
+            // Messages without a body are stored as an empty entry
+            Vec::new(),
+            vec![0, 1, 0, 0, 2],
+            vec![7; 300],
+        ];
+
+        let (compressed, frame_offsets) =
+            encode_chunk(&entries, Some(&dictionary), COMPRESSION_LEVEL).unwrap();
+        assert_eq!(decode_chunk(compressed.clone(), Some(&dictionary)).unwrap(), entries);
+
+        let mut data = Vec::new();
+        decompress_chunk(compressed, Some(&dictionary), &mut data).unwrap();
+        let offsets = decode_frame_offsets(&frame_offsets).unwrap();
+        assert_eq!(offsets, scan_frame_offsets(&data));
+
+        let chunk = CachedChunk::new(10, 13, data, offsets).unwrap();
+        for (message_index_id, entry) in (10..).zip(entries.iter()) {
+            let mut decoded = Vec::new();
+            corncobs::decode(chunk.frame(message_index_id).unwrap(), &mut decoded).unwrap();
+            assert_eq!(&decoded, entry);
+        }
+        assert!(chunk.frame(9).is_none());
+        assert!(chunk.frame(14).is_none());
+    }
+
+    #[test]
+    fn forget_user_removes_compressed_and_uncompressed_entries() {
+        let mut db = test_database();
+        let mut cache = ChunkCache::new(4);
+        log_messages(&mut db);
+        assert!(compress(&mut db).unwrap() == false);
+        assert!(get_compression_state(&db).unwrap().chunks > 0);
+        assert!(get_compression_state(&db).unwrap().uncompressed_messages > 0);
+
+        // An edit so the user has more than one revision of a message
+        let edit = test_message(1000, GUILD, CHANNEL, FORGOTTEN, "apple edited");
+        log(&mut db, edit.id.into(), edit.timestamp, LogType::Edit, Some(edit)).unwrap();
+        assert_eq!(search_count(&db, &mut cache, "apple"), MESSAGE_COUNT / 2);
+
+        let mut receipt = DeleteReceipt::default();
+        loop {
+            let (batch, more) = forget_user(&mut db, Some(GuildId::from(GUILD)), UserId::from(FORGOTTEN)).unwrap();
+            receipt.add(batch);
+            if !more {
+                break;
+            }
+        }
+        assert_eq!(receipt.index_entries, MESSAGE_COUNT / 2 + 1);
+        assert_eq!(receipt.search_entries, MESSAGE_COUNT / 2 + 1);
+        assert!(receipt.chunks_rewritten > 0);
+
+        let forgotten = get_by_user(&db, &mut cache, GuildId::from(GUILD), UserId::from(FORGOTTEN), None, 1000).unwrap();
+        assert_eq!(forgotten.len(), 0);
+        assert_eq!(search_count(&db, &mut cache, "apple"), 0);
+
+        // Everyone else's messages can still be read and searched
+        let kept = get_by_user(&db, &mut cache, GuildId::from(GUILD), UserId::from(KEPT), None, 1000).unwrap();
+        assert_eq!(kept.len() as u64, MESSAGE_COUNT / 2);
+        assert!(kept.iter().all(|m| m.message.as_ref().map_or(false, |m| m.content.starts_with("banana"))));
+        assert_eq!(search_count(&db, &mut cache, "banana"), MESSAGE_COUNT / 2);
+        assert!(verify_compressed_chunks(&db).unwrap().is_ok());
+
+        db.execute("INSERT INTO message_search (message_search) VALUES ('integrity-check')", ())
+            .unwrap();
+    }
+
+    #[test]
+    fn search_only_returns_messages_in_the_given_channels() {
+        const OTHER_CHANNEL: u64 = 5;
+        let mut db = test_database();
+        let mut cache = ChunkCache::new(4);
+        for (i, channel) in [CHANNEL, OTHER_CHANNEL, CHANNEL].into_iter().enumerate() {
+            let message = test_message(1000 + i as u64, GUILD, channel, KEPT, "apple");
+            log(&mut db, message.id.into(), message.timestamp, LogType::Create, Some(message)).unwrap();
+        }
+
+        let query = |channel_ids: Option<Vec<ChannelId>>| MessageSearchQuery {
+            guild_id: GuildId::from(GUILD),
+            text: Some("apple".to_string()),
+            user_id: None,
+            channel_id: None,
+            after_id: None,
+            before_id: None,
+            channel_ids,
+        };
+
+        let (total, results) = search(&db, &mut cache, query(None), 100, 0).unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(results.len(), 3);
+
+        let (total, results) = search(&db, &mut cache, query(Some(vec![ChannelId::from(OTHER_CHANNEL)])), 100, 0).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(results[0].channel_id, ChannelId::from(OTHER_CHANNEL));
+
+        let (total, results) = search(&db, &mut cache, query(Some(Vec::new())), 100, 0).unwrap();
+        assert_eq!(total, 0);
+        assert_eq!(results.len(), 0);
+    }
+
+    #[test]
+    fn rewrites_are_discarded_if_the_chunk_changed() {
+        let mut db = test_database();
+        let mut cache = ChunkCache::new(4);
+        log_messages(&mut db);
+        compress(&mut db).unwrap();
+        let (chunk_id, _, _) = first_chunk(&db);
+        let cutoff = MessageId::from_unix_millis(Timestamp::now().unix_timestamp() * 1000);
+        let level = |db: &Connection| -> Option<i32> {
+            db.query_row("SELECT compression_level FROM message_chunk WHERE chunk_id = ?1", [chunk_id], |r| r.get(0))
+                .unwrap()
+        };
+
+        // Built from the chunk before forget_user blanks some of its entries
+        let rewrite = prepare_cold_chunk(&db, cutoff, false).unwrap().unwrap();
+        while forget_user(&mut db, Some(GuildId::from(GUILD)), UserId::from(FORGOTTEN)).unwrap().1 {}
+        assert!(!commit_rewritten_chunk(&mut db, rewrite).unwrap());
+        assert_ne!(level(&db), Some(COLD_COMPRESSION_LEVEL));
+        assert_eq!(search_count(&db, &mut cache, "apple"), 0);
+
+        // Rebuilding it from what's there now goes through
+        let rewrite = prepare_cold_chunk(&db, cutoff, false).unwrap().unwrap();
+        assert!(commit_rewritten_chunk(&mut db, rewrite).unwrap());
+        assert_eq!(level(&db), Some(COLD_COMPRESSION_LEVEL));
+        assert!(verify_compressed_chunks(&db).unwrap().is_ok());
+        let kept = get_by_user(&db, &mut cache, GuildId::from(GUILD), UserId::from(KEPT), None, 1000).unwrap();
+        assert!(kept.iter().all(|m| m.message.as_ref().map_or(false, |m| m.content.starts_with("banana"))));
+        let forgotten = get_by_user(&db, &mut cache, GuildId::from(GUILD), UserId::from(FORGOTTEN), None, 1000).unwrap();
+        assert_eq!(forgotten.len(), 0);
     }
 }
@@ -2,8 +2,11 @@ mod table_size;
 pub use table_size::*;
 
 pub mod config;
+pub mod confirmations;
+pub mod custom_ids;
 
 pub mod message_count;
 pub mod permissions;
 pub mod interaction_roles;
+pub mod reaction_roles;
 pub mod message_log;
@@ -71,11 +71,20 @@ pub enum Permission {
     #[name = "messages.purge"]
     MessagePurge,
 
+    #[name = "messages.search"]
+    MessageSearch,
+
     #[name = "member.add"]
     MemberAdd,
 
     #[name = "member.promote"]
     MemberPromote,
+
+    #[name = "member.forget"]
+    MemberForget,
+
+    #[name = "rolemenu.manage"]
+    RoleMenuManage,
 }
 
 impl ToSql for Permission {
@@ -0,0 +1,122 @@
+//! Reaction role sets imported from the old mongo database by `migrate_mongo`.
+//! These only exist until they are converted into interaction roles
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{ChannelId, Error, GuildId, MessageId, RoleId};
+
+#[derive(Debug, Clone)]
+pub struct ReactionRoleTemp {
+    pub guild_id: GuildId,
+    pub name: String,
+    pub exclusive: bool,
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    pub choices: Vec<ReactionRoleChoiceTemp>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReactionRoleChoiceTemp {
+    /// The emoji members reacted with. Either a unicode emoji or a custom emoji
+    /// in whatever form discord.js stored it
+    pub choice: String,
+    pub role_id: RoleId,
+}
+
+/// Gets all the sets for the guild, or for every guild if `guild_id` is None
+pub fn get_all(db: &Connection, guild_id: Option<GuildId>) -> Result<Vec<ReactionRoleTemp>, Error> {
+    let mut stmt = db.prepare_cached(
+        "SELECT guild_id, name, exclusive, channel_id, message_id
+            FROM reaction_role_temp
+            WHERE ?1 IS NULL OR guild_id = ?1
+            ORDER BY guild_id, name",
+    )?;
+
+    let mut sets = stmt
+        .query_map(params![guild_id], |r| {
+            Ok(ReactionRoleTemp {
+                guild_id: GuildId::from(r.get::<_, u64>(0)?),
+                name: r.get(1)?,
+                exclusive: r.get(2)?,
+                channel_id: r.get(3)?,
+                message_id: MessageId::from(r.get::<_, u64>(4)?),
+                choices: Vec::new(),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for set in sets.iter_mut() {
+        get_choices(db, set)?;
+    }
+
+    Ok(sets)
+}
+
+/// Gets the set that was posted as the given message
+pub fn get_by_message(
+    db: &Connection,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    message_id: MessageId,
+) -> Result<Option<ReactionRoleTemp>, Error> {
+    let mut stmt = db.prepare_cached(
+        "SELECT name, exclusive
+            FROM reaction_role_temp
+            WHERE guild_id = ?1 AND channel_id = ?2 AND message_id = ?3",
+    )?;
+
+    if let Some(mut set) = stmt
+        .query_row(params![guild_id, channel_id, message_id], |r| {
+            Ok(ReactionRoleTemp {
+                guild_id,
+                name: r.get(0)?,
+                exclusive: r.get(1)?,
+                channel_id,
+                message_id,
+                choices: Vec::new(),
+            })
+        })
+        .optional()?
+    {
+        get_choices(db, &mut set)?;
+        Ok(Some(set))
+    } else {
+        Ok(None)
+    }
+}
+
+fn get_choices(db: &Connection, set: &mut ReactionRoleTemp) -> Result<(), Error> {
+    let mut stmt = db.prepare_cached(
+        "SELECT choice, role_id FROM reaction_role_choice_temp
+            WHERE guild_id = ?1 AND set_name = ?2
+            ORDER BY choice",
+    )?;
+
+    for choice in stmt.query_map(params![set.guild_id, &set.name], |r| {
+        Ok(ReactionRoleChoiceTemp {
+            choice: r.get(0)?,
+            role_id: r.get(1)?,
+        })
+    })? {
+        set.choices.push(choice?);
+    }
+
+    Ok(())
+}
+
+pub fn delete(db: &mut Connection, guild_id: GuildId, name: String) -> Result<bool, Error> {
+    let tx = db.transaction()?;
+
+    tx.execute(
+        "DELETE FROM reaction_role_choice_temp WHERE guild_id = ?1 AND set_name = ?2",
+        params![guild_id, &name],
+    )?;
+    let deleted = tx.execute(
+        "DELETE FROM reaction_role_temp WHERE guild_id = ?1 AND name = ?2",
+        params![guild_id, &name],
+    )? > 0;
+
+    tx.commit()?;
+
+    Ok(deleted)
+}
@@ -0,0 +1,38 @@
+//! Helpers shared by the database tests
+use poise::serenity_prelude::Message;
+use rusqlite::Connection;
+
+use crate::db::{
+    message_record::{AuthorRecord, MessageRecordV1},
+    open_database,
+};
+
+/// A fully migrated in memory database
+pub fn test_database() -> Connection {
+    open_database(":memory:", true, true).expect("Failed to open test database")
+}
+
+/// A guild message with just enough filled in to be logged
+pub fn test_message(message_id: u64, guild_id: u64, channel_id: u64, user_id: u64, content: &str) -> Message {
+    MessageRecordV1 {
+        id: message_id,
+        channel_id,
+        guild_id: Some(guild_id),
+        author: AuthorRecord {
+            id: user_id,
+            name: format!("user{user_id}"),
+            discriminator: 1,
+            bot: false,
+        },
+        content: content.to_string(),
+        timestamp: "2023-01-01T00:00:00+00:00".to_string(),
+        edited_timestamp: None,
+        attachments: Vec::new(),
+        embeds: Vec::new(),
+        mentions: Vec::new(),
+        mention_roles: Vec::new(),
+        reference: None,
+    }
+    .to_message()
+    .expect("Failed to build test message")
+}
@@ -26,6 +26,18 @@ use purge::*;
 mod add_member;
 use add_member::*;
 
+mod role_menu;
+use role_menu::*;
+
+mod search;
+use search::*;
+
+mod user_data;
+use user_data::*;
+
+mod transcript;
+use transcript::*;
+
 pub fn commands() -> Vec<Command<BotData, PoiseError>> {
     vec![
         help(),
@@ -46,11 +58,17 @@ pub fn commands() -> Vec<Command<BotData, PoiseError>> {
         purge_permission(),
         get_table_sizes(),
         get_disk_space(),
+        backup_now(),
         promote(),
         config_help(),
         purge(),
         add_member(),
         get_compression_state(),
+        rolemenu(),
+        search_messages(),
+        forget_user(),
+        mydata(),
+        transcript(),
     ]
 }
 
@@ -2,12 +2,16 @@ use std::{borrow::Cow, fmt::Write};
 
 use poise::{
     self,
-    serenity_prelude::{ButtonStyle, Member, RoleId},
+    serenity_prelude::{Member, RoleId},
 };
 
 use crate::{
-    db::queries::permissions::{Permission, PermissionCheck},
-    Context, Embed, EmbedFlavour, PoiseError,
+    commands::confirmation::request_confirmation,
+    db::queries::{
+        confirmations::ConfirmationAction,
+        permissions::{Permission, PermissionCheck},
+    },
+    Context, Embed, PoiseError,
 };
 
 #[poise::command(prefix_command, slash_command, guild_only, category = "Permission")]
@@ -136,99 +140,14 @@ pub async fn purge_permission(ctx: Context<'_>) -> Result<(), PoiseError> {
     ctx.defer_ephemeral().await?;
     ctx.require_permission(Permission::PermissionManage).await?;
 
-    let guild_id = ctx
-        .guild_id()
-        .expect("missing guild_id in 'guild_only' command");
-
-    let timestamp = ctx.created_at();
-
-    let reply = ctx.send(|m| m
-        .embed(|b| Embed::default()
-            .description("Are you sure you want for purge all permissions. If you aren't the server owner you will be locked out of the bot")
-            .create_embed(b)
-        )
-        .ephemeral(true)
-        .components(|c| c
-            .create_action_row(|r| r
-                .create_button(|b| b
-                    .custom_id("purge.ok")
-                    .label("Purge")
-                    .style(ButtonStyle::Danger)
-                )
-                .create_button(|b| b
-                    .custom_id("purge.cancel")
-                    .label("Cancel")
-                    .style(ButtonStyle::Secondary)
-                )
-            )
-        )
-    ).await?;
-
-    let interaction = reply
-        .message()
-        .await?
-        .await_component_interaction(ctx)
-        .author_id(ctx.author().id)
-        .await;
-
-    // Remove the buttons once one is clicked
-    reply
-        .edit(ctx, |b| {
-            b.components(|b| b).embed(|b| {
-                Embed::default()
-                    .description("Processing...")
-                    .create_embed(b)
-            })
-        })
-        .await?;
-
-    let do_purge = match &interaction {
-        Some(b) => b.data.custom_id == "purge.ok",
-        None => {
-            reply
-                .edit(ctx, |b| {
-                    b.components(|b| b).embed(|b| {
-                        Embed::error()
-                            .description("Interaction timed out")
-                            .create_embed(b)
-                    })
-                })
-                .await?;
-            return Ok(());
-        }
-    };
-
-    let (msg, err) = if do_purge {
-        match ctx
-            .data()
-            .purge_permission(guild_id.into(), timestamp)
-            .await
-        {
-            Ok(true) => ("Purged".to_string(), Some(false)),
-            Ok(false) => (
-                "Nothing to purge :person_shrugging:".to_string(),
-                Some(false),
-            ),
-            Err(e) => (format!("Error purging permission: {:?}", e), Some(true)),
-        }
-    } else {
-        ("Cancelled".to_string(), None)
-    };
-
-    reply
-        .edit(ctx, |b| {
-            b.components(|b| b).embed(|b| {
-                Embed::default()
-                    .description(msg)
-                    .flavour(match err {
-                        Some(false) => EmbedFlavour::Success,
-                        Some(true) => EmbedFlavour::Error,
-                        None => EmbedFlavour::Normal,
-                    })
-                    .create_embed(b)
-            })
-        })
-        .await?;
+    request_confirmation(
+        ctx,
+        ConfirmationAction::PermissionPurge,
+        serde_json::Value::Null,
+        "Are you sure you want for purge all permissions. If you aren't the server owner you will be locked out of the bot",
+        "Purge",
+    )
+    .await?;
 
     Ok(())
 }
@@ -0,0 +1,508 @@
+use poise::{
+    self,
+    serenity_prelude::{ChannelId, RoleId},
+    ChoiceParameter,
+};
+use tracing::warn;
+
+use crate::{
+    commands::interaction_roles::{
+        check_role_menu_role, parse_emoji, publish_interaction_role, rerender_interaction_role,
+        interaction_role_max_choices, validate_interaction_role_name, RoleAssigner,
+    },
+    commands::reaction_roles::{convert_reaction_roles, retire_legacy_set, ConvertMode},
+    db::queries::{
+        interaction_roles::{InteractionRole, InteractionRoleStyle},
+        permissions::{Permission, PermissionCheck},
+    },
+    Context, Embed, Error, GuildId, MessageId, PoiseError,
+};
+
+async fn get_menu(ctx: &Context<'_>, guild_id: GuildId, name: &str) -> Result<InteractionRole, Error> {
+    Ok(ctx
+        .data()
+        .get_interaction_role(guild_id, name.to_string())
+        .await?
+        .ok_or(anyhow::anyhow!("Role menu \"{}\" doesn't exist", name))?)
+}
+
+/// Formats the outcome of a re-render for appending to a reply
+fn rerender_msg(r: Result<Option<MessageId>, Error>) -> String {
+    match r {
+        Ok(Some(_)) => "\nPublished menu updated".to_string(),
+        Ok(None) => "\nMenu isn't published yet".to_string(),
+        Err(e) => {
+            warn!("Error re-rendering role menu: {:?}", e);
+            format!("\n:x: Failed to update the published menu: {}", e)
+        }
+    }
+}
+
+#[poise::command(
+    prefix_command,
+    slash_command,
+    guild_only,
+    category = "Role menu",
+    subcommands(
+        "rolemenu_create",
+        "rolemenu_add_choice",
+        "rolemenu_remove_choice",
+        "rolemenu_set_requirements",
+        "rolemenu_set_exclusive",
+        "rolemenu_set_style",
+        "rolemenu_publish",
+        "rolemenu_delete",
+        "rolemenu_import_legacy"
+    )
+)]
+/// Create, edit and publish self-service role menus
+pub async fn rolemenu(_ctx: Context<'_>) -> Result<(), PoiseError> {
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, rename = "create")]
+/// Create a new (unpublished) role menu
+pub async fn rolemenu_create(
+    ctx: Context<'_>,
+    #[description = "Name of the menu. Shown as the title of the menu message"] name: String,
+    #[description = "The channel the menu will be published in"] channel: ChannelId,
+    #[description = "Text shown above the buttons"] description: Option<String>,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::RoleMenuManage).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+
+    validate_interaction_role_name(&name)?;
+
+    if ctx
+        .data()
+        .get_interaction_role(guild_id, name.clone())
+        .await?
+        .is_some()
+    {
+        Err(anyhow::anyhow!("Role menu \"{}\" already exists", name))?;
+    }
+
+    ctx.data()
+        .update_interaction_role(
+            guild_id,
+            name.clone(),
+            description,
+            channel.into(),
+            None,
+            false,
+            ctx.created_at(),
+        )
+        .await?;
+
+    Embed::success()
+        .description(format!(
+            "Role menu \"{}\" created. Add some choices then publish it",
+            name
+        ))
+        .send(&ctx)
+        .await?;
+
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, rename = "add_choice")]
+/// Add (or update) a choice on a role menu
+pub async fn rolemenu_add_choice(
+    ctx: Context<'_>,
+    #[description = "Name of the menu"] name: String,
+    #[description = "Label shown on the button"] choice: String,
+    #[description = "The role the choice grants"] role: RoleId,
+    #[description = "Emoji shown on the button"] emoji: Option<String>,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::RoleMenuManage).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+    let timestamp = ctx.created_at();
+
+    let menu = get_menu(&ctx, guild_id, &name).await?;
+    if !menu.choices.iter().any(|c| c.choice == choice) {
+        let max_choices = interaction_role_max_choices(menu.style);
+        if menu.choices.len() >= max_choices {
+            Err(anyhow::anyhow!(
+                "{} role menus can have at most {} choices",
+                menu.style.name(),
+                max_choices
+            ))?;
+        }
+    }
+    if let Some(emoji) = emoji.as_ref() {
+        parse_emoji(emoji)?;
+    }
+
+    {
+        let guild = ctx
+            .guild()
+            .ok_or(anyhow::anyhow!("missing guild in rolemenu add_choice"))?;
+        let member = ctx
+            .author_member()
+            .await
+            .ok_or(anyhow::anyhow!("missing author_member in rolemenu add_choice"))?;
+        let assigner = if guild.owner_id == member.user.id {
+            RoleAssigner::Owner
+        } else {
+            RoleAssigner::Member(&member)
+        };
+        check_role_menu_role(guild_id, &guild.roles, assigner, role.into())?;
+    }
+
+    ctx.data()
+        .update_interaction_choice(
+            guild_id,
+            name.clone(),
+            choice.clone(),
+            emoji,
+            role.into(),
+            timestamp,
+        )
+        .await?;
+
+    let mut msg = format!("Choice \"{}\" for <@&{}> added to \"{}\"", choice, role, name);
+    msg.push_str(&rerender_msg(
+        rerender_interaction_role(&ctx, ctx.data(), guild_id, name).await,
+    ));
+
+    Embed::success().description(msg).send(&ctx).await?;
+
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, rename = "remove_choice")]
+/// Remove a choice from a role menu
+pub async fn rolemenu_remove_choice(
+    ctx: Context<'_>,
+    #[description = "Name of the menu"] name: String,
+    #[description = "Label of the choice to remove"] choice: String,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::RoleMenuManage).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+
+    get_menu(&ctx, guild_id, &name).await?;
+
+    let mut msg = if ctx
+        .data()
+        .delete_interaction_choice(guild_id, name.clone(), choice.clone())
+        .await?
+    {
+        format!("Choice \"{}\" removed from \"{}\"", choice, name)
+    } else {
+        format!("\"{}\" has no choice \"{}\" :person_shrugging:", name, choice)
+    };
+    msg.push_str(&rerender_msg(
+        rerender_interaction_role(&ctx, ctx.data(), guild_id, name).await,
+    ));
+
+    Embed::success().description(msg).send(&ctx).await?;
+
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, rename = "set_requirements")]
+/// Set what a member needs before they can pick a choice. Leave an option out to clear it
+pub async fn rolemenu_set_requirements(
+    ctx: Context<'_>,
+    #[description = "Name of the menu"] name: String,
+    #[description = "Label of the choice"] choice: String,
+    #[description = "Members must already have this role"] required_role: Option<RoleId>,
+    #[description = "Members with this role can't pick the choice"] forbidden_role: Option<RoleId>,
+    #[description = "Members must have posted at least this many messages"] min_messages: Option<usize>,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::RoleMenuManage).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+
+    let menu = get_menu(&ctx, guild_id, &name).await?;
+    if !menu.choices.iter().any(|c| c.choice == choice) {
+        Err(anyhow::anyhow!("\"{}\" has no choice \"{}\"", name, choice))?;
+    }
+
+    ctx.data()
+        .update_interaction_choice_requirements(
+            guild_id,
+            name.clone(),
+            choice.clone(),
+            required_role.map(|v| v.into()),
+            forbidden_role.map(|v| v.into()),
+            min_messages,
+            ctx.created_at(),
+        )
+        .await?;
+
+    let mut requirements = Vec::new();
+    if let Some(role) = required_role {
+        requirements.push(format!("must have <@&{}>", role));
+    }
+    if let Some(role) = forbidden_role {
+        requirements.push(format!("must not have <@&{}>", role));
+    }
+    if let Some(min_messages) = min_messages {
+        requirements.push(format!("must have posted {} messages", min_messages));
+    }
+
+    let msg = if requirements.len() > 0 {
+        format!(
+            "Members picking \"{}\" from \"{}\" now {}",
+            choice,
+            name,
+            requirements.join(", ")
+        )
+    } else {
+        format!("Requirements for \"{}\" on \"{}\" cleared", choice, name)
+    };
+
+    Embed::success().description(msg).send(&ctx).await?;
+
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, rename = "set_exclusive")]
+/// Set whether members can hold only one role from a role menu
+pub async fn rolemenu_set_exclusive(
+    ctx: Context<'_>,
+    #[description = "Name of the menu"] name: String,
+    #[description = "Only allow one role from the menu at a time"] exclusive: bool,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::RoleMenuManage).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+    let timestamp = ctx.created_at();
+
+    let menu = get_menu(&ctx, guild_id, &name).await?;
+    ctx.data()
+        .update_interaction_role(
+            guild_id,
+            menu.name,
+            menu.description,
+            menu.channel_id,
+            menu.message_id,
+            exclusive,
+            timestamp,
+        )
+        .await?;
+
+    let mut msg = format!("\"{}\" exclusive set to {}", name, exclusive);
+    msg.push_str(&rerender_msg(
+        rerender_interaction_role(&ctx, ctx.data(), guild_id, name).await,
+    ));
+
+    Embed::success().description(msg).send(&ctx).await?;
+
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, rename = "set_style")]
+/// Switch a role menu between buttons and a dropdown
+pub async fn rolemenu_set_style(
+    ctx: Context<'_>,
+    #[description = "Name of the menu"] name: String,
+    #[description = "How the choices are shown"] style: InteractionRoleStyle,
+    #[description = "Minimum number of roles picked (default 0, ignored if split over several dropdowns)"]
+    #[min = 0]
+    #[max = 25]
+    min_values: Option<u8>,
+    #[description = "Maximum number of roles picked from each dropdown (default all)"]
+    #[min = 1]
+    #[max = 25]
+    max_values: Option<u8>,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::RoleMenuManage).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+
+    if let (Some(min), Some(max)) = (min_values, max_values) {
+        if min > max {
+            Err(anyhow::anyhow!(
+                "min_values ({}) can't be more than max_values ({})",
+                min,
+                max
+            ))?;
+        }
+    }
+
+    let menu = get_menu(&ctx, guild_id, &name).await?;
+    let max_choices = interaction_role_max_choices(style);
+    if menu.choices.len() > max_choices {
+        Err(anyhow::anyhow!(
+            "\"{}\" has {} choices but {} role menus can have at most {}",
+            name,
+            menu.choices.len(),
+            style.name(),
+            max_choices
+        ))?;
+    }
+    ctx.data()
+        .update_interaction_role_style(
+            guild_id,
+            menu.name,
+            style,
+            min_values,
+            max_values,
+            ctx.created_at(),
+        )
+        .await?;
+
+    let mut msg = format!("\"{}\" style set to {}", name, style.name());
+    if menu.exclusive && max_values.unwrap_or(1) > 1 {
+        msg.push_str("\nNote: the menu is exclusive so only one role can be picked");
+    }
+    msg.push_str(&rerender_msg(
+        rerender_interaction_role(&ctx, ctx.data(), guild_id, name).await,
+    ));
+
+    Embed::success().description(msg).send(&ctx).await?;
+
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, rename = "publish")]
+/// Post the role menu (or update it in place if it's already been posted)
+pub async fn rolemenu_publish(
+    ctx: Context<'_>,
+    #[description = "Name of the menu"] name: String,
+    #[description = "Move the menu to a different channel"] channel: Option<ChannelId>,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::RoleMenuManage).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+
+    let mut menu = get_menu(&ctx, guild_id, &name).await?;
+
+    if let Some(channel) = channel {
+        if channel != *menu.channel_id {
+            if let Some(message_id) = menu.message_id.take() {
+                if let Err(e) = menu.channel_id.delete_message(&ctx, *message_id).await {
+                    warn!("Failed to delete old role menu message: {:?}", e);
+                }
+            }
+            menu.channel_id = channel.into();
+        }
+    }
+
+    let message_id = publish_interaction_role(&ctx, ctx.data(), &menu).await?;
+    let retired = retire_legacy_set(&ctx, ctx.data(), guild_id, &name).await?;
+
+    let mut description = format!(
+        "Role menu \"{}\" published: https://discord.com/channels/{}/{}/{}",
+        name, guild_id.0, menu.channel_id.0, message_id.0
+    );
+    if retired {
+        description.push_str("\nThe legacy reaction role message it was converted from has been retired");
+    }
+
+    Embed::success().description(description).send(&ctx).await?;
+
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, rename = "delete")]
+/// Delete a role menu and its published message
+pub async fn rolemenu_delete(
+    ctx: Context<'_>,
+    #[description = "Name of the menu"] name: String,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::RoleMenuManage).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+
+    let menu = get_menu(&ctx, guild_id, &name).await?;
+    if let Some(message_id) = menu.message_id {
+        if let Err(e) = menu.channel_id.delete_message(&ctx, *message_id).await {
+            warn!("Failed to delete role menu message: {:?}", e);
+        }
+    }
+
+    ctx.data().delete_interaction_role(guild_id, name.clone()).await?;
+
+    Embed::success()
+        .description(format!("Role menu \"{}\" deleted", name))
+        .send(&ctx)
+        .await?;
+
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, rename = "import_legacy")]
+/// Convert the reaction role sets imported from the old bot into role menus
+pub async fn rolemenu_import_legacy(
+    ctx: Context<'_>,
+    #[description = "What to do with the original reaction role messages"] mode: ConvertMode,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::RoleMenuManage).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+
+    let guild = ctx
+        .guild()
+        .ok_or(anyhow::anyhow!("missing guild in rolemenu import_legacy"))?;
+    let member = ctx
+        .author_member()
+        .await
+        .ok_or(anyhow::anyhow!("missing author_member in rolemenu import_legacy"))?;
+    let assigner = if guild.owner_id == member.user.id {
+        RoleAssigner::Owner
+    } else {
+        RoleAssigner::Member(&member)
+    };
+
+    let reports = convert_reaction_roles(&ctx, ctx.data(), guild_id, mode, assigner).await?;
+
+    let msg = if reports.len() == 0 {
+        "There are no legacy reaction role sets to convert".to_string()
+    } else {
+        reports
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    Embed::success()
+        .title("Legacy reaction role conversion")
+        .description(msg)
+        .send(&ctx)
+        .await?;
+
+    Ok(())
+}
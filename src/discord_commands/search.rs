@@ -0,0 +1,73 @@
+use std::mem;
+
+use poise::{
+    self,
+    serenity_prelude::{Channel, User},
+};
+
+use crate::{
+    commands::search::{
+        date_to_message_id, readable_channels, render_search_page, search_page_components,
+    },
+    db::queries::{
+        message_log::MessageSearchQuery,
+        permissions::{Permission, PermissionCheck},
+    },
+    ChannelId, Context, GuildId, PoiseError,
+};
+
+#[poise::command(prefix_command, slash_command, guild_only, category = "Utils")]
+/// Search the message log
+pub async fn search_messages(
+    ctx: Context<'_>,
+    #[description = "Words that must appear in the message"] text: Option<String>,
+    #[description = "Only messages sent by this user"] author: Option<User>,
+    #[description = "Only messages sent in this channel"] channel: Option<Channel>,
+    #[description = "Only messages sent on or after this date (YYYY-MM-DD)"] after: Option<String>,
+    #[description = "Only messages sent before this date (YYYY-MM-DD)"] before: Option<String>,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::MessageSearch).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+
+    let guild = ctx
+        .guild()
+        .ok_or(anyhow::anyhow!("missing guild in search_messages"))?;
+    let member = ctx
+        .author_member()
+        .await
+        .ok_or(anyhow::anyhow!("missing author_member in search_messages"))?;
+    let channel_ids = readable_channels(&guild, &member);
+
+    let channel_id: Option<ChannelId> = channel.map(|v| v.id().into());
+    if let Some(channel_id) = channel_id {
+        if !channel_ids.contains(&channel_id) {
+            Err(anyhow::anyhow!("You can't read the message history of <#{}>", channel_id.0))?;
+        }
+    }
+
+    let query = MessageSearchQuery {
+        guild_id,
+        text,
+        user_id: author.map(|v| v.id.into()),
+        channel_id,
+        after_id: after.as_deref().map(date_to_message_id).transpose()?,
+        before_id: before.as_deref().map(date_to_message_id).transpose()?,
+        channel_ids: Some(channel_ids),
+    };
+
+    let mut page = render_search_page(ctx.data(), &ctx.id().to_string(), &query, 0).await?;
+    let embed = mem::take(&mut page.embed);
+
+    ctx.send(|m| m
+        .embed(|b| embed.create_embed(b))
+        .ephemeral(true)
+        .components(|c| search_page_components(c, &page))
+    ).await?;
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::borrow::Cow;
+
+use poise::{
+    self,
+    serenity_prelude::{AttachmentType, Channel},
+};
+
+use crate::{
+    commands::{
+        search::{date_end_to_message_id, date_to_message_id, readable_channels},
+        transcript::{build_transcript, render_html, render_markdown, TranscriptFormat},
+    },
+    db::queries::permissions::{Permission, PermissionCheck},
+    ChannelId, Context, Embed, GuildId, PoiseError,
+};
+
+/// Discord rejects larger uploads on servers without boosts
+const MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+
+#[poise::command(prefix_command, slash_command, guild_only, category = "Utils")]
+/// Rebuild a channel's conversation from the message log
+pub async fn transcript(
+    ctx: Context<'_>,
+    #[description = "The channel to rebuild"] channel: Channel,
+    #[description = "The first day to include (YYYY-MM-DD)"] from: String,
+    #[description = "The last day to include (YYYY-MM-DD)"] to: String,
+    #[description = "The file format (defaults to HTML)"] format: Option<TranscriptFormat>,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::MessageSearch).await?;
+
+    let guild_id: GuildId = ctx
+        .guild_id()
+        .expect("missing guild in 'guild_only' command")
+        .into();
+    let format = format.unwrap_or(TranscriptFormat::Html);
+
+    let guild = ctx
+        .guild()
+        .ok_or(anyhow::anyhow!("missing guild in transcript"))?;
+    let member = ctx
+        .author_member()
+        .await
+        .ok_or(anyhow::anyhow!("missing author_member in transcript"))?;
+    let channel_id: ChannelId = channel.id().into();
+    if !readable_channels(&guild, &member).contains(&channel_id) {
+        Err(anyhow::anyhow!("You can't read the message history of <#{}>", channel_id.0))?;
+    }
+
+    let after_id = date_to_message_id(&from)?;
+    let before_id = date_end_to_message_id(&to)?;
+
+    let transcript = build_transcript(
+        ctx.data(),
+        guild_id,
+        channel_id,
+        after_id,
+        before_id,
+    )
+    .await?;
+
+    if transcript.entries.len() == 0 {
+        Embed::default()
+            .description("No logged messages found in that channel for those dates")
+            .send(&ctx)
+            .await?;
+        return Ok(());
+    }
+
+    let channel_name = channel
+        .clone()
+        .guild()
+        .map(|c| c.name)
+        .unwrap_or_else(|| channel.id().0.to_string());
+    let title = format!("#{} from {} to {}", channel_name, from, to);
+    let rendered = match format {
+        TranscriptFormat::Html => render_html(&transcript, &title)?,
+        TranscriptFormat::Markdown => render_markdown(&transcript, &title)?,
+    };
+
+    if rendered.len() > MAX_ATTACHMENT_SIZE {
+        Embed::error()
+            .description("The transcript is too large to send as a file. Try a shorter date range")
+            .send(&ctx)
+            .await?;
+        return Ok(());
+    }
+
+    ctx.send(|m| m
+        .ephemeral(true)
+        .content(format!(
+            "Transcript of <#{}> ({} messages{})",
+            channel.id().0,
+            transcript.entries.len(),
+            if transcript.truncated { ", truncated" } else { "" },
+        ))
+        .attachment(AttachmentType::Bytes {
+            data: Cow::Owned(rendered.into_bytes()),
+            filename: format!(
+                "transcript_{}_{}_{}.{}",
+                channel.id().0,
+                from,
+                to,
+                format.extension()
+            ),
+        })
+    ).await?;
+
+    Ok(())
+}
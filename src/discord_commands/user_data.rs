@@ -0,0 +1,88 @@
+use std::borrow::Cow;
+
+use poise::{
+    self,
+    serenity_prelude::{AttachmentType, User},
+};
+
+use crate::{
+    commands::{confirmation::request_confirmation, user_data::export_user_data},
+    db::queries::{
+        confirmations::ConfirmationAction,
+        permissions::{Permission, PermissionCheck},
+    },
+    Context, Embed, PoiseError,
+};
+
+/// Discord rejects larger uploads on servers without boosts
+const MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+
+#[poise::command(slash_command, guild_only, category = "Member management")]
+/// Get a copy of everything the bot stores about you in this server
+pub async fn mydata(ctx: Context<'_>) -> Result<(), PoiseError> {
+    // Slash command only so the ephemeral reply can't end up posted in the channel
+    ctx.defer_ephemeral().await?;
+
+    let guild = ctx
+        .guild()
+        .ok_or(anyhow::anyhow!("missing guild in 'guild_only' command"))?;
+    let member = ctx
+        .author_member()
+        .await
+        .ok_or(anyhow::anyhow!("missing author_member in 'guild_only' command"))?;
+
+    let mut export = export_user_data(ctx.data(), &guild, &member).await?;
+    let mut filename = format!("{}_{}.json", guild.id.0, member.user.id.0);
+    let mut content = "Here's everything the bot stores about you in this server";
+
+    if export.len() > MAX_ATTACHMENT_SIZE {
+        export = zstd::encode_all(export.as_slice(), zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        filename.push_str(".zst");
+        content = "Here's everything the bot stores about you in this server. It's too large \
+            to send as it is so it's been compressed with zstd";
+    }
+
+    if export.len() > MAX_ATTACHMENT_SIZE {
+        Embed::error()
+            .description("Your data is too large to send as a file, even compressed. \
+                Please ask the bot's owner for a copy")
+            .send(&ctx)
+            .await?;
+        return Ok(());
+    }
+
+    ctx.send(|m| m
+        .ephemeral(true)
+        .content(content)
+        .attachment(AttachmentType::Bytes {
+            data: Cow::Owned(export),
+            filename,
+        })
+    ).await?;
+
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, guild_only, category = "Member management")]
+/// Delete everything stored about a user's messages in this server
+pub async fn forget_user(
+    ctx: Context<'_>,
+    #[description = "The user to forget (they don't need to still be a member)"] user: User,
+) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+    ctx.require_permission(Permission::MemberForget).await?;
+
+    request_confirmation(
+        ctx,
+        ConfirmationAction::ForgetUser,
+        user.id.0.into(),
+        &format!(
+            "Are you sure you want to delete the message log and message counts for `{}`? This can't be undone",
+            user.tag()
+        ),
+        "Forget",
+    )
+    .await?;
+
+    Ok(())
+}
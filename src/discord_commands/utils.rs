@@ -4,7 +4,7 @@ use humansize::{make_format, BINARY};
 
 use crate::{
     db::queries::permissions::{Permission, PermissionCheck},
-    Context, Embed, PoiseError, db::queries::config::ConfigKey, get_config_u64_option, get_config_role_option, get_config_chan_option, get_config_string_option, Error,
+    Context, Embed, PoiseError, db::queries::config::ConfigKey, get_config_u64_option, get_config_bool_option, get_config_role_option, get_config_chan_option, get_config_string_option, Error,
 };
 
 #[poise::command(prefix_command, slash_command, category = "Utils")]
@@ -34,12 +34,16 @@ pub async fn get_compression_state(ctx: Context<'_>) -> Result<(), PoiseError> {
     compressed_messages: {},
     compressed_bytes: {},
     chunks: {},
+    chunk_cache_hit_rate: {:.1}% ({} hits, {} misses),
 }}```",
         sizes.uncompressed_messages,
         bytes_formatter(sizes.uncompressed_bytes),
         sizes.compressed_messages,
         bytes_formatter(sizes.compressed_bytes),
         sizes.chunks,
+        sizes.chunk_cache_hit_rate() * 100.0,
+        sizes.chunk_cache_hits,
+        sizes.chunk_cache_misses,
     );
 
     Embed::success()
@@ -135,6 +139,29 @@ pub async fn get_disk_space(ctx: Context<'_>) -> Result<(), PoiseError> {
     Ok(())
 }
 
+#[poise::command(prefix_command, slash_command, owners_only, category = "Utils")]
+/// Take a database backup now rather than waiting for the scheduled one
+pub async fn backup_now(ctx: Context<'_>) -> Result<(), PoiseError> {
+    ctx.defer_ephemeral().await?;
+
+    let mut embed = Embed::success().title("Database backup");
+
+    match ctx.data().db_backup().await {
+        Ok(receipt) => {
+            embed = embed.description(receipt.to_string());
+        }
+        Err(e) => {
+            embed = embed
+                .set_error(true)
+                .description(format!("Error taking backup: {:?}", e));
+        }
+    }
+
+    embed.send(&ctx).await?;
+
+    Ok(())
+}
+
 #[poise::command(prefix_command, slash_command, category = "Utils")]
 /// Check all configurable features
 pub async fn check_config(ctx: Context<'_>) -> Result<(), PoiseError> {
@@ -227,6 +254,11 @@ pub async fn check_config(ctx: Context<'_>) -> Result<(), PoiseError> {
         ConfigKey::LoggingVoiceActivity,
         &mut msg)?;
 
+    check_cfg(
+        get_config_u64_option!(data, guild_id, ConfigKey::LoggingRetentionDays),
+        ConfigKey::LoggingRetentionDays,
+        &mut msg)?;
+
     write!(&mut msg, "# Promote config\n")?; 
         
     check_cfg(
@@ -263,6 +295,13 @@ pub async fn check_config(ctx: Context<'_>) -> Result<(), PoiseError> {
         get_config_u64_option!(data, guild_id, ConfigKey::PromoteJuniorMinAge),
         ConfigKey::PromoteJuniorMinAge,
         &mut msg)?;
+
+    write!(&mut msg, "# Role menu config\n")?; 
+
+    check_cfg(
+        get_config_bool_option!(data, guild_id, ConfigKey::RoleMenuAutoRepublish),
+        ConfigKey::RoleMenuAutoRepublish,
+        &mut msg)?;
         
 
     Embed::default()
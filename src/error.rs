@@ -72,6 +72,8 @@ pub enum Error {
     RoleIdParse(#[from] poise::serenity_prelude::RoleIdParseError),
     #[error("std::num::ParseIntError: {0}")]
     ParseInt(#[from] std::num::ParseIntError),
+    #[error("std::str::ParseBoolError: {0}")]
+    ParseBool(#[from] std::str::ParseBoolError),
     #[error("rusqlite::Error: {0}")]
     Rusqlite(#[from] rusqlite::Error),
     #[error("std::convert::Infallible: {0}")]
@@ -88,6 +90,12 @@ pub enum Error {
     Join(#[from] tokio::task::JoinError),
     #[error("serde_json::Error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("rmp_serde::encode::Error: {0}")]
+    RmpEncode(#[from] rmp_serde::encode::Error),
+    #[error("rmp_serde::decode::Error: {0}")]
+    RmpDecode(#[from] rmp_serde::decode::Error),
+    #[error("chrono::ParseError: {0}")]
+    ChronoParse(#[from] chrono::ParseError),
     #[error("chrono::OutOfRangeError: {0}")]
     ChronoOutOfRange(#[from] chrono::OutOfRangeError),
     #[error("mongodb::error::Error: {0}")]
@@ -131,7 +139,9 @@ impl Error {
             Error::InvalidChoice(_) |
             Error::ChannelIdParse(_) |
             Error::RoleIdParse(_) |
-            Error::ParseInt(_) => LogBehaviour::user_safe(),
+            Error::ParseInt(_) |
+            Error::ParseBool(_) |
+            Error::ChronoParse(_) => LogBehaviour::user_safe(),
             _ => LogBehaviour::default(),
         }
     }
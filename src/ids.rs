@@ -88,3 +88,14 @@ id_from_str!(ChannelId);
 
 wrap_id!(UserId);
 wrap_id!(MessageId);
+
+/// Discord snowflakes hold the milliseconds since the start of 2015 in the top 42 bits
+const DISCORD_EPOCH_MS: i64 = 1420070400000;
+
+impl MessageId {
+    /// The smallest id a message sent at the given unix time (in milliseconds) could have.
+    /// Comparing against it finds messages sent before or after that time
+    pub fn from_unix_millis(ms: i64) -> Self {
+        Self::from(((ms - DISCORD_EPOCH_MS).max(0) as u64) << 22)
+    }
+}
@@ -1,16 +1,27 @@
-use std::{fmt::Debug, os::unix::fs::MetadataExt, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use db::{
     queries::{
         config::{ConfigKey, LogChannel},
-        interaction_roles::InteractionRole,
-        message_log::{LogType, MessageLog},
+        confirmations::{ConfirmationAction, PendingConfirmation},
+        custom_ids::{InteractionCustomId, InteractionKind},
+        interaction_roles::{InteractionRole, InteractionRoleStyle},
+        message_log::{LogType, MessageLog, MessageSearchQuery, MessageSearchResult},
         permissions::{EffectivePermission, Permission},
-    }, CompressionState, DbCommand
+        reaction_roles::ReactionRoleTemp,
+    }, run_backup, BackupConfig, BackupReceipt, CompressionState, DbCommand, ForgetUserReceipt, UserData
 };
 use lazy_regex::{regex, Captures};
-use poise::serenity_prelude::{Guild, Member, Message, Timestamp, User};
-use tokio::sync::oneshot;
+use poise::serenity_prelude::{self as serenity, Guild, Member, Message, Timestamp, User};
+use tokio::{sync::oneshot, task::JoinHandle};
 
 mod ids;
 pub use ids::*;
@@ -40,14 +51,13 @@ pub const GAGBOT_COLOR_WELCOME: i32 = GAGBOT_COLOR_GREET;
 pub const GAGBOT_COLOR_LOG_JOIN: i32 = 0x009900;
 pub const GAGBOT_COLOR_LOG_LEAVE: i32 = 0x990044;
 
-pub const INTERACTION_BUTTON_CUSTOM_ID_MAX_LEN: usize = 100;
-pub const INTERACTION_BUTTON_CUSTOM_ID_ROLE_ID_MAX_LEN: usize = 21;
-pub const INTERACTION_BUTTON_CUSTOM_ID_DELIMITER: char = '¬';
+pub const INTERACTION_CUSTOM_ID_DELIMITER: char = '¬';
+/// custom_ids with this prefix are looked up in the interaction_custom_id table
+pub const INTERACTION_CUSTOM_ID_PREFIX: &str = "ci";
+/// Confirmation buttons: prefix, pending_confirmation id and ok/cancel
+pub const INTERACTION_CONFIRM_CUSTOM_ID_PREFIX: &str = "cf";
+/// Legacy button format: prefix, set name and role id
 pub const INTERACTION_BUTTON_CUSTOM_ID_PREFIX: &str = "rr";
-pub const INTERACTION_BUTTON_CUSTOM_ID_NAME_MAX_LEN: usize = INTERACTION_BUTTON_CUSTOM_ID_MAX_LEN
-    - INTERACTION_BUTTON_CUSTOM_ID_ROLE_ID_MAX_LEN
-    - 1 //delimiter
-    - INTERACTION_BUTTON_CUSTOM_ID_PREFIX.len();
 
 /// The edit tracking functionality won't work without some cached messages
 /// 200 is the default from discord.js <https://github.com/discordjs/discord.js/blob/86e5f5a119c6d2588b988a33236d358ded357847/packages/discord.js/src/util/Options.js#L175>
@@ -55,6 +65,9 @@ pub const CACHE_MAX_MESSAGES: usize = 200;
 
 pub const DISK_SPACE_WARNING_LEVEL: u64 = 5 * 1024 * 1024 * 1024;
 
+/// How many message log entries `BotData::get_user_data` loads per DB command
+const USER_DATA_PAGE_SIZE: u64 = 500;
+
 pub fn configure_tracing() {
     tracing::subscriber::set_global_default(
         tracing_subscriber::FmtSubscriber::builder()
@@ -73,6 +86,11 @@ pub struct BotData {
     pub db_command_sender: flume::Sender<DbCommand>,
     pub db_file_path: Option<PathBuf>,
     pub background_task_frequency: Duration,
+    /// None if this bot doesn't take backups
+    pub backup_config: Option<BackupConfig>,
+    /// The running role menu reconciliation task for each guild. GuildCreate is sent
+    /// again on every reconnect so this stops them piling up
+    role_menu_tasks: Arc<Mutex<HashMap<serenity::GuildId, JoinHandle<()>>>>,
 }
 
 impl BotData {
@@ -80,12 +98,31 @@ impl BotData {
         db_command_sender: flume::Sender<DbCommand>,
         db_file_path: Option<PathBuf>,
         background_task_frequency: Duration,
+        backup_config: Option<BackupConfig>,
     ) -> Self {
         Self {
             db_command_sender,
             db_file_path,
             background_task_frequency,
+            backup_config,
+            role_menu_tasks: Default::default(),
+        }
+    }
+
+    /// Spawns the guild's role menu reconciliation task unless it already has one
+    /// running. Returns true if it was spawned
+    pub fn spawn_role_menu_task<F>(&self, guild_id: GuildId, task: F) -> bool
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.role_menu_tasks.lock().expect("role_menu_tasks lock poisoned");
+        if let Some(handle) = tasks.get(&*guild_id) {
+            if !handle.is_finished() {
+                return false;
+            }
         }
+        tasks.insert(*guild_id, tokio::spawn(task));
+        true
     }
 
     pub fn db_available_space(&self) -> Result<u64, Error> {
@@ -329,6 +366,107 @@ impl BotData {
         Ok(r.await??)
     }
 
+    pub async fn create_pending_confirmation(
+        &self,
+        guild_id: GuildId,
+        requester_id: UserId,
+        action: ConfirmationAction,
+        payload: serde_json::Value,
+        expires: i64,
+        timestamp: Timestamp,
+    ) -> Result<String, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::CreatePendingConfirmation {
+                guild_id,
+                requester_id,
+                action,
+                payload,
+                expires,
+                timestamp,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn get_pending_confirmation(
+        &self,
+        id: String,
+    ) -> Result<Option<PendingConfirmation>, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::GetPendingConfirmation {
+                id,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn delete_pending_confirmation(&self, id: String) -> Result<bool, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::DeletePendingConfirmation {
+                id,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn get_interaction_custom_id(
+        &self,
+        id: String,
+    ) -> Result<Option<InteractionCustomId>, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::GetInteractionCustomId {
+                id,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn get_or_create_interaction_custom_id(
+        &self,
+        guild_id: GuildId,
+        kind: InteractionKind,
+        set_name: String,
+        choice: String,
+        extra: Option<serde_json::Value>,
+        timestamp: Timestamp,
+    ) -> Result<String, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::GetOrCreateInteractionCustomId {
+                guild_id,
+                kind,
+                set_name,
+                choice,
+                extra,
+                timestamp,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn get_interaction_roles(
+        &self,
+        guild_id: GuildId,
+    ) -> Result<Vec<InteractionRole>, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::GetInteractionRoles {
+                guild_id,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
     pub async fn get_interaction_role(
         &self,
         guild_id: GuildId,
@@ -371,6 +509,30 @@ impl BotData {
         Ok(r.await??)
     }
 
+    pub async fn update_interaction_role_style(
+        &self,
+        guild_id: GuildId,
+        name: String,
+        style: InteractionRoleStyle,
+        min_values: Option<u8>,
+        max_values: Option<u8>,
+        timestamp: Timestamp,
+    ) -> Result<bool, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::UpdateInteractionRoleStyle {
+                guild_id,
+                name,
+                style,
+                min_values,
+                max_values,
+                timestamp,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
     pub async fn update_interaction_choice(
         &self,
         guild_id: GuildId,
@@ -395,6 +557,114 @@ impl BotData {
         Ok(r.await??)
     }
 
+    pub async fn update_interaction_choice_requirements(
+        &self,
+        guild_id: GuildId,
+        set_name: String,
+        choice: String,
+        required_role_id: Option<RoleId>,
+        forbidden_role_id: Option<RoleId>,
+        min_message_count: Option<usize>,
+        timestamp: Timestamp,
+    ) -> Result<bool, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::UpdateInteractionRoleChoiceRequirements {
+                guild_id,
+                set_name,
+                choice,
+                required_role_id,
+                forbidden_role_id,
+                min_message_count,
+                timestamp,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn delete_interaction_role(
+        &self,
+        guild_id: GuildId,
+        name: String,
+    ) -> Result<bool, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::DeleteInteractionRoleSet {
+                guild_id,
+                name,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn get_reaction_role_temps(
+        &self,
+        guild_id: Option<GuildId>,
+    ) -> Result<Vec<ReactionRoleTemp>, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::GetReactionRoleTemps {
+                guild_id,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn get_reaction_role_temp_by_message(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<Option<ReactionRoleTemp>, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::GetReactionRoleTempByMessage {
+                guild_id,
+                channel_id,
+                message_id,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn delete_reaction_role_temp(
+        &self,
+        guild_id: GuildId,
+        name: String,
+    ) -> Result<bool, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::DeleteReactionRoleTemp {
+                guild_id,
+                name,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn delete_interaction_choice(
+        &self,
+        guild_id: GuildId,
+        set_name: String,
+        choice: String,
+    ) -> Result<bool, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::DeleteInteractionRoleChoice {
+                guild_id,
+                set_name,
+                choice,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
     pub async fn log_message(
         &self,
         message_id: MessageId,
@@ -415,6 +685,46 @@ impl BotData {
         Ok(r.await??)
     }
 
+    pub async fn get_channel_message_log(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        after_id: MessageId,
+        before_id: MessageId,
+        limit: u64,
+    ) -> Result<Vec<MessageLog>, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::GetLogMessagesByChannel {
+                guild_id,
+                channel_id,
+                after_id,
+                before_id,
+                limit,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    pub async fn search_messages(
+        &self,
+        query: MessageSearchQuery,
+        limit: u64,
+        offset: u64,
+    ) -> Result<(u64, Vec<MessageSearchResult>), Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::SearchMessages {
+                query,
+                limit,
+                offset,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
     pub async fn get_message_log(
         &self,
         message_id: MessageId,
@@ -429,23 +739,93 @@ impl BotData {
         Ok(r.await??)
     }
 
-    // pub async fn lookup_user_from_message(
-    //     &self,
-    //     guild_id: GuildId,
-    //     channel_id: ChannelId,
-    //     message_id: MessageId,
-    // ) -> Result<Option<UserId>, Error> {
-    //     let (s, r) = oneshot::channel();
-    //     self.db_command_sender
-    //         .send_async(DbCommand::GetUserFromLogMessages {
-    //             guild_id,
-    //             channel_id,
-    //             message_id,
-    //             respond_to: s,
-    //         })
-    //         .await?;
-    //     Ok(r.await??)
-    // }
+    pub async fn lookup_user_from_message(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<Option<UserId>, Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::GetUserFromLogMessages {
+                guild_id,
+                channel_id,
+                message_id,
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
+    /// Gets everything stored about the user's messages in the guild. The history is
+    /// loaded a page per command so a long one doesn't hold up the DB task
+    pub async fn get_user_data(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Result<UserData, Error> {
+        let mut messages: Vec<MessageLog> = Vec::new();
+        loop {
+            let (s, r) = oneshot::channel();
+            self.db_command_sender
+                .send_async(DbCommand::GetUserMessages {
+                    guild_id,
+                    user_id,
+                    after: messages.last().map(|m| (m.message_id, m.message_index_id)),
+                    limit: USER_DATA_PAGE_SIZE,
+                    respond_to: s,
+                })
+                .await?;
+            let page = r.await??;
+            let done = (page.len() as u64) < USER_DATA_PAGE_SIZE;
+            messages.extend(page);
+            if done {
+                break;
+            }
+        }
+
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::GetUserMessageCounts {
+                guild_id,
+                user_id,
+                respond_to: s,
+            })
+            .await?;
+        let message_counts = r.await??;
+
+        Ok(UserData { messages, message_counts })
+    }
+
+    /// Deletes everything stored about the user's messages. It's done a batch per
+    /// command so other commands aren't held up while a lot of history is deleted
+    pub async fn forget_user(
+        &self,
+        guild_id: Option<GuildId>,
+        user_id: UserId,
+    ) -> Result<ForgetUserReceipt, Error> {
+        let mut receipt = ForgetUserReceipt {
+            user_id,
+            guild_id,
+            message_log: Default::default(),
+            message_counts: 0,
+        };
+        loop {
+            let (s, r) = oneshot::channel();
+            self.db_command_sender
+                .send_async(DbCommand::ForgetUser {
+                    guild_id,
+                    user_id,
+                    respond_to: s,
+                })
+                .await?;
+            let (batch, more) = r.await??;
+            receipt.add(batch);
+            if !more {
+                return Ok(receipt);
+            }
+        }
+    }
 
     pub async fn db_table_sizes(&self) -> Result<Vec<(String, u64, u64)>, Error> {
         let (s, r) = oneshot::channel();
@@ -467,6 +847,26 @@ impl BotData {
         Ok(r.await??)
     }
 
+    pub async fn db_backup(&self) -> Result<BackupReceipt, Error> {
+        let config = self
+            .backup_config
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Backups aren't configured for this bot"))?;
+        run_backup(config).await
+    }
+
+    /// Waits for the commands already queued then closes the database. The DB task stops
+    /// after this so nothing else can be sent to it
+    pub async fn db_close(&self) -> Result<(), Error> {
+        let (s, r) = oneshot::channel();
+        self.db_command_sender
+            .send_async(DbCommand::Close { 
+                respond_to: s,
+            })
+            .await?;
+        Ok(r.await??)
+    }
+
     pub async fn db_optimize(&self) -> Result<Duration, Error> {
         let (s, r) = oneshot::channel();
         self.db_command_sender